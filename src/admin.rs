@@ -0,0 +1,524 @@
+//! Admin gRPC service for inspecting accounts and lifting rate limits
+use crate::admin_grpc::admin_server::Admin;
+use crate::admin_grpc::{
+    AccountSummaryReply, AccountTierReply, AccountTierRequest, BlockReply, CheckEventReply,
+    CheckEventRequest, CompactReply, CompactRequest, EventWindowReply, EventWindowRequest,
+    RebuildGraphReply, RebuildGraphRequest,
+};
+use crate::db::Tier;
+use crate::nauthz_grpc::{Decision, EventRequest};
+use crate::{build_graph, EventAuthz};
+
+use crate::admin_grpc::admin_server::AdminServer;
+
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+
+use nostr_sdk::prelude::{Event, XOnlyPublicKey};
+use tokio::task::JoinHandle;
+use tonic::transport::Server;
+use tonic::{Request, Response, Status};
+use tracing::{error, info};
+
+pub struct AdminService {
+    /// Authorization whose repo and settings are administered
+    pub checker: EventAuthz,
+}
+
+impl AdminService {
+    /// Serves the admin service on `addr` until the process exits, apart from
+    /// the authorization service as it has no authentication of its own
+    pub fn spawn(self, mut server: Server, addr: SocketAddr) -> JoinHandle<()> {
+        info!("Admin server listening on {addr}");
+        tokio::spawn(async move {
+            if let Err(err) = server.add_service(AdminServer::new(self)).serve(addr).await {
+                error!("Admin server failed: {err}");
+            }
+        })
+    }
+}
+
+#[tonic::async_trait]
+impl Admin for AdminService {
+    async fn get_account_tier(
+        &self,
+        request: Request<AccountTierRequest>,
+    ) -> Result<Response<AccountTierReply>, Status> {
+        let pubkey = valid_pubkey(request.into_inner())?;
+        let internal = |err| {
+            error!("Could not look up {pubkey}: {err}");
+            Status::internal("Could not read account")
+        };
+        let tier = self
+            .checker
+            .repo
+            .get_account_tier(&pubkey)
+            .map_err(internal)?;
+        let (follower_count, follow_count) =
+            self.checker.repo.follow_counts(&pubkey).map_err(internal)?;
+
+        Ok(Response::new(AccountTierReply {
+            tier: format!("{tier:?}"),
+            follower_count: follower_count as u64,
+            follow_count: follow_count as u64,
+        }))
+    }
+
+    async fn open_event_window(
+        &self,
+        request: Request<EventWindowRequest>,
+    ) -> Result<Response<EventWindowReply>, Status> {
+        let request = request.into_inner();
+        let tiers = request
+            .tiers
+            .iter()
+            .map(|name| {
+                serde_json::from_value::<Tier>(serde_json::Value::String(name.clone()))
+                    .map_err(|_| format!("Unknown tier {name}"))
+            })
+            .collect::<Result<HashSet<Tier>, String>>()
+            .map_err(Status::invalid_argument)?;
+
+        let expires_at = self
+            .checker
+            .repo
+            .open_event_window(request.duration_secs, tiers);
+        Ok(Response::new(EventWindowReply { expires_at }))
+    }
+
+    async fn get_account_summary(
+        &self,
+        request: Request<AccountTierRequest>,
+    ) -> Result<Response<AccountSummaryReply>, Status> {
+        let pubkey = valid_pubkey(request.into_inner())?;
+        let summary = self
+            .checker
+            .repo
+            .account_summary(&self.checker.settings, &pubkey)
+            .map_err(|err| {
+                error!("Could not summarize {pubkey}: {err}");
+                Status::internal("Could not read account")
+            })?;
+
+        Ok(Response::new(AccountSummaryReply {
+            tier: format!("{:?}", summary.tier),
+            source: format!("{:?}", summary.source),
+            can_publish: summary.can_publish,
+            remaining_hour: summary.remaining_hour.map(|n| n as u64),
+            remaining_day: summary.remaining_day.map(|n| n as u64),
+            remaining_month: summary.remaining_month.map(|n| n as u64),
+            follower_count: summary.follower_count as u64,
+            follow_count: summary.follow_count as u64,
+            first_seen: summary.first_seen,
+            last_seen: summary.last_seen,
+            allowlisted: summary.allowlisted,
+            blocklisted: summary.blocklisted,
+            pinned: summary.pinned.map(|tier| format!("{tier:?}")),
+        }))
+    }
+
+    async fn block_pubkey(
+        &self,
+        request: Request<AccountTierRequest>,
+    ) -> Result<Response<BlockReply>, Status> {
+        let pubkey = valid_pubkey(request.into_inner())?;
+        self.checker.repo.block(&pubkey).map_err(|err| {
+            error!("Could not block {pubkey}: {err}");
+            Status::internal("Could not block account")
+        })?;
+        Ok(Response::new(BlockReply { blocked: true }))
+    }
+
+    async fn unblock_pubkey(
+        &self,
+        request: Request<AccountTierRequest>,
+    ) -> Result<Response<BlockReply>, Status> {
+        let pubkey = valid_pubkey(request.into_inner())?;
+        let internal = |err| {
+            error!("Could not unblock {pubkey}: {err}");
+            Status::internal("Could not unblock account")
+        };
+        self.checker.repo.unblock(&pubkey).map_err(internal)?;
+        let blocked = self
+            .checker
+            .repo
+            .is_blocked(&self.checker.settings, &pubkey)
+            .map_err(internal)?;
+        Ok(Response::new(BlockReply { blocked }))
+    }
+
+    async fn compact(
+        &self,
+        _request: Request<CompactRequest>,
+    ) -> Result<Response<CompactReply>, Status> {
+        let repo = self.checker.repo.clone();
+        let (size_before, size_after) = tokio::task::spawn_blocking(move || repo.compact())
+            .await
+            .map_err(|err| Status::internal(err.to_string()))?
+            .map_err(|err| {
+                error!("Could not compact: {err}");
+                Status::internal("Could not compact database")
+            })?;
+        Ok(Response::new(CompactReply {
+            size_before,
+            size_after,
+        }))
+    }
+
+    async fn check_event(
+        &self,
+        request: Request<CheckEventRequest>,
+    ) -> Result<Response<CheckEventReply>, Status> {
+        let request = request.into_inner();
+        let event = Event::from_json(&request.event_json)
+            .map_err(|err| Status::invalid_argument(format!("Invalid event: {err}")))?;
+        let auth_pubkey = request
+            .auth_pubkey
+            .map(|pubkey| XOnlyPublicKey::from_str(&pubkey))
+            .transpose()
+            .map_err(|_| Status::invalid_argument("auth_pubkey must be 32 bytes of hex"))?;
+        let author = auth_pubkey.unwrap_or(event.pubkey).to_string();
+
+        let reply = self
+            .checker
+            .dry_run(EventRequest {
+                event: Some((&event).into()),
+                ip_addr: None,
+                origin: request.origin,
+                user_agent: None,
+                auth_pubkey: auth_pubkey.map(|pubkey| pubkey.serialize().to_vec()),
+                nip05: None,
+            })
+            .await;
+
+        let internal = |err| {
+            error!("Could not look up {author}: {err}");
+            Status::internal("Could not read account")
+        };
+        let repo = &self.checker.repo;
+        let (tier, limitation) = repo
+            .resolve_policy(&self.checker.settings, &author)
+            .map_err(internal)?;
+        let (remaining_hour, remaining_day, remaining_month) = repo
+            .remaining_quota(&author, &limitation)
+            .map_err(internal)?;
+
+        Ok(Response::new(CheckEventReply {
+            permitted: reply.decision == Decision::Permit as i32,
+            message: reply.message,
+            tier: format!("{tier:?}"),
+            remaining_hour: remaining_hour.map(|n| n as u64),
+            remaining_day: remaining_day.map(|n| n as u64),
+            remaining_month: remaining_month.map(|n| n as u64),
+        }))
+    }
+
+    async fn rebuild_graph(
+        &self,
+        _request: Request<RebuildGraphRequest>,
+    ) -> Result<Response<RebuildGraphReply>, Status> {
+        // Events follow the init policy until the build is done, as on startup
+        let ready = self.checker.ready.clone();
+        if ready
+            .compare_exchange(true, false, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            return Err(Status::unavailable("Graph is already being built"));
+        }
+
+        // Spawned so a cancelled request does not leave the graph half built
+        let checker = self.checker.clone();
+        let built = tokio::spawn(async move {
+            let built = build_graph(&checker.settings, &checker.repo, &checker.nos).await;
+            ready.store(true, Ordering::SeqCst);
+            built
+        })
+        .await
+        .map_err(|err| Status::internal(err.to_string()))?;
+        built.map_err(|err| {
+            error!("Could not rebuild graph: {err}");
+            Status::internal("Could not rebuild graph")
+        })?;
+
+        let accounts_per_tier = self
+            .checker
+            .repo
+            .count_by_tier()
+            .map_err(|err| Status::internal(err.to_string()))?
+            .into_iter()
+            .map(|(tier, count)| (format!("{tier:?}"), count as u64))
+            .collect();
+        Ok(Response::new(RebuildGraphReply { accounts_per_tier }))
+    }
+}
+
+/// Pubkey of the request if it is valid hex
+#[allow(clippy::result_large_err)]
+fn valid_pubkey(request: AccountTierRequest) -> Result<String, Status> {
+    if XOnlyPublicKey::from_str(&request.pubkey).is_err() {
+        return Err(Status::invalid_argument("pubkey must be 32 bytes of hex"));
+    }
+    Ok(request.pubkey)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::breaker::CircuitBreaker;
+    use crate::config::Settings;
+    use crate::metrics::Metrics;
+    use crate::nostr::{MemoryRelay, Nostr};
+    use crate::repo::Repo;
+    use crate::updates::UpdateQueue;
+
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    use nostr_sdk::prelude::{EventBuilder, Keys, Kind, Tag};
+
+    fn admin(repo: Repo, settings: Settings) -> AdminService {
+        AdminService {
+            checker: EventAuthz {
+                repo,
+                breaker: CircuitBreaker::new(&settings.circuit_breaker),
+                settings,
+                nos: Nostr::offline(),
+                metrics: Metrics::default(),
+                updates: UpdateQueue::new(&Default::default(), Metrics::default()),
+                ready: Arc::new(AtomicBool::new(true)),
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_account_tier() {
+        let a = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9";
+        let b = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203";
+        let c = "7c27a04b7c27299f16dc07d3eb8f28544f188bc7a34982328b7d581edc405dc2";
+        let repo = Repo::new_in_memory(HashSet::from([a.to_string()]));
+        repo.set_tier(&HashSet::from([a.to_string()]), Tier::Primary)
+            .await
+            .unwrap();
+        repo.update_contacts(a, HashSet::from([b.to_string()]))
+            .await
+            .unwrap();
+        repo.update_contacts(b, HashSet::from([c.to_string()]))
+            .await
+            .unwrap();
+        let admin = admin(repo, Settings::default());
+
+        let reply = admin
+            .get_account_tier(Request::new(AccountTierRequest {
+                pubkey: b.to_string(),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(
+            AccountTierReply {
+                tier: "Secondary".to_string(),
+                follower_count: 1,
+                follow_count: 1,
+            },
+            reply
+        );
+
+        let invalid = admin
+            .get_account_tier(Request::new(AccountTierRequest {
+                pubkey: "npub".to_string(),
+            }))
+            .await;
+        assert_eq!(tonic::Code::InvalidArgument, invalid.unwrap_err().code());
+    }
+
+    #[tokio::test]
+    async fn test_open_event_window() {
+        let repo = Repo::new_in_memory(HashSet::new());
+        let admin = admin(repo, Settings::default());
+
+        let reply = admin
+            .open_event_window(Request::new(EventWindowRequest {
+                duration_secs: 60,
+                tiers: vec!["Secondary".to_string(), "Tertiary".to_string()],
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(reply.expires_at > crate::utils::unix_time());
+
+        let invalid = admin
+            .open_event_window(Request::new(EventWindowRequest {
+                duration_secs: 60,
+                tiers: vec!["Fifth".to_string()],
+            }))
+            .await;
+        assert_eq!(tonic::Code::InvalidArgument, invalid.unwrap_err().code());
+    }
+
+    #[tokio::test]
+    async fn test_get_account_summary() {
+        let a = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9";
+        let b = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203";
+        let repo = Repo::new_in_memory(HashSet::from([a.into()]));
+        repo.pin_tier(b, Tier::Secondary).unwrap();
+        let mut settings = Settings::default();
+        settings.secondary.can_publish = true;
+        settings.secondary.events_per_day = Some(10);
+        let admin = admin(repo, settings);
+
+        let reply = admin
+            .get_account_summary(Request::new(AccountTierRequest {
+                pubkey: b.to_string(),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!("Secondary", reply.tier);
+        assert_eq!("Pinned", reply.source);
+        assert_eq!(Some("Secondary".to_string()), reply.pinned);
+        assert!(reply.can_publish);
+        assert_eq!(
+            (None, Some(10)),
+            (reply.remaining_hour, reply.remaining_day)
+        );
+        assert_eq!(None, reply.last_seen);
+    }
+
+    #[tokio::test]
+    async fn test_block_pubkey() {
+        let a = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9";
+        let b = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203";
+        let repo = Repo::new_in_memory(HashSet::new());
+        let mut settings = Settings::default();
+        settings.info.blocklist = HashSet::from([b.to_string()]);
+        let admin = admin(repo, settings);
+        let request = |pubkey: &str| {
+            Request::new(AccountTierRequest {
+                pubkey: pubkey.to_string(),
+            })
+        };
+
+        admin.block_pubkey(request(a)).await.unwrap();
+        assert!(admin
+            .checker
+            .repo
+            .is_blocked(&admin.checker.settings, a)
+            .unwrap());
+        let reply = admin.unblock_pubkey(request(a)).await.unwrap().into_inner();
+        assert!(!reply.blocked);
+
+        // The config blocklist can not be lifted over the API
+        admin.block_pubkey(request(b)).await.unwrap();
+        let reply = admin.unblock_pubkey(request(b)).await.unwrap().into_inner();
+        assert!(reply.blocked);
+
+        assert!(admin.block_pubkey(request("npub")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_compact() {
+        let repo = Repo::new_in_memory(HashSet::new());
+        let admin = admin(repo, Settings::default());
+
+        let reply = admin
+            .compact(Request::new(CompactRequest {}))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(reply.size_before > 0);
+        assert!(reply.size_after > 0);
+    }
+
+    #[tokio::test]
+    async fn test_check_event() {
+        let keys = Keys::generate();
+        let pubkey = keys.public_key().to_string();
+        let repo = Repo::new_in_memory(HashSet::new());
+        repo.set_tier(&HashSet::from([pubkey.clone()]), Tier::Secondary)
+            .await
+            .unwrap();
+        let mut settings = Settings::default();
+        settings.secondary.can_publish = true;
+        settings.secondary.events_per_hour = Some(1);
+        let admin = admin(repo, settings);
+
+        let note = EventBuilder::new_text_note("gm", &[])
+            .to_event(&keys)
+            .unwrap();
+        let request = || {
+            Request::new(CheckEventRequest {
+                event_json: note.as_json().unwrap(),
+                auth_pubkey: None,
+                origin: None,
+            })
+        };
+
+        // Nothing is recorded so the answer does not change
+        for _ in 0..3 {
+            let reply = admin.check_event(request()).await.unwrap().into_inner();
+            assert!(reply.permitted);
+            assert_eq!("Secondary", reply.tier);
+            assert_eq!(Some(1), reply.remaining_hour);
+        }
+
+        admin.checker.repo.add_event(&pubkey).unwrap();
+        let reply = admin.check_event(request()).await.unwrap().into_inner();
+        assert!(!reply.permitted);
+        assert_eq!(Some("Hour limit exhausted".to_string()), reply.message);
+        assert_eq!(Some(0), reply.remaining_hour);
+
+        let invalid = admin
+            .check_event(Request::new(CheckEventRequest {
+                event_json: "{}".to_string(),
+                auth_pubkey: None,
+                origin: None,
+            }))
+            .await;
+        assert!(invalid.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_rebuild_graph() {
+        let primary = Keys::generate();
+        let follow = Keys::generate().public_key();
+        let stale = Keys::generate().public_key().to_string();
+        let relay = MemoryRelay::default();
+        relay.publish(
+            EventBuilder::new(Kind::ContactList, "", &[Tag::PubKey(follow, None)])
+                .to_event(&primary)
+                .unwrap(),
+        );
+
+        let mut settings = Settings::default();
+        settings.info.primary_keys = HashSet::from([primary.public_key().to_string()]);
+        let repo = Repo::new_in_memory(settings.info.primary_keys.clone());
+        repo.set_tier(&HashSet::from([stale.clone()]), Tier::Secondary)
+            .await
+            .unwrap();
+        let mut admin = admin(repo, settings);
+        admin.checker.nos = Nostr::with_client(Arc::new(relay));
+
+        let reply = admin
+            .rebuild_graph(Request::new(RebuildGraphRequest {}))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(Some(&1), reply.accounts_per_tier.get("Primary"));
+        assert_eq!(Some(&1), reply.accounts_per_tier.get("Secondary"));
+        assert_eq!(
+            Tier::Other,
+            admin.checker.repo.get_account_tier(&stale).unwrap()
+        );
+        assert!(admin.checker.ready.load(Ordering::SeqCst));
+
+        // Only one build runs at a time
+        admin.checker.ready.store(false, Ordering::SeqCst);
+        let status = admin
+            .rebuild_graph(Request::new(RebuildGraphRequest {}))
+            .await
+            .unwrap_err();
+        assert_eq!(tonic::Code::Unavailable, status.code());
+    }
+}
@@ -0,0 +1,133 @@
+//! Circuit breaker that stops calling the DB after repeated failures
+use crate::config::CircuitBreakerSettings;
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tracing::{debug, info, warn};
+
+#[derive(Clone)]
+pub struct CircuitBreaker {
+    /// Consecutive failures that open the breaker, never opens when `None`
+    threshold: Option<u32>,
+    probe_interval: Duration,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Consecutive failures
+    failures: u32,
+    /// When the breaker opened or last let a probe through, `None` while closed
+    opened_at: Option<Instant>,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        Self::new(&CircuitBreakerSettings::default())
+    }
+}
+
+impl CircuitBreaker {
+    pub fn new(settings: &CircuitBreakerSettings) -> Self {
+        Self {
+            threshold: settings.failure_threshold.filter(|t| *t > 0),
+            probe_interval: Duration::from_millis(settings.probe_interval_ms),
+            state: Arc::new(Mutex::new(State::default())),
+        }
+    }
+
+    /// Whether a call should go to the DB
+    /// While open one call is let through every probe interval to check for recovery
+    pub fn allow(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match state.opened_at {
+            None => true,
+            Some(at) if at.elapsed() >= self.probe_interval => {
+                debug!("Probing DB");
+                state.opened_at = Some(Instant::now());
+                true
+            }
+            Some(_) => false,
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.opened_at.take().is_some() {
+            info!("DB recovered, circuit breaker closed");
+        }
+        state.failures = 0;
+    }
+
+    pub fn record_failure(&self) {
+        let threshold = match self.threshold {
+            Some(threshold) => threshold,
+            None => return,
+        };
+        let mut state = self.state.lock().unwrap();
+        state.failures = state.failures.saturating_add(1);
+        if state.failures >= threshold {
+            if state.opened_at.is_none() {
+                warn!(
+                    "{} consecutive DB failures, circuit breaker open",
+                    state.failures
+                );
+            }
+            state.opened_at = Some(Instant::now());
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.state.lock().unwrap().opened_at.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::FailMode;
+
+    #[test]
+    fn test_trip_and_recover() {
+        let breaker = CircuitBreaker::new(&CircuitBreakerSettings {
+            failure_threshold: Some(3),
+            probe_interval_ms: 20,
+            degraded_mode: FailMode::FailClosed,
+        });
+
+        // A success resets the count of consecutive failures
+        breaker.record_failure();
+        breaker.record_failure();
+        breaker.record_success();
+        breaker.record_failure();
+        breaker.record_failure();
+        assert!(breaker.allow());
+
+        breaker.record_failure();
+        assert!(breaker.is_open());
+        assert!(!breaker.allow());
+
+        // A failed probe keeps it open
+        std::thread::sleep(Duration::from_millis(25));
+        assert!(breaker.allow());
+        assert!(!breaker.allow());
+        breaker.record_failure();
+        assert!(breaker.is_open());
+
+        std::thread::sleep(Duration::from_millis(25));
+        assert!(breaker.allow());
+        breaker.record_success();
+        assert!(!breaker.is_open());
+        assert!(breaker.allow());
+    }
+
+    #[test]
+    fn test_disabled() {
+        let breaker = CircuitBreaker::default();
+        for _ in 0..100 {
+            breaker.record_failure();
+        }
+        assert!(breaker.allow());
+    }
+}
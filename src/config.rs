@@ -1,21 +1,455 @@
 //! Configuration file and settings management
-use config::{Config, ConfigError, File};
+use crate::db::Tier;
+
+use config::{Config, ConfigError, Environment, File, Map};
 use log::warn;
+use nostr_sdk::prelude::{FromBech32, XOnlyPublicKey};
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default)]
+/// Seconds in each rate limit window
+pub const HOUR: u64 = 3600;
+pub const DAY: u64 = 86400;
+pub const MONTH: u64 = 30 * DAY;
+
+/// Prefix of environment variables overriding settings
+const ENV_PREFIX: &str = "NAUTHZ_";
+
+/// Hops past which every account is in the other tier
+pub const MAX_GRAPH_DEPTH: u8 = 5;
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct Limitation {
     pub can_publish: bool,
+    /// Skip rate limits entirely, events are still denied without `can_publish`
+    #[serde(default)]
+    pub unlimited: bool,
+    /// Events permitted in the past hour, the next one is denied
     pub events_per_hour: Option<usize>,
+    /// Events permitted in the past 24 hours, the next one is denied
     pub events_per_day: Option<usize>,
+    /// Events permitted in the past 30 days, the next one is denied
+    pub events_per_month: Option<usize>,
+    /// Max distinct event kinds an account can publish in 24 hours
+    pub max_distinct_kinds: Option<usize>,
+    /// Max `p` tags (mentions) in an event, contact lists are exempt
+    pub max_p_tags_per_event: Option<usize>,
+    /// Max content bytes an account can publish in an hour
+    pub bytes_per_hour: Option<u64>,
+    /// Max content bytes an account can publish in 24 hours
+    pub bytes_per_day: Option<u64>,
+    /// Only these kinds can be published when set
+    pub allowed_kinds: Option<Vec<u64>>,
+    /// Kinds that can not be published
+    pub denied_kinds: Option<Vec<u64>>,
+    /// Max events of an account being decided on at once, not applied when `unlimited`
+    pub max_concurrent: Option<usize>,
+    /// Whether event counts are limited by windows or by a token bucket
+    #[serde(default)]
+    pub rate_limit_mode: RateLimitMode,
+    /// Tokens an account's bucket refills by per hour, one is taken per event
+    pub tokens_per_hour: Option<u64>,
+    /// Tokens a bucket holds when full, `tokens_per_hour` when unset
+    pub burst: Option<u64>,
+}
+
+/// How event counts are rate limited
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitMode {
+    /// `events_per_hour`, `events_per_day` and `events_per_month` over fixed windows
+    #[default]
+    Window,
+    /// `tokens_per_hour` refilling a bucket of `burst` tokens, smoothing bursts
+    Bucket,
+}
+
+impl Limitation {
+    /// Checks that limits over nested windows do not contradict each other
+    pub fn check(&self) -> Result<(), String> {
+        fn at_most<T: PartialOrd>(
+            smaller: (&str, Option<T>),
+            larger: (&str, Option<T>),
+        ) -> Result<(), String> {
+            match (smaller.1, larger.1) {
+                (Some(s), Some(l)) if s > l => {
+                    Err(format!("{} is more than {}", smaller.0, larger.0))
+                }
+                _ => Ok(()),
+            }
+        }
+        at_most(
+            ("events_per_hour", self.events_per_hour),
+            ("events_per_day", self.events_per_day),
+        )?;
+        at_most(
+            ("events_per_day", self.events_per_day),
+            ("events_per_month", self.events_per_month),
+        )?;
+        at_most(
+            ("events_per_hour", self.events_per_hour),
+            ("events_per_month", self.events_per_month),
+        )?;
+        at_most(
+            ("bytes_per_hour", self.bytes_per_hour),
+            ("bytes_per_day", self.bytes_per_day),
+        )?;
+        if self.rate_limit_mode == RateLimitMode::Bucket {
+            if self.tokens_per_hour.is_none() {
+                return Err("rate_limit_mode is bucket without tokens_per_hour".to_string());
+            }
+            if self.burst == Some(0) {
+                return Err("burst is 0, no event could be published".to_string());
+            }
+        }
+        if let (Some(allowed), Some(denied)) = (&self.allowed_kinds, &self.denied_kinds) {
+            if let Some(kind) = allowed.iter().find(|k| denied.contains(k)) {
+                return Err(format!("Kind {kind} is both allowed and denied"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether any event count or byte rate limit is set
+    pub fn is_rate_limited(&self) -> bool {
+        self.limits_events() || self.limits_bytes() || self.token_bucket().is_some()
+    }
+
+    /// Whether any event count limit is set
+    pub fn limits_events(&self) -> bool {
+        self.event_window().is_some()
+    }
+
+    /// Refill rate per hour and capacity of the token bucket in bucket mode
+    pub fn token_bucket(&self) -> Option<(u64, u64)> {
+        if self.rate_limit_mode != RateLimitMode::Bucket {
+            return None;
+        }
+        let per_hour = self.tokens_per_hour?;
+        Some((per_hour, self.burst.unwrap_or(per_hour)))
+    }
+
+    /// Seconds covered by the longest event count limit, none in bucket mode
+    pub fn event_window(&self) -> Option<u64> {
+        if self.rate_limit_mode == RateLimitMode::Bucket {
+            return None;
+        }
+        [
+            (self.events_per_month, MONTH),
+            (self.events_per_day, DAY),
+            (self.events_per_hour, HOUR),
+        ]
+        .into_iter()
+        .find_map(|(limit, window)| limit.map(|_| window))
+    }
+
+    /// Whether `kind` passes the allowed and denied kinds
+    pub fn allows_kind(&self, kind: u64) -> bool {
+        self.allowed_kinds
+            .as_ref()
+            .is_none_or(|kinds| kinds.contains(&kind))
+            && !self
+                .denied_kinds
+                .as_ref()
+                .is_some_and(|kinds| kinds.contains(&kind))
+    }
+
+    /// Whether published bytes need to be recorded
+    pub fn limits_bytes(&self) -> bool {
+        self.bytes_per_hour.is_some() || self.bytes_per_day.is_some()
+    }
+}
+
+/// Detection of the same content being reposted by one author
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default)]
+pub struct DuplicateContent {
+    pub enabled: bool,
+    /// Seconds a content hash is remembered for
+    pub window: u64,
+    /// Number of repeats allowed within the window before denying
+    pub max_dupes: usize,
+}
+
+/// What to do with a contact list update when the update queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Drop the oldest queued update
+    DropOldest,
+    /// Reject the new update and deny its event
+    Reject,
+    /// Wait for space in the queue
+    #[default]
+    Block,
+}
+
+/// Decision for accounts that are not allowlisted or primary while the graph is being built
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum InitPolicy {
+    #[default]
+    Deny,
+    Permit,
+    /// Use whatever tier is already stored
+    Tier,
+}
+
+/// How a new contact list is applied to the stored follows
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ContactUpdateMode {
+    /// The new list replaces the stored follows
+    #[default]
+    Replace,
+    /// New follows are added and nothing is unfollowed
+    Merge,
+}
+
+/// A step of the admission pipeline, run in the order configured by `pipeline`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    /// With `deny_blocked_tags`, deny references to blocked pubkeys or events
+    /// Blocked authors are denied before the first stage
+    Denylist,
+    /// Skip every later stage for allowlisted authors, whose events are still recorded
+    Allowlist,
+    /// Deny by the limitation of the author's tier
+    Tier,
+    /// Deny authors over their tier's rate limits
+    RateLimit,
+}
+
+/// Pipeline used when `pipeline` is not set
+pub const DEFAULT_PIPELINE: [Stage; 4] = [
+    Stage::Denylist,
+    Stage::Allowlist,
+    Stage::Tier,
+    Stage::RateLimit,
+];
+
+/// What to do when building the graph at startup fails
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum InitFailurePolicy {
+    /// Keep serving with whatever is in the db
+    #[default]
+    Degraded,
+    /// Exit the process
+    Exit,
+}
+
+/// What to do when more primary keys are configured than `max_primary_keys`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum PrimaryKeysCapPolicy {
+    /// Refuse to start
+    #[default]
+    Refuse,
+    /// Log a warning and start anyway
+    Warn,
+}
+
+/// Decision when the DB can not be used to decide on an event
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum FailMode {
+    /// Deny every event
+    #[default]
+    FailClosed,
+    /// Permit every event
+    FailOpen,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct CircuitBreakerSettings {
+    /// Consecutive DB failures before events are decided by `degraded_mode`, never when unset
+    pub failure_threshold: Option<u32>,
+    /// Milliseconds between checks of whether the DB has recovered
+    pub probe_interval_ms: u64,
+    pub degraded_mode: FailMode,
+}
+
+impl Default for CircuitBreakerSettings {
+    fn default() -> Self {
+        Self {
+            failure_threshold: None,
+            probe_interval_ms: 5000,
+            degraded_mode: FailMode::FailClosed,
+        }
+    }
+}
+
+/// In-memory cache of tier lookups on the admission path
+/// A write changing the tier or pin of a pubkey drops its entry, so a cached tier is never stale
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct TierCacheSettings {
+    /// Max pubkeys whose tier is cached, the cache is off when 0
+    pub size: usize,
+    /// Seconds a tier is cached for
+    pub ttl_secs: u64,
+}
+
+impl Default for TierCacheSettings {
+    fn default() -> Self {
+        Self {
+            size: 10000,
+            ttl_secs: 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct UpdateQueueSettings {
+    /// Max queued contact list updates
+    pub size: usize,
+    pub overflow: OverflowPolicy,
+    /// Seconds spent applying queued updates on shutdown before dropping them
+    pub shutdown_drain_timeout: u64,
+}
+
+impl Default for UpdateQueueSettings {
+    fn default() -> Self {
+        Self {
+            size: 1000,
+            overflow: OverflowPolicy::Block,
+            shutdown_drain_timeout: 10,
+        }
+    }
+}
+
+/// Accepts a list or a single string of comma separated values, the form
+/// environment variables give lists in
+fn string_or_list<'de, D, C>(deserializer: D) -> Result<C, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    C: FromIterator<String>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrList {
+        String(String),
+        List(Vec<String>),
+    }
+
+    Ok(match StringOrList::deserialize(deserializer)? {
+        StringOrList::String(values) => values
+            .split(',')
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .map(String::from)
+            .collect(),
+        StringOrList::List(values) => values.into_iter().collect(),
+    })
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(default)]
 pub struct Info {
-    pub relay_url: String,
+    /// Relays contact lists are fetched from
+    #[serde(deserialize_with = "string_or_list")]
+    pub relay_url: Vec<String>,
     pub nostr_key: Option<String>,
+    #[serde(deserialize_with = "string_or_list")]
     pub primary_keys: HashSet<String>,
+    /// Keep admission counters across restarts
+    pub persist_metrics: bool,
+    /// Seconds between writes of persisted counters, 60 when unset
+    pub metrics_flush_secs: Option<u64>,
+    /// Max size of event content for any kind without its own cap
+    pub max_content_bytes: Option<usize>,
+    /// OTLP collector spans are exported to, requires the `otel` feature
+    pub otlp_endpoint: Option<String>,
+    /// Only fetch contact lists updated within this many days
+    pub contact_list_since_days: Option<u64>,
+    /// Max contact list events fetched per relay query
+    pub contact_list_limit: Option<usize>,
+    /// Pubkeys that are denied whatever their tier, on top of those blocked with the admin API
+    #[serde(deserialize_with = "string_or_list")]
+    pub blocklist: HashSet<String>,
+    /// Event ids that are blocked
+    pub blocked_events: HashSet<String>,
+    /// Deny events whose `p` or `e` tags reference a blocked pubkey or event
+    pub deny_blocked_tags: bool,
+    /// Block all publishing from the other tier regardless of its limitation
+    pub other_read_only: bool,
+    /// Warn about primaries without a contact list on the relay
+    pub warn_missing_primary_contacts: bool,
+    /// Pubkeys always permitted to publish regardless of tier or rate limits
+    #[serde(deserialize_with = "string_or_list")]
+    pub allowlist: HashSet<String>,
+    /// Deny accounts that are not in the graph without applying the other tier's limitation
+    pub deny_unknown: bool,
+    /// Deny events signed by another pubkey than the one the connection authenticated as,
+    /// otherwise they are decided on for the authenticated pubkey and the mismatch logged
+    pub require_auth_match: bool,
+    pub init_policy: InitPolicy,
+    /// File the startup summary is written to as JSON
+    pub startup_summary_path: Option<String>,
+    /// Seconds between sweeps demoting accounts no follower vouches for
+    pub demotion_sweep_secs: Option<u64>,
+    /// Seconds between DB compactions, never when unset
+    pub compact_interval_secs: Option<u64>,
+    /// Sanity cap on the number of primary keys
+    pub max_primary_keys: Option<usize>,
+    pub max_primary_keys_policy: PrimaryKeysCapPolicy,
+    /// Kinds denied when their content is empty
+    pub require_content_kinds: HashSet<u64>,
+    /// Decision when an internal error prevents one
+    pub fail_mode: FailMode,
+    /// Record every denied event in the DB, kept as long as event timestamps
+    pub audit_log: bool,
+    /// URL each change of an account's tier is posted to as JSON
+    pub tier_webhook_url: Option<String>,
+    pub contact_update_mode: ContactUpdateMode,
+    /// Only promote a primary's follows that follow the primary back
+    pub mutual_primary_follows: bool,
+    /// Hops from the primaries the graph is built to, 2 when unset
+    pub graph_depth: Option<u8>,
+    /// Times a failed relay fetch is retried
+    pub relay_retry_count: u32,
+    /// Milliseconds before the first retry, doubled for each retry after
+    pub relay_retry_delay_ms: u64,
+    /// Answer NIP-42 AUTH challenges of relays queried for contact lists with `nostr_key`
+    pub relay_auth: bool,
+    /// Store the NIP-65 relay lists of accounts in the graph and also fetch
+    /// contact lists from their write relays
+    pub nip65_relays: bool,
+    /// Seconds a relay query may take before it counts as failed, unbounded when unset
+    pub relay_timeout_secs: Option<u64>,
+    /// Keep contact lists within `graph_depth` hops current from a live relay subscription
+    pub subscribe_contact_lists: bool,
+    pub init_failure_policy: InitFailurePolicy,
+    /// Clear and rebuild the whole graph on start instead of keeping the db
+    pub rebuild_on_start: bool,
+    /// Keep the DB in a temp file removed on exit, the graph is rebuilt on every start
+    pub ephemeral_db: bool,
+    /// Serve the admin gRPC service for looking up account tiers
+    pub enable_admin_api: bool,
+    /// Order admission stages run in, `DEFAULT_PIPELINE` when unset
+    pub pipeline: Option<Vec<Stage>>,
+    /// Deny events whose signature does not verify
+    pub verify_signatures: bool,
+    /// Addresses of relays connecting over gRPC whose events are admitted without
+    /// verifying signatures, except contact lists, deletions and relay lists
+    pub trusted_verification_peers: HashSet<IpAddr>,
+    /// Max accounts written per transaction by bulk operations, unbounded when unset
+    pub max_batch_size: Option<usize>,
+    /// Address the gRPC server listens on, `[::1]:50051` when unset
+    pub listen_addr: Option<String>,
+    /// Address the admin gRPC service listens on, `[::1]:50052` when unset
+    /// It is never served on `listen_addr` so it can be kept off the network
+    pub admin_addr: Option<String>,
+    /// Address the HTTP status server serving `/readyz`, `/stats` and `/metrics` listens on,
+    /// not served when unset
+    pub status_addr: Option<String>,
+    /// PEM certificate chain the gRPC listener serves TLS with, plaintext when unset
+    pub tls_cert: Option<String>,
+    /// PEM private key of `tls_cert`
+    pub tls_key: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
@@ -26,42 +460,507 @@ pub struct Settings {
     pub tertiary: Limitation,
     pub quaternary: Limitation,
     pub other: Limitation,
+    /// Limitation for the grace tier, the other tier's when unset
+    pub grace: Option<Limitation>,
+    pub duplicate_content: DuplicateContent,
+    /// Content size caps by event kind, overriding `max_content_bytes`
+    #[serde(default, with = "kind_map")]
+    pub max_content_bytes_per_kind: HashMap<u64, usize>,
+    pub update_queue: UpdateQueueSettings,
+    pub circuit_breaker: CircuitBreakerSettings,
+    pub tier_cache: TierCacheSettings,
+    /// Config file the settings were read from, `None` when using defaults
+    #[serde(skip)]
+    pub config_source: Option<String>,
 }
+
+/// Config keys are always strings so maps keyed by kind are stored with string keys
+mod kind_map {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::collections::HashMap;
+
+    pub fn serialize<S, V>(map: &HashMap<u64, V>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+        V: Serialize,
+    {
+        serializer.collect_map(map.iter().map(|(k, v)| (k.to_string(), v)))
+    }
+
+    pub fn deserialize<'de, D, V>(deserializer: D) -> Result<HashMap<u64, V>, D::Error>
+    where
+        D: Deserializer<'de>,
+        V: Deserialize<'de>,
+    {
+        let map: HashMap<String, V> = HashMap::deserialize(deserializer)?;
+        map.into_iter()
+            .map(|(k, v)| k.parse().map(|k| (k, v)).map_err(D::Error::custom))
+            .collect()
+    }
+}
+
+/// Hex form of a hex or bech32 `npub` public key
+pub fn normalize_pubkey(key: &str) -> Result<String, ConfigError> {
+    let key = key.trim();
+    let pubkey = if key.starts_with("npub") {
+        XOnlyPublicKey::from_bech32(key).ok()
+    } else {
+        XOnlyPublicKey::from_str(key).ok()
+    };
+    pubkey
+        .map(|pubkey| pubkey.to_string())
+        .ok_or_else(|| ConfigError::Message(format!("Invalid public key {key:?}")))
+}
+
 impl Settings {
     #[must_use]
+    /// Settings from `config_file_name`, or `config.toml` when `None`, with
+    /// `NAUTHZ_` environment variables over them
+    /// Defaults are only used when `config.toml` does not exist and no variable
+    /// is set, a file that can not be read or settings that fail validation
+    /// are an error, as is a missing file that was asked for
     pub fn new(config_file_name: &Option<String>) -> Self {
-        let default_settings = Self::default();
-        // attempt to construct settings with file
-        let from_file = Self::new_from_default(&default_settings, config_file_name);
-        match from_file {
-            Ok(f) => f,
-            Err(e) => {
-                warn!("Error reading config file ({:?})", e);
-                default_settings
+        let config_file = config_file_name.as_deref().unwrap_or("config.toml");
+        let env: Vec<(String, String)> = std::env::vars()
+            .filter(|(key, _)| key.starts_with(ENV_PREFIX))
+            .collect();
+        let exists = Path::new(config_file).exists();
+        if !exists {
+            if config_file_name.is_some() {
+                panic!("Config file {config_file} not found");
+            }
+            if env.is_empty() {
+                warn!("No config file {config_file}, using defaults");
+                return Self::default();
             }
+            warn!("No config file {config_file}, using defaults and environment");
+        }
+
+        match Self::from_sources(exists.then_some(config_file), env) {
+            Ok(settings) => settings,
+            Err(e) => panic!("Invalid config {config_file}: {e}"),
+        }
+    }
+
+    /// Defaults overridden by `config_file` then by the `NAUTHZ_` variables
+    /// of `env`, normalized and validated
+    pub fn from_sources(
+        config_file: Option<&str>,
+        env: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self, ConfigError> {
+        let mut settings = Self::new_from_default(&Self::default(), config_file, env)?;
+        settings.normalize_keys()?;
+        settings.check_primary_keys()?;
+        settings.check_tls()?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    /// Checks invariants the service relies on but deserializing can not
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.info.relay_url.is_empty() {
+            return Err(ConfigError::Message("No relay_url configured".to_string()));
+        }
+        if let Some(depth) = self.info.graph_depth {
+            if depth > MAX_GRAPH_DEPTH {
+                return Err(ConfigError::Message(format!(
+                    "graph_depth is {depth}, max is {MAX_GRAPH_DEPTH}"
+                )));
+            }
+        }
+        let tiers = [
+            ("primary", Some(&self.primary)),
+            ("secondary", Some(&self.secondary)),
+            ("tertiary", Some(&self.tertiary)),
+            ("quaternary", Some(&self.quaternary)),
+            ("other", Some(&self.other)),
+            ("grace", self.grace.as_ref()),
+        ];
+        for (name, limitation) in tiers {
+            if let Some(limitation) = limitation {
+                limitation
+                    .check()
+                    .map_err(|e| ConfigError::Message(format!("[{name}] {e}")))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Limitation applied to accounts in `tier`
+    pub fn limitation(&self, tier: Tier) -> Limitation {
+        match tier {
+            Tier::Primary => self.primary.clone(),
+            Tier::Secondary => self.secondary.clone(),
+            Tier::Tertiary => self.tertiary.clone(),
+            Tier::Quaternary => self.quaternary.clone(),
+            Tier::Other if self.info.other_read_only => Limitation {
+                can_publish: false,
+                ..self.other.clone()
+            },
+            Tier::Other => self.other.clone(),
+            Tier::Grace => self
+                .grace
+                .clone()
+                .unwrap_or_else(|| self.limitation(Tier::Other)),
+        }
+    }
+
+    /// How long event timestamps are kept, the longest event count window
+    /// of any tier and at least a day
+    pub fn event_retention(&self) -> u64 {
+        [
+            Tier::Primary,
+            Tier::Secondary,
+            Tier::Tertiary,
+            Tier::Quaternary,
+            Tier::Grace,
+            Tier::Other,
+        ]
+        .into_iter()
+        .filter_map(|tier| self.limitation(tier).event_window())
+        .fold(DAY, u64::max)
+    }
+
+    /// Admission stages in the order they run
+    pub fn pipeline(&self) -> &[Stage] {
+        self.info.pipeline.as_deref().unwrap_or(&DEFAULT_PIPELINE)
+    }
+
+    /// Max content size for an event kind
+    pub fn max_content_bytes(&self, kind: u64) -> Option<usize> {
+        self.max_content_bytes_per_kind
+            .get(&kind)
+            .copied()
+            .or(self.info.max_content_bytes)
+    }
+
+    /// Converts configured pubkeys to hex so they match event authors
+    /// Keys may be given as hex or as bech32 `npub`
+    pub fn normalize_keys(&mut self) -> Result<(), ConfigError> {
+        for keys in [
+            &mut self.info.primary_keys,
+            &mut self.info.allowlist,
+            &mut self.info.blocklist,
+        ] {
+            *keys = keys
+                .iter()
+                .map(|key| normalize_pubkey(key))
+                .collect::<Result<_, _>>()?;
+        }
+        Ok(())
+    }
+
+    /// Checks the number of primary keys against `max_primary_keys`
+    pub fn check_primary_keys(&self) -> Result<(), ConfigError> {
+        let count = self.info.primary_keys.len();
+        match self.info.max_primary_keys {
+            Some(max) if count > max => {
+                let message = format!("{count} primary keys configured, max is {max}");
+                match self.info.max_primary_keys_policy {
+                    PrimaryKeysCapPolicy::Refuse => Err(ConfigError::Message(message)),
+                    PrimaryKeysCapPolicy::Warn => {
+                        warn!("{message}");
+                        Ok(())
+                    }
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks that a TLS certificate and key are set together or not at all
+    pub fn check_tls(&self) -> Result<(), ConfigError> {
+        match (&self.info.tls_cert, &self.info.tls_key) {
+            (Some(_), None) => Err(ConfigError::Message(
+                "tls_cert is set without tls_key".to_string(),
+            )),
+            (None, Some(_)) => Err(ConfigError::Message(
+                "tls_key is set without tls_cert".to_string(),
+            )),
+            _ => Ok(()),
         }
     }
 
     fn new_from_default(
         default: &Settings,
-        config_file_name: &Option<String>,
+        config_file: Option<&str>,
+        env: impl IntoIterator<Item = (String, String)>,
     ) -> Result<Self, ConfigError> {
-        let default_config_file_name = "config.toml".to_string();
-        let config: &String = match config_file_name {
-            Some(value) => value,
-            None => &default_config_file_name,
-        };
-        let builder = Config::builder();
-        let config: Config = builder
+        // `NAUTHZ_INFO__RELAY_URL` sets `info.relay_url`, the prefix is taken
+        // off here as the environment source would expect `NAUTHZ__`
+        let env: Map<String, String> = env
+            .into_iter()
+            .filter_map(|(key, value)| Some((key.strip_prefix(ENV_PREFIX)?.to_string(), value)))
+            .collect();
+
+        let mut builder = Config::builder()
             // use defaults
-            .add_source(Config::try_from(default)?)
+            .add_source(Config::try_from(default)?);
+        if let Some(config_file) = config_file {
             // override with file contents
-            .add_source(File::with_name(config))
+            builder = builder.add_source(File::with_name(config_file));
+        }
+        let config: Config = builder
+            // override with environment
+            .add_source(
+                Environment::default()
+                    .separator("__")
+                    .try_parsing(true)
+                    .source(Some(env)),
+            )
             .build()?;
-        let settings: Settings = config.try_deserialize()?;
-
-        // println!("{settings:?}");
+        let mut settings: Settings = config.try_deserialize()?;
+        settings.config_source = config_file.map(String::from);
 
         Ok(settings)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::temp_path;
+    use nostr_sdk::prelude::ToBech32;
+
+    #[test]
+    fn test_event_retention() {
+        let mut settings = Settings::default();
+        assert_eq!(DAY, settings.event_retention());
+
+        settings.secondary.events_per_hour = Some(10);
+        assert_eq!(DAY, settings.event_retention());
+
+        settings.grace = Some(Limitation {
+            events_per_month: Some(100),
+            ..Default::default()
+        });
+        assert_eq!(MONTH, settings.event_retention());
+    }
+
+    #[test]
+    fn test_normalize_keys() {
+        let hex = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9";
+        let npub = XOnlyPublicKey::from_str(hex).unwrap().to_bech32().unwrap();
+        let mut settings = Settings::default();
+        settings.info.primary_keys = HashSet::from([npub.clone(), hex.to_uppercase()]);
+        settings.info.blocklist = HashSet::from([format!(" {npub} ")]);
+        settings.normalize_keys().unwrap();
+        assert_eq!(HashSet::from([hex.to_string()]), settings.info.primary_keys);
+        assert_eq!(HashSet::from([hex.to_string()]), settings.info.blocklist);
+
+        for malformed in ["npub1xyz", "7995c67e", "not a key"] {
+            settings.info.primary_keys = HashSet::from([malformed.to_string()]);
+            let err = settings.normalize_keys().unwrap_err().to_string();
+            assert!(err.contains(malformed));
+        }
+    }
+
+    #[test]
+    fn test_max_primary_keys() {
+        let mut settings = Settings::default();
+        settings.info.primary_keys = (0..3).map(|i| i.to_string()).collect();
+        assert!(settings.check_primary_keys().is_ok());
+
+        settings.info.max_primary_keys = Some(3);
+        assert!(settings.check_primary_keys().is_ok());
+
+        settings.info.max_primary_keys = Some(2);
+        assert!(settings.check_primary_keys().is_err());
+
+        settings.info.max_primary_keys_policy = PrimaryKeysCapPolicy::Warn;
+        assert!(settings.check_primary_keys().is_ok());
+    }
+
+    #[test]
+    fn test_check_tls() {
+        let mut settings = Settings::default();
+        assert!(settings.check_tls().is_ok());
+
+        settings.info.tls_cert = Some("cert.pem".to_string());
+        assert!(settings.check_tls().is_err());
+
+        settings.info.tls_key = Some("key.pem".to_string());
+        assert!(settings.check_tls().is_ok());
+
+        settings.info.tls_cert = None;
+        assert!(settings.check_tls().is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut settings = Settings::default();
+        assert!(settings.validate().is_err());
+        settings.info.relay_url = vec!["wss://relay.one".to_string()];
+        assert!(settings.validate().is_ok());
+
+        settings.info.graph_depth = Some(MAX_GRAPH_DEPTH + 1);
+        assert!(settings.validate().is_err());
+        settings.info.graph_depth = Some(MAX_GRAPH_DEPTH);
+        assert!(settings.validate().is_ok());
+
+        settings.secondary.events_per_hour = Some(100);
+        settings.secondary.events_per_day = Some(50);
+        let err = settings.validate().unwrap_err().to_string();
+        assert!(err.contains("[secondary] events_per_hour is more than events_per_day"));
+        settings.secondary.events_per_day = Some(1000);
+        settings.secondary.events_per_month = Some(10);
+        assert!(settings.validate().is_err());
+        settings.secondary.events_per_month = None;
+        assert!(settings.validate().is_ok());
+
+        settings.grace = Some(Limitation {
+            allowed_kinds: Some(vec![1, 7]),
+            denied_kinds: Some(vec![7]),
+            ..Default::default()
+        });
+        assert!(settings.validate().is_err());
+
+        settings.grace = Some(Limitation {
+            rate_limit_mode: RateLimitMode::Bucket,
+            ..Default::default()
+        });
+        assert!(settings.validate().is_err());
+        settings.grace = Some(Limitation {
+            rate_limit_mode: RateLimitMode::Bucket,
+            tokens_per_hour: Some(60),
+            ..Default::default()
+        });
+        assert!(settings.validate().is_ok());
+        assert_eq!(
+            settings.grace.as_ref().unwrap().token_bucket(),
+            Some((60, 60))
+        );
+    }
+
+    #[test]
+    fn test_config_file() {
+        // Only the default file falls back to defaults when missing
+        let missing = std::env::temp_dir().join("contact-group-auth-missing.toml");
+        let missing = Some(missing.to_string_lossy().to_string());
+        assert!(std::panic::catch_unwind(|| Settings::new(&missing)).is_err());
+
+        let invalid_file = temp_path(".toml");
+        std::fs::write(
+            &invalid_file,
+            "[info]\nrelay_url = \"wss://relay.one\"\ngraph_depth = \"far\"\n",
+        )
+        .unwrap();
+        let invalid = Some(invalid_file.to_string_lossy().to_string());
+        assert!(std::panic::catch_unwind(|| Settings::new(&invalid)).is_err());
+
+        let valid = temp_path(".toml");
+        std::fs::write(&valid, "[info]\nrelay_url = \"wss://relay.one\"\n").unwrap();
+        let settings = Settings::new(&Some(valid.to_string_lossy().to_string()));
+        assert_eq!(vec!["wss://relay.one".to_string()], settings.info.relay_url);
+    }
+
+    #[test]
+    fn test_env_overrides() {
+        let a = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9";
+        let b = "04918dfc36c93e7db6cc0d60f37e1522f1c36b64d3f4b424c532d7c595febbc5";
+        let temp = temp_path(".toml");
+        std::fs::write(
+            &temp,
+            "[info]\nrelay_url = \"wss://file.one\"\n[secondary]\ncan_publish = true\nevents_per_hour = 10\n",
+        )
+        .unwrap();
+        let file = temp.to_string_lossy().to_string();
+
+        let env = |vars: &[(&str, &str)]| -> Vec<(String, String)> {
+            vars.iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+        let settings = Settings::from_sources(
+            Some(&file),
+            env(&[
+                ("NAUTHZ_INFO__RELAY_URL", "wss://env.one, wss://env.two"),
+                ("NAUTHZ_INFO__PRIMARY_KEYS", &format!("{a},{b}")),
+                ("NAUTHZ_INFO__LISTEN_ADDR", "0.0.0.0:50051"),
+                ("NAUTHZ_INFO__AUDIT_LOG", "true"),
+                ("NAUTHZ_SECONDARY__EVENTS_PER_HOUR", "100"),
+                ("OTHER__CAN_PUBLISH", "true"),
+            ]),
+        )
+        .unwrap();
+
+        assert_eq!(
+            vec!["wss://env.one".to_string(), "wss://env.two".to_string()],
+            settings.info.relay_url
+        );
+        assert_eq!(
+            HashSet::from([a.to_string(), b.to_string()]),
+            settings.info.primary_keys
+        );
+        assert_eq!(Some("0.0.0.0:50051"), settings.info.listen_addr.as_deref());
+        assert!(settings.info.audit_log);
+        // Keys the environment leaves alone keep the file's values
+        assert!(settings.secondary.can_publish);
+        assert_eq!(Some(100), settings.secondary.events_per_hour);
+        assert!(!settings.other.can_publish);
+        assert_eq!(Some(file.clone()), settings.config_source);
+
+        // The environment alone is enough without a file
+        let settings =
+            Settings::from_sources(None, env(&[("NAUTHZ_INFO__RELAY_URL", "wss://env.one")]))
+                .unwrap();
+        assert_eq!(vec!["wss://env.one".to_string()], settings.info.relay_url);
+        assert_eq!(None, settings.config_source);
+
+        // and is validated like a file
+        assert!(Settings::from_sources(
+            None,
+            env(&[
+                ("NAUTHZ_INFO__RELAY_URL", "wss://env.one"),
+                ("NAUTHZ_INFO__PRIMARY_KEYS", "not a key"),
+            ])
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_relay_url_string_or_list() {
+        let single = r#"
+            [info]
+            relay_url = "wss://relay.one"
+        "#;
+        let many = r#"
+            [info]
+            relay_url = ["wss://relay.one", "wss://relay.two"]
+        "#;
+
+        let parse = |toml: &str| -> Vec<String> {
+            let settings: Settings = Config::builder()
+                .add_source(Config::try_from(&Settings::default()).unwrap())
+                .add_source(File::from_str(toml, config::FileFormat::Toml))
+                .build()
+                .unwrap()
+                .try_deserialize()
+                .unwrap();
+            settings.info.relay_url
+        };
+        assert_eq!(vec!["wss://relay.one".to_string()], parse(single));
+        assert_eq!(
+            vec!["wss://relay.one".to_string(), "wss://relay.two".to_string()],
+            parse(many)
+        );
+    }
+
+    #[test]
+    fn test_grace_limitation() {
+        let mut settings = Settings::default();
+        settings.other.can_publish = true;
+        settings.info.other_read_only = true;
+        assert!(!settings.limitation(Tier::Grace).can_publish);
+
+        settings.grace = Some(Limitation {
+            can_publish: true,
+            events_per_hour: Some(5),
+            ..Default::default()
+        });
+        let limitation = settings.limitation(Tier::Grace);
+        assert!(limitation.can_publish);
+        assert_eq!(Some(5), limitation.events_per_hour);
+    }
+}
@@ -1,12 +1,18 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
 use redb::{
     Database, MultimapTableDefinition, ReadableMultimapTable, ReadableTable, TableDefinition,
 };
 use serde::{Deserialize, Serialize};
-use tracing::debug;
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
 
 use crate::error::Error;
+use crate::snapshot::GraphSnapshot;
+use crate::tier_cache::TierCache;
+use crate::utils::unix_time;
 // key is hex pubkey value is name
 const ACCOUNTTABLE: TableDefinition<&str, u8> = TableDefinition::new("account");
 const EVENTTABLE: MultimapTableDefinition<&str, u64> = MultimapTableDefinition::new("event");
@@ -15,14 +21,51 @@ const FOLLOWSTABLE: MultimapTableDefinition<&str, &str> = MultimapTableDefinitio
 // Key is pubkey value is pubkey of who follows that pubkey
 const FOLLOWERSTABLE: MultimapTableDefinition<&str, &str> =
     MultimapTableDefinition::new("followers");
-
-#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq, PartialOrd, Ord)]
+// Key is counter name value is cumulative count
+const METRICSTABLE: TableDefinition<&str, u64> = TableDefinition::new("metrics");
+// Key is "pubkey:kind" value is when that kind was last published
+const KINDTABLE: TableDefinition<&str, u64> = TableDefinition::new("kind");
+// Key is "pubkey:timestamp" value is content bytes published that second
+const BYTESTABLE: TableDefinition<&str, u64> = TableDefinition::new("bytes");
+// Key is pubkey value is when it was first seen in an event or the graph
+const FIRSTSEENTABLE: TableDefinition<&str, u64> = TableDefinition::new("first_seen");
+// Key is pubkey value is a tier pinned by an admin, overriding the graph
+const PINTABLE: TableDefinition<&str, u8> = TableDefinition::new("pin");
+// Key is a blocked pubkey value is when it was blocked
+const BLOCKTABLE: TableDefinition<&str, u64> = TableDefinition::new("block");
+// Key is pubkey value is `created_at` of its last applied contact list
+const CONTACTLISTTABLE: TableDefinition<&str, u64> = TableDefinition::new("contact_list");
+// Key is "follower:followee" value is the relay hint of the follower's `p` tag
+const RELAYHINTTABLE: TableDefinition<&str, &str> = TableDefinition::new("relay_hint");
+// Id of the last contact list applied per pubkey
+const CONTACTLISTIDTABLE: TableDefinition<&str, &str> = TableDefinition::new("contact_list_id");
+// Key is "pubkey:timestamp:n" value is the denial as JSON
+const DENIALTABLE: TableDefinition<&str, &str> = TableDefinition::new("denial");
+// Key is pubkey value is its token bucket as JSON
+const BUCKETTABLE: TableDefinition<&str, &str> = TableDefinition::new("token_bucket");
+// Key is pubkey value is its NIP-65 relay list as JSON
+const RELAYLISTTABLE: TableDefinition<&str, &str> = TableDefinition::new("relay_list");
+// Key is the name of a value about the whole graph, such as `GRAPH_BUILT_AT`
+const GRAPHMETATABLE: TableDefinition<&str, u64> = TableDefinition::new("graph_meta");
+
+/// Unix time the graph was last built from the relays
+const GRAPH_BUILT_AT: &str = "built_at";
+
+/// Times an account's tier can change in one propagation before it stops
+/// being walked, a cycle demotes itself a tier per pass so settles within this
+const MAX_TIER_CHANGES: usize = 8;
+
+/// Stored as its discriminant so new variants take new values
+/// Ordered by distance from a primary, see `Tier::rank`
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq, Hash)]
 pub enum Tier {
     Primary = 0,
     Secondary = 1,
     Tertiary = 2,
     Quaternary = 3,
     Other = 4,
+    /// Barely reachable, one hop past quaternary
+    Grace = 5,
 }
 
 impl From<u8> for Tier {
@@ -32,32 +75,59 @@ impl From<u8> for Tier {
             1 => Tier::Secondary,
             2 => Tier::Tertiary,
             3 => Tier::Quaternary,
+            5 => Tier::Grace,
             _ => Tier::Other,
         }
     }
 }
 
+impl PartialOrd for Tier {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Tier {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.rank().cmp(&other.rank())
+    }
+}
+
 impl Tier {
     // Its like golf
     // lowest tier has most permission
-    /*
-    fn lower_tier(&self) -> Tier {
+    /// Tier one hop closer to a primary, the inverse of `raise_tier`
+    pub fn lower_tier(&self) -> Tier {
         match self {
             Tier::Primary => Tier::Primary, // Can't move up from the first variant
             Tier::Secondary => Tier::Primary,
             Tier::Tertiary => Tier::Secondary,
             Tier::Quaternary => Tier::Tertiary,
-            Tier::Other => Tier::Quaternary,
+            Tier::Grace => Tier::Quaternary,
+            Tier::Other => Tier::Grace,
+        }
+    }
+
+    /// Hops from a primary, `Other` is furthest
+    fn rank(&self) -> u8 {
+        match self {
+            Tier::Primary => 0,
+            Tier::Secondary => 1,
+            Tier::Tertiary => 2,
+            Tier::Quaternary => 3,
+            Tier::Grace => 4,
+            Tier::Other => 5,
         }
     }
-    */
 
-    fn raise_tier(&self) -> Tier {
+    /// Tier one hop further from a primary
+    pub fn raise_tier(&self) -> Tier {
         match self {
             Tier::Primary => Tier::Secondary,
             Tier::Secondary => Tier::Tertiary,
             Tier::Tertiary => Tier::Quaternary,
-            Tier::Quaternary => Tier::Other,
+            Tier::Quaternary => Tier::Grace,
+            Tier::Grace => Tier::Other,
             Tier::Other => Tier::Other,
         }
     }
@@ -69,9 +139,89 @@ pub struct Account {
     pub tier: Tier,
 }
 
+/// Audit log entry of a denied event
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Denial {
+    pub timestamp: u64,
+    pub pubkey: String,
+    pub tier: Tier,
+    pub kind: u64,
+    pub message: String,
+}
+
+/// Tokens left to an account rate limited by a token bucket
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct TokenBucket {
+    pub tokens: f64,
+    /// When `tokens` was last topped up
+    pub refilled_at: u64,
+}
+
+impl TokenBucket {
+    /// Bucket with `tokens` topped up to `now` at `per_hour`, never above `burst`
+    pub fn refill(self, per_hour: u64, burst: u64, now: u64) -> Self {
+        let elapsed = now.saturating_sub(self.refilled_at) as f64;
+        Self {
+            tokens: (self.tokens + elapsed * per_hour as f64 / 3600.0).min(burst as f64),
+            refilled_at: now.max(self.refilled_at),
+        }
+    }
+}
+
+/// Relays an account publishes to from its NIP-65 relay list
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RelayList {
+    pub write: Vec<String>,
+    /// Creation time of the relay list event
+    pub created_at: u64,
+}
+
+/// Timestamp of a `pubkey:timestamp:n` denial table key
+fn denial_row_time(key: &str) -> Option<u64> {
+    key.rsplit(':').nth(1)?.parse().ok()
+}
+
+/// Timestamp of a `pubkey:timestamp` bytes table key
+fn byte_row_time(key: &str) -> Option<u64> {
+    key.rsplit_once(':')?.1.parse().ok()
+}
+
+/// Numbers the files of ephemeral DBs opened by this process
+static EPHEMERAL_DBS: AtomicUsize = AtomicUsize::new(0);
+
+/// Edges read per transaction when walking the whole follows table
+const FOLLOW_EDGES_PAGE: usize = 1000;
+
 pub struct Db {
     db: Database,
+    /// File the DB is stored in
+    path: String,
     primary: HashSet<String>,
+    /// Primaries only promote accounts that follow them back
+    mutual_primary_follows: bool,
+    /// The file is removed on drop
+    ephemeral: bool,
+    /// Receives the tier changes written by `update_account`
+    tier_changes: Option<mpsc::UnboundedSender<TierChange>>,
+    /// Told the pubkeys whose tier or pin is written so it drops their cached tiers
+    tier_cache: Option<Arc<TierCache>>,
+}
+
+/// Move of an account between tiers
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TierChange {
+    pub pubkey: String,
+    /// `None` when the account was not in the graph
+    pub old_tier: Option<Tier>,
+    pub new_tier: Tier,
+}
+
+impl Drop for Db {
+    fn drop(&mut self) {
+        if self.ephemeral {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
 }
 
 impl Default for Db {
@@ -82,8 +232,29 @@ impl Default for Db {
 
 impl Db {
     pub fn new(primary: HashSet<String>) -> Self {
-        debug!("Creating DB");
-        let db = Database::create("my_db.redb").unwrap();
+        Self::open("my_db.redb", primary)
+    }
+
+    /// DB in a temp file of its own that is removed when the DB is dropped
+    /// For tests and for deployments that rebuild the graph on every start
+    pub fn new_in_memory(primary: HashSet<String>) -> Self {
+        let n = EPHEMERAL_DBS.fetch_add(1, Ordering::SeqCst);
+        let path = std::env::temp_dir().join(format!(
+            "contact-group-auth-ephemeral-{}-{n}.redb",
+            std::process::id()
+        ));
+        // Left behind by an earlier process with the same id
+        let _ = std::fs::remove_file(&path);
+
+        let mut db = Self::open(&path.to_string_lossy(), primary);
+        db.ephemeral = true;
+        db
+    }
+
+    /// Opens or creates the DB at `path`
+    pub fn open(path: &str, primary: HashSet<String>) -> Self {
+        debug!("Creating DB at {path}");
+        let db = Database::create(path).unwrap();
         //  db.set_write_strategy(WriteStrategy::TwoPhase).unwrap();
         let write_txn = db.begin_write().unwrap();
         {
@@ -92,19 +263,149 @@ impl Db {
             let _ = write_txn.open_multimap_table(EVENTTABLE).unwrap();
             let _ = write_txn.open_multimap_table(FOLLOWSTABLE).unwrap();
             let _ = write_txn.open_multimap_table(FOLLOWERSTABLE).unwrap();
+            let _ = write_txn.open_table(METRICSTABLE).unwrap();
+            let _ = write_txn.open_table(KINDTABLE).unwrap();
+            let _ = write_txn.open_table(BYTESTABLE).unwrap();
+            let _ = write_txn.open_table(FIRSTSEENTABLE).unwrap();
+            let _ = write_txn.open_table(PINTABLE).unwrap();
+            let _ = write_txn.open_table(BLOCKTABLE).unwrap();
+            let _ = write_txn.open_table(CONTACTLISTTABLE).unwrap();
+            let _ = write_txn.open_table(RELAYHINTTABLE).unwrap();
+            let _ = write_txn.open_table(CONTACTLISTIDTABLE).unwrap();
+            let _ = write_txn.open_table(DENIALTABLE).unwrap();
+            let _ = write_txn.open_table(BUCKETTABLE).unwrap();
+            let _ = write_txn.open_table(GRAPHMETATABLE).unwrap();
+            let _ = write_txn.open_table(RELAYLISTTABLE).unwrap();
         }
         write_txn.commit().unwrap();
 
-        Self { db, primary }
+        Self {
+            db,
+            path: path.to_string(),
+            primary,
+            mutual_primary_follows: false,
+            ephemeral: false,
+            tier_changes: None,
+            tier_cache: None,
+        }
+    }
+
+    /// Copies every table into a new file, leaving behind the free space of
+    /// removed entries, and swaps it in for the current file
+    /// Returns the file size before and after
+    pub fn compact(&mut self) -> Result<(u64, u64), Error> {
+        let before = std::fs::metadata(&self.path)?.len();
+        let compact_path = format!("{}.compact", self.path);
+        if std::path::Path::new(&compact_path).exists() {
+            std::fs::remove_file(&compact_path)?;
+        }
+
+        let compacted = Database::create(&compact_path)?;
+        {
+            let read_txn = self.db.begin_read()?;
+            let write_txn = compacted.begin_write()?;
+
+            macro_rules! copy_table {
+                ($table:expr) => {{
+                    let source = read_txn.open_table($table)?;
+                    let mut dest = write_txn.open_table($table)?;
+                    for (key, value) in source.iter()? {
+                        dest.insert(key.value(), value.value())?;
+                    }
+                }};
+            }
+            macro_rules! copy_multimap_table {
+                ($table:expr) => {{
+                    let source = read_txn.open_multimap_table($table)?;
+                    let mut dest = write_txn.open_multimap_table($table)?;
+                    for (key, values) in source.iter()? {
+                        for value in values {
+                            dest.insert(key.value(), value.value())?;
+                        }
+                    }
+                }};
+            }
+
+            copy_table!(ACCOUNTTABLE);
+            copy_multimap_table!(EVENTTABLE);
+            copy_multimap_table!(FOLLOWSTABLE);
+            copy_multimap_table!(FOLLOWERSTABLE);
+            copy_table!(METRICSTABLE);
+            copy_table!(KINDTABLE);
+            copy_table!(BYTESTABLE);
+            copy_table!(FIRSTSEENTABLE);
+            copy_table!(PINTABLE);
+            copy_table!(BLOCKTABLE);
+            copy_table!(CONTACTLISTTABLE);
+            copy_table!(RELAYHINTTABLE);
+            copy_table!(CONTACTLISTIDTABLE);
+            copy_table!(DENIALTABLE);
+            copy_table!(BUCKETTABLE);
+            copy_table!(GRAPHMETATABLE);
+            copy_table!(RELAYLISTTABLE);
+            write_txn.commit()?;
+        }
+
+        // Drops the old database before its file is replaced
+        self.db = compacted;
+        std::fs::rename(&compact_path, &self.path)?;
+
+        let after = std::fs::metadata(&self.path)?.len();
+        Ok((before, after))
+    }
+
+    /// Drops cached tiers from `cache` as tiers and pins are written
+    pub fn set_tier_cache(&mut self, cache: Arc<TierCache>) {
+        self.tier_cache = Some(cache);
+    }
+
+    /// Drops the cached tiers of `pubkeys` once their tier or pin was written
+    fn tiers_written<'a>(&self, pubkeys: impl IntoIterator<Item = &'a str>) {
+        if let Some(cache) = &self.tier_cache {
+            cache.invalidate(pubkeys);
+        }
+    }
+
+    /// Drops every cached tier once the whole graph was replaced
+    fn all_tiers_written(&self) {
+        if let Some(cache) = &self.tier_cache {
+            cache.clear();
+        }
+    }
+
+    pub fn set_mutual_primary_follows(&mut self, mutual: bool) {
+        self.mutual_primary_follows = mutual;
+    }
+
+    /// Sends every tier change made by `update_account` to `sender`
+    pub fn set_tier_changes(&mut self, sender: mpsc::UnboundedSender<TierChange>) {
+        self.tier_changes = Some(sender);
+    }
+
+    /// Whether `follower` at `follower_tier` counts towards the tier of `pubkey`
+    fn vouches(&self, follower: &str, follower_tier: Tier, pubkey: &str) -> Result<bool, Error> {
+        if !self.mutual_primary_follows || follower_tier != Tier::Primary {
+            return Ok(true);
+        }
+        Ok(self.get_follows(pubkey)?.contains(follower))
+    }
+
+    /// Whether `pubkey` is a configured primary
+    pub fn is_primary(&self, pubkey: &str) -> bool {
+        self.primary.contains(pubkey)
     }
 
     pub fn write_account(&self, account: &Account) -> Result<(), Error> {
         let write_txn = self.db.begin_write()?;
-        {
+        let changed = {
             let mut table = write_txn.open_table(ACCOUNTTABLE)?;
-            table.insert(account.pubkey.as_str(), account.tier as u8)?;
+            let old = table.insert(account.pubkey.as_str(), account.tier as u8)?;
+            old.map(|t| t.value()) != Some(account.tier as u8)
+        };
+        write_txn.commit()?;
+        if changed {
+            self.tiers_written([account.pubkey.as_str()]);
         }
-        write_txn.commit().unwrap();
         Ok(())
     }
 
@@ -121,15 +422,19 @@ impl Db {
         Ok(None)
     }
 
-    pub fn read_all_accounts(&self) -> Result<(), Error> {
-        debug!("Registered accounts");
+    /// Every account in key order
+    pub fn read_all_accounts(&self) -> Result<Vec<Account>, Error> {
         let read_txn = self.db.begin_read()?;
         let table = read_txn.open_table(ACCOUNTTABLE)?;
 
-        for a in table.iter()? {
-            debug!("{:?}, {}", a.0.value(), a.1.value());
+        let mut accounts = Vec::new();
+        for (pubkey, tier) in table.iter()? {
+            accounts.push(Account {
+                pubkey: pubkey.value().to_string(),
+                tier: Tier::from(tier.value()),
+            });
         }
-        Ok(())
+        Ok(accounts)
     }
 
     pub fn write_event(&self, pubkey: &str, timestamp: u64) -> Result<(), Error> {
@@ -137,591 +442,2181 @@ impl Db {
         {
             let mut table = write_txn.open_multimap_table(EVENTTABLE)?;
             table.insert(pubkey, timestamp)?;
+
+            let mut first_seen_table = write_txn.open_table(FIRSTSEENTABLE)?;
+            let first_seen = first_seen_table.get(pubkey)?.map(|t| t.value());
+            if first_seen.is_none_or(|t| timestamp < t) {
+                first_seen_table.insert(pubkey, timestamp)?;
+            }
         }
-        write_txn.commit().unwrap();
+        write_txn.commit()?;
         Ok(())
     }
 
-    pub fn get_events(&self, pubkey: &str) -> Result<Vec<u64>, Error> {
-        let read_txn = self.db.begin_read()?;
-        let table = read_txn.open_multimap_table(EVENTTABLE)?;
+    /// Pins `pubkey` to `tier` regardless of the graph
+    pub fn write_pin(&self, pubkey: &str, tier: Tier) -> Result<(), Error> {
+        let write_txn = self.db.begin_write()?;
+        {
+            let mut table = write_txn.open_table(PINTABLE)?;
+            table.insert(pubkey, tier as u8)?;
+        }
+        write_txn.commit()?;
+        self.tiers_written([pubkey]);
+        Ok(())
+    }
 
-        let result = table.get(pubkey)?;
+    pub fn remove_pin(&self, pubkey: &str) -> Result<(), Error> {
+        let write_txn = self.db.begin_write()?;
+        {
+            let mut table = write_txn.open_table(PINTABLE)?;
+            table.remove(pubkey)?;
+        }
+        write_txn.commit()?;
+        self.tiers_written([pubkey]);
+        Ok(())
+    }
 
-        Ok(result.map(|e| e.value()).collect())
+    pub fn read_pin(&self, pubkey: &str) -> Result<Option<Tier>, Error> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(PINTABLE)?;
+        let pin = table.get(pubkey)?.map(|t| Tier::from(t.value()));
+        Ok(pin)
     }
 
-    pub fn set_tier(&self, keys: &HashSet<String>, tier: Tier) -> Result<(), Error> {
-        let write_txn = self.db.begin_write()?;
+    /// Every pinned account and its tier
+    pub fn read_pins(&self) -> Result<HashMap<String, Tier>, Error> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(PINTABLE)?;
+        let mut pins = HashMap::new();
+        for (pubkey, tier) in table.iter()? {
+            pins.insert(pubkey.value().to_string(), Tier::from(tier.value()));
+        }
+        Ok(pins)
+    }
 
+    pub fn write_block(&self, pubkey: &str) -> Result<(), Error> {
+        let write_txn = self.db.begin_write()?;
         {
-            let mut table = write_txn.open_table(ACCOUNTTABLE)?;
-            for k in keys {
-                table.insert(k.as_str(), tier as u8)?;
-            }
+            let mut table = write_txn.open_table(BLOCKTABLE)?;
+            table.insert(pubkey, unix_time())?;
         }
-        write_txn.commit().unwrap();
-
+        write_txn.commit()?;
         Ok(())
     }
 
-    pub fn clear_tables(&self) -> Result<(), Error> {
+    pub fn remove_block(&self, pubkey: &str) -> Result<(), Error> {
         let write_txn = self.db.begin_write()?;
-
         {
-            let mut table = write_txn.open_table(ACCOUNTTABLE)?;
-            while table.len()? > 0 {
-                let _ = table.pop_first();
-            }
-            let mut table = write_txn.open_multimap_table(EVENTTABLE)?;
-            let keys: HashSet<String> = table.iter()?.map(|(x, _)| x.value().to_string()).collect();
+            let mut table = write_txn.open_table(BLOCKTABLE)?;
+            table.remove(pubkey)?;
+        }
+        write_txn.commit()?;
+        Ok(())
+    }
 
-            for k in keys {
-                table.remove_all(k.as_str())?;
-            }
-            let mut table = write_txn.open_multimap_table(FOLLOWSTABLE)?;
-            let keys: HashSet<String> = table.iter()?.map(|(x, _)| x.value().to_string()).collect();
+    pub fn is_blocked(&self, pubkey: &str) -> Result<bool, Error> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(BLOCKTABLE)?;
+        let blocked = table.get(pubkey)?.is_some();
+        Ok(blocked)
+    }
 
-            for k in keys {
-                table.remove_all(k.as_str())?;
-            }
-            let mut table = write_txn.open_multimap_table(FOLLOWERSTABLE)?;
-            let keys: HashSet<String> = table.iter()?.map(|(x, _)| x.value().to_string()).collect();
+    /// `created_at` of the last contact list applied for `pubkey`
+    pub fn read_contact_list_time(&self, pubkey: &str) -> Result<Option<u64>, Error> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(CONTACTLISTTABLE)?;
+        let created_at = table.get(pubkey)?.map(|t| t.value());
+        Ok(created_at)
+    }
 
-            for k in keys {
-                table.remove_all(k.as_str())?;
-            }
+    pub fn write_contact_list_time(&self, pubkey: &str, created_at: u64) -> Result<(), Error> {
+        let write_txn = self.db.begin_write()?;
+        {
+            let mut table = write_txn.open_table(CONTACTLISTTABLE)?;
+            table.insert(pubkey, created_at)?;
         }
-        write_txn.commit().unwrap();
-
+        write_txn.commit()?;
         Ok(())
     }
 
-    fn add_follows(&self, pubkey: &str, contacts: &HashSet<String>) -> Result<(), Error> {
-        let write_txn = self.db.begin_write().unwrap();
+    /// Event id of the last contact list applied for `pubkey`
+    pub fn read_contact_list_id(&self, pubkey: &str) -> Result<Option<String>, Error> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(CONTACTLISTIDTABLE)?;
+        let id = table.get(pubkey)?.map(|id| id.value().to_string());
+        Ok(id)
+    }
 
+    /// Sets the id of the contact list applied for `pubkey`, removing it when `None`
+    pub fn write_contact_list_id(&self, pubkey: &str, id: Option<&str>) -> Result<(), Error> {
+        let write_txn = self.db.begin_write()?;
         {
-            let mut follows_table = write_txn.open_multimap_table(FOLLOWSTABLE)?;
-            for f in contacts {
-                // debug!("Set follow {pubkey}, {f}");
-                follows_table.insert(pubkey, f.as_str()).unwrap();
+            let mut table = write_txn.open_table(CONTACTLISTIDTABLE)?;
+            match id {
+                Some(id) => {
+                    table.insert(pubkey, id)?;
+                }
+                None => {
+                    table.remove(pubkey)?;
+                }
             }
         }
-        write_txn.commit().unwrap();
-
+        write_txn.commit()?;
         Ok(())
     }
 
-    fn add_followers(&self, pubkey: &str, contacts: &HashSet<String>) -> Result<(), Error> {
-        let write_txn = self.db.begin_write().unwrap();
+    /// Token bucket of `pubkey`, `None` if it has not published since it was full
+    pub fn read_bucket(&self, pubkey: &str) -> Result<Option<TokenBucket>, Error> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(BUCKETTABLE)?;
+        let bucket = match table.get(pubkey)? {
+            Some(value) => Some(serde_json::from_str(value.value())?),
+            None => None,
+        };
+        Ok(bucket)
+    }
 
+    pub fn write_bucket(&self, pubkey: &str, bucket: &TokenBucket) -> Result<(), Error> {
+        let value = serde_json::to_string(bucket)?;
+        let write_txn = self.db.begin_write()?;
         {
-            let mut followers_table = write_txn.open_multimap_table(FOLLOWERSTABLE)?;
-            for f in contacts {
-                // debug!("Set follower {f}, {pubkey}");
-                followers_table.insert(f.as_str(), pubkey).unwrap();
-            }
+            let mut table = write_txn.open_table(BUCKETTABLE)?;
+            table.insert(pubkey, value.as_str())?;
         }
-        write_txn.commit().unwrap();
-
+        write_txn.commit()?;
         Ok(())
     }
 
-    fn remove_follows(&self, pubkey: &str, follows: &HashSet<String>) -> Result<(), Error> {
+    pub fn read_relay_list(&self, pubkey: &str) -> Result<Option<RelayList>, Error> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(RELAYLISTTABLE)?;
+        let relay_list = match table.get(pubkey)? {
+            Some(value) => Some(serde_json::from_str(value.value())?),
+            None => None,
+        };
+        Ok(relay_list)
+    }
+
+    pub fn write_relay_list(&self, pubkey: &str, relay_list: &RelayList) -> Result<(), Error> {
+        let value = serde_json::to_string(relay_list)?;
         let write_txn = self.db.begin_write()?;
         {
-            let mut follows_table = write_txn.open_multimap_table(FOLLOWSTABLE)?;
-            for follow in follows {
-                debug!("remove follows {pubkey}, {follow}");
-                follows_table.remove(pubkey, follow.as_str())?;
-            }
+            let mut table = write_txn.open_table(RELAYLISTTABLE)?;
+            table.insert(pubkey, value.as_str())?;
         }
-        write_txn.commit().unwrap();
-
+        write_txn.commit()?;
         Ok(())
     }
 
-    fn remove_followers(&self, pubkey: &str, followers: &HashSet<String>) -> Result<(), Error> {
+    /// Unix time the graph was last built from the relays, `None` if it never was
+    pub fn read_graph_built_at(&self) -> Result<Option<u64>, Error> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(GRAPHMETATABLE)?;
+        let built_at = table.get(GRAPH_BUILT_AT)?.map(|t| t.value());
+        Ok(built_at)
+    }
+
+    pub fn write_graph_built_at(&self, built_at: u64) -> Result<(), Error> {
         let write_txn = self.db.begin_write()?;
         {
-            let mut followers_table = write_txn.open_multimap_table(FOLLOWERSTABLE)?;
-            for follower in followers {
-                debug!("remove followers {follower}, {pubkey}");
-                followers_table.remove(follower.as_str(), pubkey)?;
-            }
+            let mut table = write_txn.open_table(GRAPHMETATABLE)?;
+            table.insert(GRAPH_BUILT_AT, built_at)?;
         }
-        write_txn.commit().unwrap();
-
+        write_txn.commit()?;
         Ok(())
     }
 
-    pub fn set_contact_list(&self, pubkey: &str, contacts: &HashSet<String>) -> Result<(), Error> {
-        println!("Setting contacts");
-        debug!("{pubkey}, {contacts:?}");
-        debug!("adding {} follows for {pubkey}", contacts.len());
-        self.add_followers(pubkey, contacts).unwrap();
-        debug!("added follwers");
-        self.add_follows(pubkey, contacts).unwrap();
-        debug!("added follows");
+    /// Size in bytes of the DB file
+    pub fn file_size(&self) -> Result<u64, Error> {
+        Ok(std::fs::metadata(&self.path)?.len())
+    }
 
+    /// Appends `denial` to the audit log
+    pub fn write_denial(&self, denial: &Denial) -> Result<(), Error> {
+        let value = serde_json::to_string(denial)?;
+        let write_txn = self.db.begin_write()?;
+        {
+            let mut table = write_txn.open_table(DENIALTABLE)?;
+            // Numbered so several denials in the same second are all kept
+            let prefix = format!("{}:{}:", denial.pubkey, denial.timestamp);
+            let n = table
+                .range(prefix.as_str()..)?
+                .take_while(|(k, _)| k.value().starts_with(&prefix))
+                .count();
+            table.insert(format!("{prefix}{n}").as_str(), value.as_str())?;
+        }
+        write_txn.commit()?;
         Ok(())
     }
 
-    fn get_follows(&self, pubkey: &str) -> Result<HashSet<String>, Error> {
+    /// Latest `limit` denials of `pubkey`, newest first
+    pub fn read_denials(&self, pubkey: &str, limit: usize) -> Result<Vec<Denial>, Error> {
         let read_txn = self.db.begin_read()?;
-        let table = read_txn.open_multimap_table(FOLLOWSTABLE)?;
-
-        let result = table.get(pubkey)?;
-        Ok(result.map(|e| e.value().to_string()).collect())
+        let table = read_txn.open_table(DENIALTABLE)?;
+
+        let prefix = format!("{pubkey}:");
+        let mut denials = table
+            .range(prefix.as_str()..)?
+            .take_while(|(k, _)| k.value().starts_with(&prefix))
+            .map(|(_, v)| serde_json::from_str(v.value()))
+            .collect::<Result<Vec<Denial>, _>>()?;
+        denials.reverse();
+        denials.sort_by_key(|d| std::cmp::Reverse(d.timestamp));
+        denials.truncate(limit);
+        Ok(denials)
     }
 
-    fn get_account_tiers(&self, accounts: HashSet<String>) -> Result<HashMap<String, Tier>, Error> {
-        let mut accounts_with_tiers = HashMap::new();
+    /// Relay hints `pubkey` gave for its follows, by followee
+    pub fn read_relay_hints(&self, pubkey: &str) -> Result<HashMap<String, String>, Error> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(RELAYHINTTABLE)?;
+
+        let prefix = format!("{pubkey}:");
+        let hints = table
+            .range(prefix.as_str()..)?
+            .take_while(|(k, _)| k.value().starts_with(&prefix))
+            .map(|(k, v)| (k.value()[prefix.len()..].to_string(), v.value().to_string()))
+            .collect();
+        Ok(hints)
+    }
 
-        for account in accounts {
-            let tier;
-            if let Some(t) = self.read_account(account.as_str())? {
-                tier = t.tier;
-            } else {
-                tier = Tier::Other;
+    /// Stores the relay hints of `pubkey` for its follows
+    /// Hints for accounts it no longer follows are removed
+    pub fn write_relay_hints(
+        &self,
+        pubkey: &str,
+        hints: &HashMap<String, String>,
+    ) -> Result<(), Error> {
+        let write_txn = self.db.begin_write()?;
+        {
+            let follows_table = write_txn.open_multimap_table(FOLLOWSTABLE)?;
+            let follows: HashSet<String> = follows_table
+                .get(pubkey)?
+                .map(|f| f.value().to_string())
+                .collect();
+
+            let mut table = write_txn.open_table(RELAYHINTTABLE)?;
+            let prefix = format!("{pubkey}:");
+            let unfollowed: Vec<String> = table
+                .range(prefix.as_str()..)?
+                .take_while(|(k, _)| k.value().starts_with(&prefix))
+                .map(|(k, _)| k.value().to_string())
+                .filter(|k| !follows.contains(&k[prefix.len()..]))
+                .collect();
+            for key in unfollowed {
+                table.remove(key.as_str())?;
             }
 
-            accounts_with_tiers.insert(account, tier);
+            for (followee, relay) in hints {
+                if follows.contains(followee) {
+                    table.insert(format!("{prefix}{followee}").as_str(), relay.as_str())?;
+                }
+            }
         }
-        Ok(accounts_with_tiers)
+        write_txn.commit()?;
+        Ok(())
     }
 
-    fn get_followers(&self, pubkey: &str) -> Result<HashSet<String>, Error> {
+    pub fn read_first_seen(&self, pubkey: &str) -> Result<Option<u64>, Error> {
         let read_txn = self.db.begin_read()?;
-        let table = read_txn.open_multimap_table(FOLLOWERSTABLE)?;
+        let table = read_txn.open_table(FIRSTSEENTABLE)?;
+        let first_seen = table.get(pubkey)?.map(|t| t.value());
+        Ok(first_seen)
+    }
 
-        let followers: HashSet<String> = table.get(pubkey)?.map(|p| p.value().to_owned()).collect();
+    pub fn get_events(&self, pubkey: &str) -> Result<Vec<u64>, Error> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_multimap_table(EVENTTABLE)?;
 
-        debug!("{} followed by {:?}", pubkey, followers);
+        let result = table.get(pubkey)?;
 
-        Ok(followers)
+        Ok(result.map(|e| e.value()).collect())
     }
 
-    fn update_account(&self, pubkey: &str, min_tier: Tier) -> Result<(), Error> {
-        debug!("Update account: {pubkey}");
-        let mut tier = min_tier;
-        debug!("{tier:?}");
-
-        // Min tier of follower
-        if self.primary.contains(pubkey) {
-            tier = Tier::Primary;
-        } else {
-            // Get account followers
-            let followers = self.get_followers(pubkey)?;
-            debug!("Followers: {:?}", followers);
-            let followers = self.get_account_tiers(followers)?;
-            // Minium tier based on followers
-            let min_tier = followers.iter().min_by_key(|&(_, v)| v).map(|(_, v)| *v);
-            debug!("Follower min tier: {min_tier:?}");
-            if let Some(min_tier) = min_tier {
-                let t = min_tier.raise_tier();
-                if t < tier {
-                    tier = t;
-                }
-            }
-        }
-
-        debug!("New tier: {tier:?}");
+    /// Events of `pubkey` at or after `since_time`, counted without collecting them
+    pub fn count_events_since(&self, pubkey: &str, since_time: u64) -> Result<usize, Error> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_multimap_table(EVENTTABLE)?;
 
-        let account = Account {
-            pubkey: pubkey.to_string(),
-            tier,
-        };
-        self.write_account(&account)
+        let count = table
+            .get(pubkey)?
+            .filter(|e| e.value() >= since_time)
+            .count();
+        Ok(count)
     }
 
-    pub fn update_contact_list(
-        &self,
-        pubkey: &str,
-        new_contacts: &HashSet<String>,
-    ) -> Result<(), Error> {
-        if let Some(account) = self.read_account(pubkey)? {
-            // Get current list of follows
-            let current_follows = self.get_follows(pubkey)?;
-            self.set_contact_list(pubkey, new_contacts)?;
-            debug!("current follows: {:?}", current_follows);
-            debug!("new contact list {:?}", new_contacts);
-
-            let new_follows: HashSet<String> =
-                new_contacts.difference(&current_follows).cloned().collect();
-            debug!("{} followed: {:?}", account.pubkey, new_follows);
+    /// Events of `pubkey` within the last `range` seconds
+    pub fn count_events_in_range(&self, pubkey: &str, range: u64) -> Result<usize, Error> {
+        self.count_events_since(pubkey, unix_time().saturating_sub(range))
+    }
 
-            let unfollowed: HashSet<String> =
-                current_follows.difference(new_contacts).cloned().collect();
-            debug!("{} unfollowed {unfollowed:?}", account.pubkey);
+    /// Removes event timestamps and byte counts before `older_than`
+    /// Returns the number of timestamps removed
+    pub fn prune_events(&self, older_than: u64) -> Result<usize, Error> {
+        let write_txn = self.db.begin_write()?;
+        let pruned = {
+            let mut table = write_txn.open_multimap_table(EVENTTABLE)?;
+            let mut old: Vec<(String, u64)> = Vec::new();
+            for (pubkey, timestamps) in table.iter()? {
+                let pubkey = pubkey.value().to_string();
+                for timestamp in timestamps {
+                    if timestamp.value() < older_than {
+                        old.push((pubkey.clone(), timestamp.value()));
+                    }
+                }
+            }
+            for (pubkey, timestamp) in &old {
+                table.remove(pubkey.as_str(), *timestamp)?;
+            }
 
-            self.remove_follows(pubkey, &unfollowed)?;
-            self.remove_followers(pubkey, &unfollowed)?;
+            let mut bytes_table = write_txn.open_table(BYTESTABLE)?;
+            let old_bytes: Vec<String> = bytes_table
+                .iter()?
+                .map(|(k, _)| k.value().to_string())
+                .filter(|k| byte_row_time(k).is_none_or(|t| t < older_than))
+                .collect();
+            for key in &old_bytes {
+                bytes_table.remove(key.as_str())?;
+            }
 
-            let new_follow_tier = account.tier.raise_tier();
-            self.update_follows(new_follows, new_follow_tier)?;
+            let mut denial_table = write_txn.open_table(DENIALTABLE)?;
+            let old_denials: Vec<String> = denial_table
+                .iter()?
+                .map(|(k, _)| k.value().to_string())
+                .filter(|k| denial_row_time(k).is_none_or(|t| t < older_than))
+                .collect();
+            for key in &old_denials {
+                denial_table.remove(key.as_str())?;
+            }
 
-            let unfollowed_tier = Tier::Other;
-            self.update_follows(unfollowed, unfollowed_tier)?;
-        }
-        Ok(())
+            // Buckets untouched this long have refilled
+            let mut bucket_table = write_txn.open_table(BUCKETTABLE)?;
+            let old_buckets: Vec<String> = bucket_table
+                .iter()?
+                .filter(|(_, v)| {
+                    serde_json::from_str::<TokenBucket>(v.value())
+                        .map_or(true, |b| b.refilled_at < older_than)
+                })
+                .map(|(k, _)| k.value().to_string())
+                .collect();
+            for key in &old_buckets {
+                bucket_table.remove(key.as_str())?;
+            }
+            old.len()
+        };
+        write_txn.commit()?;
+        Ok(pruned)
     }
 
-    /// For the each follow in Set passed get their follows
-    /// Updated follow and each of their follow
-    fn update_follows(&self, follows: HashSet<String>, min_tier: Tier) -> Result<(), Error> {
-        for f in follows {
-            let follows_followers = self.get_follows(&f)?;
-            self.update_account(&f, min_tier)?;
-
-            for f_f in follows_followers {
-                let f_f_tier = min_tier.raise_tier();
+    /// Every `(pubkey, timestamp)` event row, optionally for one pubkey
+    pub fn read_all_events(&self, pubkey: Option<&str>) -> Result<Vec<(String, u64)>, Error> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_multimap_table(EVENTTABLE)?;
 
-                if let Ok(Some(f_f_account)) = self.read_account(&f_f) {
-                    if f_f_account.tier.ne(&f_f_tier) {
-                        self.update_account(&f_f, f_f_tier)?;
+        let mut events = Vec::new();
+        match pubkey {
+            Some(pubkey) => {
+                for timestamp in table.get(pubkey)? {
+                    events.push((pubkey.to_string(), timestamp.value()));
+                }
+            }
+            None => {
+                for (pubkey, timestamps) in table.iter()? {
+                    let pubkey = pubkey.value().to_string();
+                    for timestamp in timestamps {
+                        events.push((pubkey.clone(), timestamp.value()));
                     }
                 }
             }
         }
-        Ok(())
+        Ok(events)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::utils::unix_time;
-    use serial_test::serial;
+    /// Adds `bytes` to the content published by `pubkey` at `timestamp`
+    pub fn write_bytes(&self, pubkey: &str, timestamp: u64, bytes: u64) -> Result<(), Error> {
+        let write_txn = self.db.begin_write()?;
+        {
+            let mut table = write_txn.open_table(BYTESTABLE)?;
+            let key = format!("{pubkey}:{timestamp}");
+            let current = table.get(key.as_str())?.map_or(0, |b| b.value());
+            table.insert(key.as_str(), current + bytes)?;
+        }
+        write_txn.commit()?;
+        Ok(())
+    }
 
+    /// Content bytes published by `pubkey` after `since`
+    pub fn get_bytes(&self, pubkey: &str, since: u64) -> Result<u64, Error> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(BYTESTABLE)?;
+
+        let prefix = format!("{pubkey}:");
+        let bytes = table
+            .range(prefix.as_str()..)?
+            .take_while(|(k, _)| k.value().starts_with(&prefix))
+            .filter(|(k, _)| byte_row_time(k.value()).is_some_and(|t| t > since))
+            .map(|(_, b)| b.value())
+            .sum();
+        Ok(bytes)
+    }
+
+    pub fn write_kind(&self, pubkey: &str, kind: u64, timestamp: u64) -> Result<(), Error> {
+        let write_txn = self.db.begin_write()?;
+        {
+            let mut table = write_txn.open_table(KINDTABLE)?;
+            table.insert(format!("{pubkey}:{kind}").as_str(), timestamp)?;
+        }
+        write_txn.commit()?;
+        Ok(())
+    }
+
+    /// Kinds published by `pubkey` since `since`
+    pub fn get_kinds(&self, pubkey: &str, since: u64) -> Result<HashSet<u64>, Error> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(KINDTABLE)?;
+
+        let prefix = format!("{pubkey}:");
+        let kinds = table
+            .range(prefix.as_str()..)?
+            .take_while(|(k, _)| k.value().starts_with(&prefix))
+            .filter(|(_, t)| t.value() >= since)
+            .flat_map(|(k, _)| k.value()[prefix.len()..].parse())
+            .collect();
+        Ok(kinds)
+    }
+
+    pub fn read_counters(&self) -> Result<HashMap<String, u64>, Error> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(METRICSTABLE)?;
+
+        let counters = table
+            .iter()?
+            .map(|(k, v)| (k.value().to_string(), v.value()))
+            .collect();
+        Ok(counters)
+    }
+
+    /// Adds each count to its persisted counter in one transaction
+    pub fn add_counters(&self, counts: &HashMap<String, u64>) -> Result<(), Error> {
+        let write_txn = self.db.begin_write()?;
+        {
+            let mut table = write_txn.open_table(METRICSTABLE)?;
+            for (name, count) in counts {
+                let total = table.get(name.as_str())?.map(|c| c.value()).unwrap_or(0);
+                table.insert(name.as_str(), total + count)?;
+            }
+        }
+        write_txn.commit()?;
+        Ok(())
+    }
+
+    /// Sets the tier of `keys`, pinned keys are set to their pinned tier
+    pub fn set_tier(&self, keys: &HashSet<String>, tier: Tier) -> Result<(), Error> {
+        let write_txn = self.db.begin_write()?;
+
+        let mut changed = Vec::new();
+        {
+            let mut table = write_txn.open_table(ACCOUNTTABLE)?;
+            let mut first_seen_table = write_txn.open_table(FIRSTSEENTABLE)?;
+            let pin_table = write_txn.open_table(PINTABLE)?;
+            let now = unix_time();
+            for k in keys {
+                // Pinned accounts keep their pinned tier
+                let tier = pin_table.get(k.as_str())?.map_or(tier as u8, |t| t.value());
+                if table.insert(k.as_str(), tier)?.map(|t| t.value()) != Some(tier) {
+                    changed.push(k.as_str());
+                }
+                if first_seen_table.get(k.as_str())?.is_none() {
+                    first_seen_table.insert(k.as_str(), now)?;
+                }
+            }
+        }
+        write_txn.commit()?;
+        self.tiers_written(changed);
+
+        Ok(())
+    }
+
+    pub fn clear_tables(&self) -> Result<(), Error> {
+        let write_txn = self.db.begin_write()?;
+
+        // Dropping a table frees its pages without visiting every entry,
+        // opening it again recreates it empty in the same transaction
+        macro_rules! clear_table {
+            ($table:expr) => {{
+                write_txn.delete_table($table)?;
+                write_txn.open_table($table)?;
+            }};
+        }
+        macro_rules! clear_multimap_table {
+            ($table:expr) => {{
+                write_txn.delete_multimap_table($table)?;
+                write_txn.open_multimap_table($table)?;
+            }};
+        }
+
+        clear_table!(ACCOUNTTABLE);
+        clear_table!(KINDTABLE);
+        clear_table!(BYTESTABLE);
+        clear_table!(BUCKETTABLE);
+        clear_table!(CONTACTLISTTABLE);
+        clear_table!(RELAYHINTTABLE);
+        clear_table!(CONTACTLISTIDTABLE);
+        clear_multimap_table!(EVENTTABLE);
+        clear_multimap_table!(FOLLOWSTABLE);
+        clear_multimap_table!(FOLLOWERSTABLE);
+        write_txn.commit()?;
+        self.all_tiers_written();
+
+        Ok(())
+    }
+
+    /// Adds and removes follows of `pubkey` in both the follows and followers
+    /// tables in one transaction so the tables can not disagree
+    fn write_edges(
+        &self,
+        pubkey: &str,
+        added: &HashSet<String>,
+        removed: &HashSet<String>,
+    ) -> Result<(), Error> {
+        let write_txn = self.db.begin_write()?;
+        {
+            let mut follows_table = write_txn.open_multimap_table(FOLLOWSTABLE)?;
+            let mut followers_table = write_txn.open_multimap_table(FOLLOWERSTABLE)?;
+            // An account can not vouch for itself
+            for f in added.iter().filter(|f| *f != pubkey) {
+                follows_table.insert(pubkey, f.as_str())?;
+                followers_table.insert(f.as_str(), pubkey)?;
+            }
+            for f in removed {
+                debug!("remove follow {pubkey}, {f}");
+                follows_table.remove(pubkey, f.as_str())?;
+                followers_table.remove(f.as_str(), pubkey)?;
+            }
+        }
+        write_txn.commit()?;
+
+        Ok(())
+    }
+
+    pub fn set_contact_list(&self, pubkey: &str, contacts: &HashSet<String>) -> Result<(), Error> {
+        debug!("adding {} follows for {pubkey}", contacts.len());
+        self.write_edges(pubkey, contacts, &HashSet::new())
+    }
+
+    /// Number of accounts at each tier
+    pub fn count_by_tier(&self) -> Result<BTreeMap<Tier, usize>, Error> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(ACCOUNTTABLE)?;
+
+        let mut counts = BTreeMap::new();
+        for (_, tier) in table.iter()? {
+            *counts.entry(Tier::from(tier.value())).or_insert(0) += 1;
+        }
+        Ok(counts)
+    }
+
+    /// Pubkeys of the accounts at `tier`, in key order
+    pub fn accounts_by_tier(&self, tier: Tier) -> Result<Vec<String>, Error> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(ACCOUNTTABLE)?;
+
+        let mut pubkeys = Vec::new();
+        for (pubkey, t) in table.iter()? {
+            if Tier::from(t.value()) == tier {
+                pubkeys.push(pubkey.value().to_string());
+            }
+        }
+        Ok(pubkeys)
+    }
+
+    /// Accounts closer to a primary than `tier`
+    pub fn pubkeys_before(&self, tier: Tier) -> Result<HashSet<String>, Error> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(ACCOUNTTABLE)?;
+
+        let mut pubkeys = HashSet::new();
+        for (pubkey, t) in table.iter()? {
+            if Tier::from(t.value()) < tier {
+                pubkeys.insert(pubkey.value().to_string());
+            }
+        }
+        Ok(pubkeys)
+    }
+
+    /// Follow edges of followers after `after`, in follower order
+    /// Stops at the first follower boundary once `limit` edges are read
+    pub fn follow_edges_page(
+        &self,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>, Error> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_multimap_table(FOLLOWSTABLE)?;
+
+        let mut edges = Vec::new();
+        for (follower, follows) in table.range(after.unwrap_or_default()..)? {
+            if edges.len() >= limit {
+                break;
+            }
+            let follower = follower.value();
+            if Some(follower) == after {
+                continue;
+            }
+            for follow in follows {
+                edges.push((follower.to_string(), follow.value().to_string()));
+            }
+        }
+        Ok(edges)
+    }
+
+    /// Every (follower, followee) edge, read a page at a time
+    pub fn all_follow_edges(&self) -> Result<Vec<(String, String)>, Error> {
+        let mut edges: Vec<(String, String)> = Vec::new();
+        loop {
+            let after = edges.last().map(|(follower, _)| follower.as_str());
+            let page = self.follow_edges_page(after, FOLLOW_EDGES_PAGE)?;
+            if page.is_empty() {
+                return Ok(edges);
+            }
+            edges.extend(page);
+        }
+    }
+
+    /// Number of follow edges
+    pub fn count_follows(&self) -> Result<usize, Error> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_multimap_table(FOLLOWSTABLE)?;
+
+        let mut count = 0;
+        for (_, follows) in table.iter()? {
+            count += follows.count();
+        }
+        Ok(count)
+    }
+
+    /// Every account and follow edge
+    pub fn read_snapshot(&self) -> Result<GraphSnapshot, Error> {
+        let read_txn = self.db.begin_read()?;
+        let account_table = read_txn.open_table(ACCOUNTTABLE)?;
+        let follows_table = read_txn.open_multimap_table(FOLLOWSTABLE)?;
+
+        let mut snapshot = GraphSnapshot::default();
+        for (pubkey, tier) in account_table.iter()? {
+            snapshot
+                .accounts
+                .insert(pubkey.value().to_string(), Tier::from(tier.value()));
+        }
+        for (pubkey, follows) in follows_table.iter()? {
+            for follow in follows {
+                snapshot
+                    .follows
+                    .insert((pubkey.value().to_string(), follow.value().to_string()));
+            }
+        }
+        Ok(snapshot)
+    }
+
+    /// Replaces the accounts, follows and followers with those of `snapshot`
+    /// in a single transaction
+    pub fn write_snapshot(&self, snapshot: &GraphSnapshot) -> Result<(), Error> {
+        let write_txn = self.db.begin_write()?;
+        // Dropped and recreated empty as in `clear_tables` rather than emptied entry by entry
+        write_txn.delete_table(ACCOUNTTABLE)?;
+        write_txn.delete_multimap_table(FOLLOWSTABLE)?;
+        write_txn.delete_multimap_table(FOLLOWERSTABLE)?;
+        {
+            let mut account_table = write_txn.open_table(ACCOUNTTABLE)?;
+            for (pubkey, tier) in &snapshot.accounts {
+                account_table.insert(pubkey.as_str(), *tier as u8)?;
+            }
+
+            let mut follows_table = write_txn.open_multimap_table(FOLLOWSTABLE)?;
+            let mut followers_table = write_txn.open_multimap_table(FOLLOWERSTABLE)?;
+            for (follower, followee) in &snapshot.follows {
+                follows_table.insert(follower.as_str(), followee.as_str())?;
+                followers_table.insert(followee.as_str(), follower.as_str())?;
+            }
+        }
+        write_txn.commit()?;
+        self.all_tiers_written();
+
+        Ok(())
+    }
+
+    /// Replaces the follows of each pubkey in a single transaction
+    pub fn write_contact_lists(
+        &self,
+        contact_lists: &[(String, HashSet<String>)],
+    ) -> Result<(), Error> {
+        let write_txn = self.db.begin_write()?;
+        {
+            let mut follows_table = write_txn.open_multimap_table(FOLLOWSTABLE)?;
+            let mut followers_table = write_txn.open_multimap_table(FOLLOWERSTABLE)?;
+            for (pubkey, contacts) in contact_lists {
+                let current_follows: Vec<String> = follows_table
+                    .get(pubkey.as_str())?
+                    .map(|f| f.value().to_string())
+                    .collect();
+                for follow in current_follows {
+                    followers_table.remove(follow.as_str(), pubkey.as_str())?;
+                }
+                follows_table.remove_all(pubkey.as_str())?;
+
+                for f in contacts.iter().filter(|f| *f != pubkey) {
+                    follows_table.insert(pubkey.as_str(), f.as_str())?;
+                    followers_table.insert(f.as_str(), pubkey.as_str())?;
+                }
+            }
+        }
+        write_txn.commit()?;
+
+        Ok(())
+    }
+
+    /// Recomputes every tier from the shortest follow path to a primary
+    pub fn recompute_tiers(&self) -> Result<(), Error> {
+        let pins = self.read_pins()?;
+        let write_txn = self.db.begin_write()?;
+        let mut changed = Vec::new();
+        {
+            let mut account_table = write_txn.open_table(ACCOUNTTABLE)?;
+            let follows_table = write_txn.open_multimap_table(FOLLOWSTABLE)?;
+
+            let mut tiers: HashMap<String, Tier> = HashMap::new();
+            let mut frontier: HashSet<String> = self.primary.clone();
+            for (pubkey, tier) in account_table.iter()? {
+                let pubkey = pubkey.value().to_string();
+                if Tier::from(tier.value()) == Tier::Primary && !pins.contains_key(&pubkey) {
+                    frontier.insert(pubkey.clone());
+                }
+                tiers.insert(pubkey, Tier::Other);
+            }
+
+            let mut seen: HashSet<String> = HashSet::new();
+            let mut tier = Tier::Primary;
+            while tier != Tier::Other {
+                // Pinned accounts join the walk at their pinned tier and no other
+                frontier.retain(|p| pins.get(p).is_none_or(|pin| *pin == tier));
+                frontier.extend(
+                    pins.iter()
+                        .filter(|(p, pin)| **pin == tier && !seen.contains(*p))
+                        .map(|(p, _)| p.clone()),
+                );
+                if frontier.is_empty() && !pins.values().any(|pin| *pin > tier) {
+                    break;
+                }
+
+                let mut next = HashSet::new();
+                for pubkey in frontier {
+                    for f in follows_table.get(pubkey.as_str())? {
+                        let f = f.value().to_string();
+                        if self.mutual_primary_follows && tier == Tier::Primary {
+                            let mut follows_back = false;
+                            for back in follows_table.get(f.as_str())? {
+                                follows_back |= back.value() == pubkey;
+                            }
+                            if !follows_back {
+                                continue;
+                            }
+                        }
+                        next.insert(f);
+                    }
+                    seen.insert(pubkey.clone());
+                    tiers.insert(pubkey, tier);
+                }
+                next.retain(|p| !seen.contains(p));
+                frontier = next;
+                tier = tier.raise_tier();
+            }
+            // Accounts reached only past the grace tier are not added, as in `update_account`
+            tiers.extend(pins);
+
+            for (pubkey, tier) in tiers {
+                let old = account_table.insert(pubkey.as_str(), tier as u8)?;
+                if old.map(|t| t.value()) != Some(tier as u8) {
+                    changed.push(pubkey);
+                }
+            }
+        }
+        write_txn.commit()?;
+        self.tiers_written(changed.iter().map(String::as_str));
+
+        Ok(())
+    }
+
+    /// Demotes accounts that no longer have a follower one tier above them
+    /// Repeats until no tier changes so demotions cascade down the graph
+    pub fn demote_unvouched(&self) -> Result<Vec<String>, Error> {
+        let pins = self.read_pins()?;
+        let mut demoted: HashSet<String> = HashSet::new();
+        let write_txn = self.db.begin_write()?;
+        {
+            let mut account_table = write_txn.open_table(ACCOUNTTABLE)?;
+            let followers_table = write_txn.open_multimap_table(FOLLOWERSTABLE)?;
+
+            let mut tiers: HashMap<String, Tier> = HashMap::new();
+            for (pubkey, tier) in account_table.iter()? {
+                tiers.insert(pubkey.value().to_string(), Tier::from(tier.value()));
+            }
+            // Pinned accounts keep and vouch at their pinned tier
+            tiers.extend(pins.iter().map(|(pubkey, tier)| (pubkey.clone(), *tier)));
+
+            let mut changed = true;
+            while changed {
+                changed = false;
+                let candidates: Vec<(String, Tier)> = tiers
+                    .iter()
+                    .filter(|(pubkey, tier)| {
+                        !matches!(tier, Tier::Primary | Tier::Other)
+                            && !self.primary.contains(*pubkey)
+                            && !pins.contains_key(*pubkey)
+                    })
+                    .map(|(pubkey, tier)| (pubkey.clone(), *tier))
+                    .collect();
+
+                for (pubkey, tier) in candidates {
+                    let mut vouched_tier = Tier::Other;
+                    for follower in followers_table.get(pubkey.as_str())? {
+                        let follower_tier =
+                            tiers.get(follower.value()).copied().unwrap_or(Tier::Other);
+                        if !self.vouches(follower.value(), follower_tier, &pubkey)? {
+                            continue;
+                        }
+                        vouched_tier = vouched_tier.min(follower_tier.raise_tier());
+                        // One follower a tier closer is enough to keep the tier
+                        if follower_tier <= tier.lower_tier() {
+                            break;
+                        }
+                    }
+
+                    if vouched_tier > tier {
+                        debug!("Demoting {pubkey} from {tier:?} to {vouched_tier:?}");
+                        tiers.insert(pubkey.clone(), vouched_tier);
+                        demoted.insert(pubkey);
+                        changed = true;
+                    }
+                }
+            }
+
+            for pubkey in &demoted {
+                account_table.insert(pubkey.as_str(), tiers[pubkey] as u8)?;
+            }
+        }
+        write_txn.commit()?;
+        self.tiers_written(demoted.iter().map(String::as_str));
+
+        let mut demoted: Vec<String> = demoted.into_iter().collect();
+        demoted.sort();
+        Ok(demoted)
+    }
+
+    pub fn get_follows(&self, pubkey: &str) -> Result<HashSet<String>, Error> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_multimap_table(FOLLOWSTABLE)?;
+
+        let result = table.get(pubkey)?;
+        Ok(result.map(|e| e.value().to_string()).collect())
+    }
+
+    /// Accounts in the tables that cannot be reached from any primary
+    pub fn orphans(&self) -> Result<Vec<String>, Error> {
+        let read_txn = self.db.begin_read()?;
+        let account_table = read_txn.open_table(ACCOUNTTABLE)?;
+        let followers_table = read_txn.open_multimap_table(FOLLOWERSTABLE)?;
+
+        let mut accounts: HashSet<String> = HashSet::new();
+        let mut primaries = self.primary.clone();
+        for (pubkey, tier) in account_table.iter()? {
+            if Tier::from(tier.value()) == Tier::Primary {
+                primaries.insert(pubkey.value().to_string());
+            }
+            accounts.insert(pubkey.value().to_string());
+        }
+
+        // Reverse of the followers table, follower to who they follow
+        let mut follows: HashMap<String, HashSet<String>> = HashMap::new();
+        for (followee, followers) in followers_table.iter()? {
+            let followee = followee.value().to_string();
+            for follower in followers {
+                let follower = follower.value().to_string();
+                accounts.insert(follower.clone());
+                follows
+                    .entry(follower)
+                    .or_default()
+                    .insert(followee.clone());
+            }
+            accounts.insert(followee);
+        }
+
+        let mut reachable: HashSet<String> = HashSet::new();
+        let mut frontier: Vec<String> = primaries.into_iter().collect();
+        while let Some(pubkey) = frontier.pop() {
+            if !reachable.insert(pubkey.clone()) {
+                continue;
+            }
+            if let Some(followees) = follows.get(&pubkey) {
+                frontier.extend(
+                    followees
+                        .iter()
+                        .filter(|f| !reachable.contains(*f))
+                        .cloned(),
+                );
+            }
+        }
+
+        let mut orphans: Vec<String> = accounts.difference(&reachable).cloned().collect();
+        orphans.sort();
+        Ok(orphans)
+    }
+
+    /// Tiers of `accounts` read in a single transaction, `Other` for unknown accounts
+    pub fn get_account_tiers(
+        &self,
+        accounts: HashSet<String>,
+    ) -> Result<HashMap<String, Tier>, Error> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(ACCOUNTTABLE)?;
+
+        let mut accounts_with_tiers = HashMap::with_capacity(accounts.len());
+        for account in accounts {
+            let tier = table
+                .get(account.as_str())?
+                .map_or(Tier::Other, |t| Tier::from(t.value()));
+            accounts_with_tiers.insert(account, tier);
+        }
+        Ok(accounts_with_tiers)
+    }
+
+    pub fn get_followers(&self, pubkey: &str) -> Result<HashSet<String>, Error> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_multimap_table(FOLLOWERSTABLE)?;
+
+        let followers: HashSet<String> = table.get(pubkey)?.map(|p| p.value().to_owned()).collect();
+
+        debug!("{} followed by {:?}", pubkey, followers);
+
+        Ok(followers)
+    }
+
+    /// Recomputes the tier of `pubkey` from its followers and returns it
+    fn update_account(&self, pubkey: &str, min_tier: Tier) -> Result<Tier, Error> {
+        debug!("Update account: {pubkey}");
+        let mut tier = min_tier;
+        debug!("{tier:?}");
+
+        // Min tier of follower
+        if self.primary.contains(pubkey) {
+            tier = Tier::Primary;
+        } else if let Some(pin) = self.read_pin(pubkey)? {
+            tier = pin;
+        } else {
+            // Get account followers
+            let followers = self.get_followers(pubkey)?;
+            debug!("Followers: {:?}", followers);
+            let mut followers = self.get_account_tiers(followers)?;
+            let mut one_way = Vec::new();
+            for (follower, follower_tier) in &followers {
+                if !self.vouches(follower, *follower_tier, pubkey)? {
+                    one_way.push(follower.clone());
+                }
+            }
+            followers.retain(|f, _| !one_way.contains(f));
+            // Minium tier based on followers
+            let min_tier = followers.iter().min_by_key(|&(_, v)| v).map(|(_, v)| *v);
+            debug!("Follower min tier: {min_tier:?}");
+            if let Some(min_tier) = min_tier {
+                let t = min_tier.raise_tier();
+                if t < tier {
+                    tier = t;
+                }
+            }
+        }
+
+        debug!("New tier: {tier:?}");
+
+        let old_tier = self.read_account(pubkey)?.map(|a| a.tier);
+        // Nothing vouches for it, so a stranger's contact list can not make
+        // its follows known accounts
+        if old_tier.is_none() && tier == Tier::Other {
+            return Ok(tier);
+        }
+
+        let account = Account {
+            pubkey: pubkey.to_string(),
+            tier,
+        };
+        self.write_account(&account)?;
+
+        if let Some(sender) = &self.tier_changes {
+            if old_tier != Some(tier) {
+                // Never blocks, a closed receiver only loses the notification
+                let _ = sender.send(TierChange {
+                    pubkey: pubkey.to_string(),
+                    old_tier,
+                    new_tier: tier,
+                });
+            }
+        }
+        Ok(tier)
+    }
+
+    pub fn update_contact_list(
+        &self,
+        pubkey: &str,
+        new_contacts: &HashSet<String>,
+    ) -> Result<(), Error> {
+        let account = match self.read_account(pubkey)? {
+            Some(account) => account,
+            // Authors new to the graph have their follows recorded but only get an
+            // account once a primary key, pin or follower vouches for them
+            None => {
+                let tier = self.update_account(pubkey, Tier::Other)?;
+                Account {
+                    pubkey: pubkey.to_string(),
+                    tier,
+                }
+            }
+        };
+
+        // A self follow would make the account its own follower
+        let new_contacts: HashSet<String> = new_contacts
+            .iter()
+            .filter(|c| *c != pubkey)
+            .cloned()
+            .collect();
+        let new_contacts = &new_contacts;
+
+        // Get current list of follows
+        let current_follows = self.get_follows(pubkey)?;
+        debug!("current follows: {:?}", current_follows);
+        debug!("new contact list {:?}", new_contacts);
+
+        let new_follows: HashSet<String> =
+            new_contacts.difference(&current_follows).cloned().collect();
+        debug!("{} followed: {:?}", account.pubkey, new_follows);
+
+        let unfollowed: HashSet<String> =
+            current_follows.difference(new_contacts).cloned().collect();
+        debug!("{} unfollowed {unfollowed:?}", account.pubkey);
+
+        // Edges change atomically, tiers below are derived from them and can be recomputed
+        self.write_edges(pubkey, &new_follows, &unfollowed)?;
+
+        let mut new_follows = new_follows;
+        if self.mutual_primary_follows && account.tier == Tier::Primary {
+            // One way follows get their tier from other followers
+            let mut one_way = HashSet::new();
+            for f in &new_follows {
+                if !self.get_follows(f)?.contains(pubkey) {
+                    one_way.insert(f.clone());
+                }
+            }
+            new_follows.retain(|f| !one_way.contains(f));
+            self.update_follows(one_way, Tier::Other)?;
+        }
+
+        // Following or unfollowing a primary can change whether they vouch for us
+        let primary_changed = self.mutual_primary_follows
+            && new_follows
+                .iter()
+                .chain(unfollowed.iter())
+                .any(|f| matches!(self.read_account(f), Ok(Some(a)) if a.tier == Tier::Primary));
+
+        let new_follow_tier = account.tier.raise_tier();
+        self.update_follows(new_follows, new_follow_tier)?;
+
+        let unfollowed_tier = Tier::Other;
+        self.update_follows(unfollowed, unfollowed_tier)?;
+
+        if primary_changed {
+            self.update_follows(HashSet::from([pubkey.to_string()]), Tier::Other)?;
+        }
+        Ok(())
+    }
+
+    /// Adds `new_contacts` to the follows of `pubkey` without removing any
+    pub fn merge_contact_list(
+        &self,
+        pubkey: &str,
+        new_contacts: &HashSet<String>,
+    ) -> Result<(), Error> {
+        let mut contacts = self.get_follows(pubkey)?;
+        contacts.extend(new_contacts.iter().cloned());
+        self.update_contact_list(pubkey, &contacts)
+    }
+
+    /// Updates the tier of each of `follows`, then walks down the graph
+    /// recomputing the follows of every account whose tier changed until
+    /// tiers stop changing
+    fn update_follows(&self, follows: HashSet<String>, min_tier: Tier) -> Result<(), Error> {
+        // Accounts further down only take the tier their followers give them
+        let mut min_tier = Some(min_tier);
+        let mut changes: HashMap<String, usize> = HashMap::new();
+
+        // Each pass is a set so an account is recomputed at most once per pass
+        // however many of its followers changed, accounts in a cycle come round
+        // again in later passes only while their tiers keep changing
+        let mut pass = follows;
+        while !pass.is_empty() {
+            let mut next = HashSet::new();
+            for pubkey in pass {
+                let before = self.read_account(&pubkey)?.map(|a| a.tier);
+                // Accounts without a row are recomputed too, a newly vouched for
+                // author's follows can enter the graph through it
+                let after = self.update_account(&pubkey, min_tier.unwrap_or(Tier::Other))?;
+                // Accounts left at other without a row were not added
+                if before.unwrap_or(Tier::Other) == after {
+                    continue;
+                }
+
+                let count = changes.entry(pubkey.clone()).or_default();
+                *count += 1;
+                if *count > MAX_TIER_CHANGES {
+                    warn!("Tier of {pubkey} did not settle, leaving it at {after:?}");
+                    continue;
+                }
+                debug!("{pubkey} moved from {before:?} to {after:?}");
+                next.extend(self.get_follows(&pubkey)?);
+            }
+            pass = next;
+            min_tier = None;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
     // use tracing::{debug, error, info};
-    use tracing_test::traced_test;
 
-    use super::*;
+    use super::*;
+    use crate::test_utils::temp_path;
+
+    #[test]
+    fn test_get_events() {
+        let db = Db::new_in_memory(HashSet::new());
+        let pubkey = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9";
+
+        let timestamp = unix_time();
+        db.write_event(pubkey, timestamp).unwrap();
+
+        let events = db.get_events(pubkey).unwrap();
+
+        assert_eq!(vec![timestamp], events);
+        assert_eq!(1, db.count_events_in_range(pubkey, 3600).unwrap());
+
+        // Events exactly at the window boundary are counted
+        for timestamp in [99, 100, 101] {
+            db.write_event(pubkey, timestamp).unwrap();
+        }
+        assert_eq!(3, db.count_events_since(pubkey, 100).unwrap());
+    }
+
+    #[test]
+    fn test_set_contacts() {
+        let db = Db::new_in_memory(HashSet::new());
+        let pubkey = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9".to_string();
+
+        let follow_one =
+            "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203".to_string();
+        let follow_two =
+            "7c27a04b7c27299f16dc07d3eb8f28544f188bc7a34982328b7d581edc405dc2".to_string();
+
+        let follows = HashSet::from([follow_one.clone(), follow_two.clone()]);
+
+        let primary_account = Account {
+            pubkey: pubkey.clone(),
+            tier: Tier::Primary,
+        };
+        db.write_account(&primary_account).unwrap();
+        db.set_contact_list(&pubkey, &follows).unwrap();
+        db.update_follows(follows.clone(), Tier::Secondary).unwrap();
+
+        let db_follows = db.get_follows(&pubkey).unwrap();
+
+        assert_eq!(follows, db_follows);
+
+        let db_followers = db.get_followers(&follow_one).unwrap();
+        assert_eq!(HashSet::from([pubkey.clone()]), db_followers);
+        let db_followers = db.get_followers(&follow_two).unwrap();
+        assert_eq!(HashSet::from([pubkey.clone()]), db_followers);
+
+        assert_eq!(primary_account, db.read_account(&pubkey).unwrap().unwrap());
+        let one_account = Account {
+            pubkey: follow_one.clone(),
+            tier: Tier::Secondary,
+        };
+        assert_eq!(one_account, db.read_account(&follow_one).unwrap().unwrap());
+        let two_account = Account {
+            pubkey: follow_two.clone(),
+            tier: Tier::Secondary,
+        };
+        assert_eq!(
+            two_account,
+            db.read_account(&follow_two.clone()).unwrap().unwrap()
+        );
+    }
+
+    // Test that a primary user can unfollow a user
+    // #[traced_test]
+    #[test]
+    fn test_primary_unfollow() {
+        let db = Db::new_in_memory(HashSet::new());
+        let pubkey = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9".to_string();
+
+        let a = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203".to_string();
+        let b = "7c27a04b7c27299f16dc07d3eb8f28544f188bc7a34982328b7d581edc405dc2".to_string();
+
+        let a_follows = HashSet::from([a.clone(), b.clone()]);
+
+        let a_account = Account {
+            pubkey: pubkey.clone(),
+            tier: Tier::Primary,
+        };
+        db.write_account(&a_account).unwrap();
+        db.set_contact_list(&pubkey, &a_follows.clone()).unwrap();
+        db.update_follows(a_follows, Tier::Secondary).unwrap();
+
+        let new_contacts = HashSet::from([a.clone()]);
+
+        db.update_contact_list(&pubkey, &new_contacts).unwrap();
+        let db_follows = db.get_follows(&pubkey).unwrap();
+
+        assert_eq!(new_contacts, db_follows);
+
+        assert_eq!(a_account, db.read_account(&pubkey).unwrap().unwrap());
+
+        let one_account = Account {
+            pubkey: a.clone(),
+            tier: Tier::Secondary,
+        };
+        assert_eq!(one_account, db.read_account(&a).unwrap().unwrap());
+
+        let two_account = Account {
+            pubkey: b.clone(),
+            tier: Tier::Other,
+        };
+        assert_eq!(two_account, db.read_account(&b.clone()).unwrap().unwrap());
+    }
+
+    // ----------
+    // A -> B, A -> C, B -> D
+    // A = 0, B = 1, C = 1, D = 2
+    // ----------
+    // A -> C, B -> D
+    // A = 0, B = 4, C = 1, D = 4
+    // ----------
+    #[test]
+    // #[traced_test]
+    fn test_primary_unfollow_with_tier() {
+        let a = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9".to_string();
+        let db = Db::new_in_memory(HashSet::from([a.clone()]));
+
+        let b = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203".to_string();
+        let c = "7c27a04b7c27299f16dc07d3eb8f28544f188bc7a34982328b7d581edc405dc2".to_string();
+
+        let a_follows = HashSet::from([b.clone(), c.clone()]);
+
+        let a_account = Account {
+            pubkey: a.clone(),
+            tier: Tier::Primary,
+        };
+        db.write_account(&a_account).unwrap();
+        db.set_contact_list(&a, &a_follows.clone()).unwrap();
+        db.update_follows(a_follows, Tier::Secondary).unwrap();
+
+        let d = "5b3a49bcbdf41f511f5c9034dbe46240863b73b39a2fdfebfb611f23b88d3922".to_string();
+
+        let b_follows = HashSet::from([d.clone()]);
+        db.set_contact_list(&b, &b_follows).unwrap();
+        db.update_follows(b_follows, Tier::Tertiary).unwrap();
+
+        let d_account = Account {
+            pubkey: d.clone(),
+            tier: Tier::Tertiary,
+        };
+        assert_eq!(d_account, db.read_account(&d.clone()).unwrap().unwrap());
+
+        // --------------
+        let a_new_contacts = HashSet::from([c.clone()]);
+
+        db.update_contact_list(&a, &a_new_contacts).unwrap();
+        let db_follows = db.get_follows(&a).unwrap();
+
+        assert_eq!(a_new_contacts, db_follows);
+
+        assert_eq!(a_account, db.read_account(&a).unwrap().unwrap());
+
+        let b_account = Account {
+            pubkey: b.clone(),
+            tier: Tier::Other,
+        };
+        assert_eq!(b_account, db.read_account(&b).unwrap().unwrap());
+
+        let c_account = Account {
+            pubkey: c.clone(),
+            tier: Tier::Secondary,
+        };
+        assert_eq!(c_account, db.read_account(&c.clone()).unwrap().unwrap());
+
+        let d_account = Account {
+            pubkey: d.clone(),
+            tier: Tier::Other,
+        };
+        assert_eq!(d_account, db.read_account(&d.clone()).unwrap().unwrap());
+        // Unfollow sec should also set t to other
+    }
+
+    // ----------
+    // A -> B, A -> C, B -> D
+    // A = 0, B = 1, C = 1, D = 2
+    // ----------
+    // A -> C, B -> D
+    // A = 0, B = 4, C = 1, D = 4
+    // ----------
+    // A -> B
+    // A = 0, B = 1, C = 1, D = 2
+    // ----------
+    #[test]
+    // #[traced_test]
+    fn test_primary_unfollow_with_tier_refollow() {
+        let a = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9".to_string();
+        let db = Db::new_in_memory(HashSet::from([a.clone()]));
+
+        let b = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203".to_string();
+        let c = "7c27a04b7c27299f16dc07d3eb8f28544f188bc7a34982328b7d581edc405dc2".to_string();
+
+        let a_follows = HashSet::from([b.clone(), c.clone()]);
+
+        let a_account = Account {
+            pubkey: a.clone(),
+            tier: Tier::Primary,
+        };
+        db.write_account(&a_account).unwrap();
+        db.set_contact_list(&a, &a_follows.clone()).unwrap();
+        db.update_follows(a_follows.clone(), Tier::Secondary)
+            .unwrap();
+
+        let d = "5b3a49bcbdf41f511f5c9034dbe46240863b73b39a2fdfebfb611f23b88d3922".to_string();
 
-    #[test]
-    #[serial]
-    fn test_get_events() {
-        let db = Db::new(HashSet::new());
-        db.clear_tables().unwrap();
-        let pubkey = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9";
+        let c_follows = HashSet::from([d.clone()]);
+        db.set_contact_list(&b, &c_follows).unwrap();
+        db.update_follows(c_follows, Tier::Tertiary).unwrap();
 
-        let timestamp = unix_time();
-        db.write_event(pubkey, timestamp).unwrap();
+        let c_account = Account {
+            pubkey: c.clone(),
+            tier: Tier::Secondary,
+        };
+        assert_eq!(c_account, db.read_account(&c.clone()).unwrap().unwrap());
 
-        let events = db.get_events(pubkey).unwrap();
+        // -------------
 
-        assert_eq!(vec![timestamp], events);
+        let a_new_contacts = HashSet::from([c.clone()]);
+
+        // Unfollow secondary
+        db.update_contact_list(&a, &a_new_contacts).unwrap();
+        let db_follows = db.get_follows(&a).unwrap();
+
+        assert_eq!(a_new_contacts, db_follows);
+
+        // ----------------
+
+        // Refollow secondary
+        db.update_contact_list(&a, &a_follows).unwrap();
+
+        assert_eq!(a_account, db.read_account(&a).unwrap().unwrap());
+
+        let b_account = Account {
+            pubkey: b.clone(),
+            tier: Tier::Secondary,
+        };
+        assert_eq!(b_account, db.read_account(&b).unwrap().unwrap());
+
+        let c_account = Account {
+            pubkey: c.clone(),
+            tier: Tier::Secondary,
+        };
+        assert_eq!(c_account, db.read_account(&c.clone()).unwrap().unwrap());
+
+        let d_account = Account {
+            pubkey: d.clone(),
+            tier: Tier::Tertiary,
+        };
+
+        assert_eq!(d_account, db.read_account(&d.clone()).unwrap().unwrap());
+        // Unfollow sec should also set t to other
     }
 
+    // -----------------
+    // A -> B, B -> C
+    // A = 0, B = 1, C = 2
+    // -----------------
+    // A -> C
+    // A = 0, B = 1, C = 1
+    // -----------------
     #[test]
-    #[serial]
-    fn test_set_contacts() {
-        let db = Db::new(HashSet::new());
-        debug!("setting contacts");
-        db.clear_tables().unwrap();
-        let pubkey = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9".to_string();
+    fn test_primary_follows_ter() {
+        let a = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9".to_string();
+        let db = Db::new_in_memory(HashSet::from([a.clone()]));
 
-        let follow_one =
-            "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203".to_string();
-        let follow_two =
-            "7c27a04b7c27299f16dc07d3eb8f28544f188bc7a34982328b7d581edc405dc2".to_string();
+        let b = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203".to_string();
+        let c = "7c27a04b7c27299f16dc07d3eb8f28544f188bc7a34982328b7d581edc405dc2".to_string();
 
-        let follows = HashSet::from([follow_one.clone(), follow_two.clone()]);
+        let a_follows = HashSet::from([b.clone()]);
 
         let primary_account = Account {
-            pubkey: pubkey.clone(),
+            pubkey: a.clone(),
             tier: Tier::Primary,
         };
         db.write_account(&primary_account).unwrap();
-        db.set_contact_list(&pubkey, &follows).unwrap();
-        db.update_follows(follows.clone(), Tier::Secondary).unwrap();
+        db.set_contact_list(&a, &a_follows.clone()).unwrap();
+        db.update_follows(a_follows.clone(), Tier::Secondary)
+            .unwrap();
 
-        let db_follows = db.get_follows(&pubkey).unwrap();
+        let b_follows = HashSet::from([c.clone()]);
 
-        assert_eq!(follows, db_follows);
+        db.update_contact_list(&b, &b_follows).unwrap();
+        let b_account = Account {
+            pubkey: b.clone(),
+            tier: Tier::Secondary,
+        };
+        assert_eq!(b_account, db.read_account(&b).unwrap().unwrap());
 
-        let db_followers = db.get_followers(&follow_one).unwrap();
-        assert_eq!(HashSet::from([pubkey.clone()]), db_followers);
-        let db_followers = db.get_followers(&follow_two).unwrap();
-        assert_eq!(HashSet::from([pubkey.clone()]), db_followers);
+        let c_account = Account {
+            pubkey: c.clone(),
+            tier: Tier::Tertiary,
+        };
+        assert_eq!(c_account, db.read_account(&c.clone()).unwrap().unwrap());
 
-        assert_eq!(primary_account, db.read_account(&pubkey).unwrap().unwrap());
-        let one_account = Account {
-            pubkey: follow_one.clone(),
+        let new_a_contacts = HashSet::from([b.clone(), c.clone()]);
+
+        db.update_contact_list(&a, &new_a_contacts).unwrap();
+
+        assert_eq!(primary_account, db.read_account(&a).unwrap().unwrap());
+
+        let a_account = Account {
+            pubkey: a.clone(),
+            tier: Tier::Primary,
+        };
+        assert_eq!(a_account, db.read_account(&a).unwrap().unwrap());
+
+        let b_account = Account {
+            pubkey: b.clone(),
             tier: Tier::Secondary,
         };
-        assert_eq!(one_account, db.read_account(&follow_one).unwrap().unwrap());
-        let two_account = Account {
-            pubkey: follow_two.clone(),
+        assert_eq!(b_account, db.read_account(&b.clone()).unwrap().unwrap());
+
+        let c_account = Account {
+            pubkey: c.clone(),
             tier: Tier::Secondary,
         };
-        assert_eq!(
-            two_account,
-            db.read_account(&follow_two.clone()).unwrap().unwrap()
-        );
+        assert_eq!(c_account, db.read_account(&c.clone()).unwrap().unwrap());
     }
 
-    // Test that a primary user can unfollow a user
-    // #[traced_test]
+    // -----------------
+    // A -> B, B -> C, C -> D
+    // A = 0, B = 1, C = 2, D = 3
+    // -----------------
+    // A -> B, C -> D
+    // A = 0, B = 1, C = 4, D = 4
+    // -----------------
     #[test]
-    #[serial]
-    fn test_primary_unfollow() {
-        let db = Db::new(HashSet::new());
-        db.clear_tables().unwrap();
-        let pubkey = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9".to_string();
+    fn test_demote_unvouched() {
+        let a = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9".to_string();
+        let db = Db::new_in_memory(HashSet::from([a.clone()]));
 
-        let a = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203".to_string();
-        let b = "7c27a04b7c27299f16dc07d3eb8f28544f188bc7a34982328b7d581edc405dc2".to_string();
+        let b = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203".to_string();
+        let c = "7c27a04b7c27299f16dc07d3eb8f28544f188bc7a34982328b7d581edc405dc2".to_string();
+        let d = "5b3a49bcbdf41f511f5c9034dbe46240863b73b39a2fdfebfb611f23b88d3922".to_string();
 
-        let a_follows = HashSet::from([a.clone(), b.clone()]);
+        for (pubkey, tier) in [
+            (&a, Tier::Primary),
+            (&b, Tier::Secondary),
+            (&c, Tier::Tertiary),
+            (&d, Tier::Quaternary),
+        ] {
+            db.set_tier(&HashSet::from([pubkey.clone()]), tier).unwrap();
+        }
+        db.set_contact_list(&a, &HashSet::from([b.clone()]))
+            .unwrap();
+        db.set_contact_list(&b, &HashSet::from([c.clone()]))
+            .unwrap();
+        db.set_contact_list(&c, &HashSet::from([d.clone()]))
+            .unwrap();
 
-        let a_account = Account {
-            pubkey: pubkey.clone(),
-            tier: Tier::Primary,
-        };
-        db.write_account(&a_account).unwrap();
-        db.set_contact_list(&pubkey, &a_follows.clone()).unwrap();
-        db.update_follows(a_follows, Tier::Secondary).unwrap();
+        assert!(db.demote_unvouched().unwrap().is_empty());
 
-        let new_contacts = HashSet::from([a.clone()]);
+        // C's only promoter unfollows it without the tier being updated
+        let unfollowed = HashSet::from([c.clone()]);
+        db.write_edges(&b, &HashSet::new(), &unfollowed).unwrap();
 
-        db.update_contact_list(&pubkey, &new_contacts).unwrap();
-        let db_follows = db.get_follows(&pubkey).unwrap();
+        let mut expected = vec![c.clone(), d.clone()];
+        expected.sort();
+        assert_eq!(expected, db.demote_unvouched().unwrap());
 
-        assert_eq!(new_contacts, db_follows);
+        assert_eq!(Tier::Secondary, db.read_account(&b).unwrap().unwrap().tier);
+        assert_eq!(Tier::Other, db.read_account(&c).unwrap().unwrap().tier);
+        assert_eq!(Tier::Other, db.read_account(&d).unwrap().unwrap().tier);
+    }
 
-        assert_eq!(a_account, db.read_account(&pubkey).unwrap().unwrap());
+    #[test]
+    fn test_demote_unvouched_pins() {
+        let a = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9".to_string();
+        let db = Db::new_in_memory(HashSet::from([a.clone()]));
 
-        let one_account = Account {
-            pubkey: a.clone(),
-            tier: Tier::Secondary,
+        let b = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203".to_string();
+        let c = "7c27a04b7c27299f16dc07d3eb8f28544f188bc7a34982328b7d581edc405dc2".to_string();
+
+        // B is pinned and followed by no one, C is only vouched for by B
+        for (pubkey, tier) in [
+            (&a, Tier::Primary),
+            (&b, Tier::Secondary),
+            (&c, Tier::Tertiary),
+        ] {
+            db.set_tier(&HashSet::from([pubkey.clone()]), tier).unwrap();
+        }
+        db.write_pin(&b, Tier::Secondary).unwrap();
+        db.set_contact_list(&b, &HashSet::from([c.clone()]))
+            .unwrap();
+
+        assert!(db.demote_unvouched().unwrap().is_empty());
+        assert_eq!(Tier::Secondary, db.read_account(&b).unwrap().unwrap().tier);
+        assert_eq!(Tier::Tertiary, db.read_account(&c).unwrap().unwrap().tier);
+
+        // Once unpinned neither is vouched for
+        db.remove_pin(&b).unwrap();
+        let mut expected = vec![b.clone(), c.clone()];
+        expected.sort();
+        assert_eq!(expected, db.demote_unvouched().unwrap());
+    }
+
+    #[test]
+    fn test_lower_tier() {
+        for tier in [
+            Tier::Secondary,
+            Tier::Tertiary,
+            Tier::Quaternary,
+            Tier::Grace,
+            Tier::Other,
+        ] {
+            assert!(tier.lower_tier() < tier);
+            assert_eq!(tier, tier.lower_tier().raise_tier());
+        }
+        assert_eq!(Tier::Primary, Tier::Primary.lower_tier());
+    }
+
+    // -----------------
+    // A -> B -> C -> D
+    // A = 0, B = 1, C = 2, D = 3
+    // -----------------
+    // B -> C -> D
+    // A = 0, B = 4, C = 4, D = 4
+    // -----------------
+    // A -> B -> C -> D
+    // A = 0, B = 1, C = 2, D = 3
+    // -----------------
+    #[test]
+    fn test_refollow_restores_tiers() {
+        let a = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9".to_string();
+        let db = Db::new_in_memory(HashSet::from([a.clone()]));
+
+        let b = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203".to_string();
+        let c = "7c27a04b7c27299f16dc07d3eb8f28544f188bc7a34982328b7d581edc405dc2".to_string();
+        let d = "5b3a49bcbdf41f511f5c9034dbe46240863b73b39a2fdfebfb611f23b88d3922".to_string();
+
+        db.set_tier(&HashSet::from([a.clone()]), Tier::Primary)
+            .unwrap();
+        for (follower, follow) in [(&a, &b), (&b, &c), (&c, &d)] {
+            db.update_contact_list(follower, &HashSet::from([follow.clone()]))
+                .unwrap();
+        }
+        let tiers = || {
+            [&b, &c, &d]
+                .iter()
+                .map(|p| db.read_account(p).unwrap().unwrap().tier)
+                .collect::<Vec<Tier>>()
         };
-        assert_eq!(one_account, db.read_account(&a).unwrap().unwrap());
+        let original = vec![Tier::Secondary, Tier::Tertiary, Tier::Quaternary];
+        assert_eq!(original, tiers());
 
-        let two_account = Account {
+        db.update_contact_list(&a, &HashSet::new()).unwrap();
+        assert_eq!(vec![Tier::Other; 3], tiers());
+
+        // Each account moves back up a tier below its best follower
+        db.update_contact_list(&a, &HashSet::from([b.clone()]))
+            .unwrap();
+        assert_eq!(original, tiers());
+    }
+
+    // -----------------
+    // A -> B, B -> C, C -> B
+    // A = 0, B = 1, C = 2
+    // -----------------
+    // B -> C, C -> B
+    // A = 0, B = 4, C = 4
+    // -----------------
+    #[test]
+    fn test_mutual_follows() {
+        let a = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9".to_string();
+        let db = Db::new_in_memory(HashSet::from([a.clone()]));
+
+        let b = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203".to_string();
+        let c = "7c27a04b7c27299f16dc07d3eb8f28544f188bc7a34982328b7d581edc405dc2".to_string();
+
+        db.set_tier(&HashSet::from([a.clone()]), Tier::Primary)
+            .unwrap();
+        db.update_contact_list(&a, &HashSet::from([b.clone()]))
+            .unwrap();
+        db.update_contact_list(&b, &HashSet::from([c.clone()]))
+            .unwrap();
+        db.update_contact_list(&c, &HashSet::from([b.clone()]))
+            .unwrap();
+        let tier = |pubkey: &str| db.read_account(pubkey).unwrap().unwrap().tier;
+        assert_eq!(Tier::Secondary, tier(&b));
+        assert_eq!(Tier::Tertiary, tier(&c));
+
+        // Applying the same lists again changes nothing
+        db.update_contact_list(&b, &HashSet::from([c.clone()]))
+            .unwrap();
+        db.update_contact_list(&c, &HashSet::from([b.clone()]))
+            .unwrap();
+        assert_eq!(Tier::Secondary, tier(&b));
+        assert_eq!(Tier::Tertiary, tier(&c));
+
+        // The pair only vouch for each other once A unfollows
+        db.update_contact_list(&a, &HashSet::new()).unwrap();
+        assert_eq!(Tier::Other, tier(&b));
+        assert_eq!(Tier::Other, tier(&c));
+
+        db.recompute_tiers().unwrap();
+        assert_eq!(Tier::Other, tier(&b));
+        assert_eq!(Tier::Other, tier(&c));
+    }
+
+    #[test]
+    fn test_accounts_by_tier() {
+        let db = Db::new_in_memory(HashSet::new());
+        let pubkeys: Vec<String> = (0..5u32).map(|n| format!("{n:064x}")).collect();
+        db.set_tier(&HashSet::from([pubkeys[0].clone()]), Tier::Primary)
+            .unwrap();
+        db.set_tier(
+            &HashSet::from([pubkeys[3].clone(), pubkeys[1].clone()]),
+            Tier::Secondary,
+        )
+        .unwrap();
+        db.set_tier(&HashSet::from([pubkeys[2].clone()]), Tier::Grace)
+            .unwrap();
+        db.set_tier(&HashSet::from([pubkeys[4].clone()]), Tier::Other)
+            .unwrap();
+
+        assert_eq!(
+            vec![pubkeys[1].clone(), pubkeys[3].clone()],
+            db.accounts_by_tier(Tier::Secondary).unwrap()
+        );
+        assert_eq!(
+            vec![pubkeys[0].clone()],
+            db.accounts_by_tier(Tier::Primary).unwrap()
+        );
+        assert_eq!(
+            vec![pubkeys[2].clone()],
+            db.accounts_by_tier(Tier::Grace).unwrap()
+        );
+        assert_eq!(
+            vec![pubkeys[4].clone()],
+            db.accounts_by_tier(Tier::Other).unwrap()
+        );
+        assert!(db.accounts_by_tier(Tier::Tertiary).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_read_all_accounts() {
+        let db = Db::new_in_memory(HashSet::new());
+        assert!(db.read_all_accounts().unwrap().is_empty());
+
+        let a = format!("{:064x}", 1);
+        let b = format!("{:064x}", 2);
+        db.set_tier(&HashSet::from([b.clone()]), Tier::Grace)
+            .unwrap();
+        db.set_tier(&HashSet::from([a.clone()]), Tier::Primary)
+            .unwrap();
+        assert_eq!(
+            vec![
+                Account {
+                    pubkey: a,
+                    tier: Tier::Primary
+                },
+                Account {
+                    pubkey: b,
+                    tier: Tier::Grace
+                },
+            ],
+            db.read_all_accounts().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_graph_built_at() {
+        let db = Db::new_in_memory(HashSet::new());
+        assert_eq!(None, db.read_graph_built_at().unwrap());
+        db.write_graph_built_at(1_700_000_000).unwrap();
+
+        // Kept when the graph is cleared for the next build
+        db.clear_tables().unwrap();
+        assert_eq!(Some(1_700_000_000), db.read_graph_built_at().unwrap());
+        assert!(db.file_size().unwrap() > 0);
+    }
+
+    #[test]
+    fn test_tier_changes() {
+        let a = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9".to_string();
+        let b = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203".to_string();
+        let mut db = Db::new_in_memory(HashSet::from([a.clone()]));
+        let (sender, mut changes) = mpsc::unbounded_channel();
+        db.set_tier_changes(sender);
+
+        db.set_tier(&HashSet::from([a.clone()]), Tier::Primary)
+            .unwrap();
+        db.update_contact_list(&a, &HashSet::from([b.clone()]))
+            .unwrap();
+        let change = |old_tier, new_tier| TierChange {
             pubkey: b.clone(),
-            tier: Tier::Other,
+            old_tier,
+            new_tier,
         };
-        assert_eq!(two_account, db.read_account(&b.clone()).unwrap().unwrap());
+        assert_eq!(Ok(change(None, Tier::Secondary)), changes.try_recv());
+        assert!(changes.try_recv().is_err());
+
+        // Recomputing the same tier is not a change
+        db.update_account(&b, Tier::Other).unwrap();
+        assert!(changes.try_recv().is_err());
+
+        db.update_contact_list(&a, &HashSet::new()).unwrap();
+        assert_eq!(
+            Ok(change(Some(Tier::Secondary), Tier::Other)),
+            changes.try_recv()
+        );
     }
 
-    // ----------
-    // A -> B, A -> C, B -> D
-    // A = 0, B = 1, C = 1, D = 2
-    // ----------
-    // A -> C, B -> D
-    // A = 0, B = 4, C = 1, D = 4
-    // ----------
     #[test]
-    #[serial]
-    // #[traced_test]
-    fn test_primary_unfollow_with_tier() {
+    fn test_self_follow() {
         let a = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9".to_string();
-        let db = Db::new(HashSet::from([a.clone()]));
-        db.clear_tables().unwrap();
+        let db = Db::new_in_memory(HashSet::from([a.clone()]));
 
         let b = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203".to_string();
         let c = "7c27a04b7c27299f16dc07d3eb8f28544f188bc7a34982328b7d581edc405dc2".to_string();
 
-        let a_follows = HashSet::from([b.clone(), c.clone()]);
+        db.set_tier(&HashSet::from([a.clone()]), Tier::Primary)
+            .unwrap();
+        db.update_contact_list(&a, &HashSet::from([b.clone()]))
+            .unwrap();
+        db.update_contact_list(&b, &HashSet::from([b.clone(), c.clone()]))
+            .unwrap();
+        assert_eq!(db.get_follows(&b).unwrap(), HashSet::from([c.clone()]));
+        assert!(!db.get_followers(&b).unwrap().contains(&b));
 
-        let a_account = Account {
-            pubkey: a.clone(),
-            tier: Tier::Primary,
-        };
-        db.write_account(&a_account).unwrap();
-        db.set_contact_list(&a, &a_follows.clone()).unwrap();
-        db.update_follows(a_follows, Tier::Secondary).unwrap();
+        // Once unfollowed B can not keep its own tier
+        db.update_contact_list(&a, &HashSet::new()).unwrap();
+        let tier = |pubkey: &str| db.read_account(pubkey).unwrap().unwrap().tier;
+        assert_eq!(Tier::Other, tier(&b));
 
-        let d = "5b3a49bcbdf41f511f5c9034dbe46240863b73b39a2fdfebfb611f23b88d3922".to_string();
+        db.set_contact_list(&c, &HashSet::from([c.clone()]))
+            .unwrap();
+        assert!(db.get_follows(&c).unwrap().is_empty());
+    }
 
-        let b_follows = HashSet::from([d.clone()]);
-        db.set_contact_list(&b, &b_follows).unwrap();
-        db.update_follows(b_follows, Tier::Tertiary).unwrap();
+    #[test]
+    fn test_unknown_contact_list() {
+        let a = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9".to_string();
+        let b = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203".to_string();
+        let c = "7c27a04b7c27299f16dc07d3eb8f28544f188bc7a34982328b7d581edc405dc2".to_string();
+        let db = Db::new_in_memory(HashSet::from([a.clone()]));
+        db.set_tier(&HashSet::from([a.clone()]), Tier::Primary)
+            .unwrap();
+        db.update_contact_list(&a, &HashSet::from([b.clone()]))
+            .unwrap();
 
-        let d_account = Account {
-            pubkey: d.clone(),
-            tier: Tier::Tertiary,
-        };
-        assert_eq!(d_account, db.read_account(&d.clone()).unwrap().unwrap());
+        // The follows of an author never seen are recorded
+        let unknown =
+            "04918dfc36c93e7db6cc0d60f37e1522f1c36b64d3f4b424c532d7c595febbc5".to_string();
+        db.update_contact_list(&unknown, &HashSet::from([b.clone(), c.clone()]))
+            .unwrap();
+        assert_eq!(
+            HashSet::from([b.clone(), c.clone()]),
+            db.get_follows(&unknown).unwrap()
+        );
+        assert!(db.get_followers(&c).unwrap().contains(&unknown));
 
-        // --------------
-        let a_new_contacts = HashSet::from([c.clone()]);
+        // Neither it nor its follows become accounts, those in the graph keep their tiers
+        let tier = |pubkey: &str| db.read_account(pubkey).unwrap().map(|a| a.tier);
+        assert_eq!(None, tier(&unknown));
+        assert_eq!(None, tier(&c));
+        assert_eq!(Some(Tier::Secondary), tier(&b));
 
-        db.update_contact_list(&a, &a_new_contacts).unwrap();
-        let db_follows = db.get_follows(&a).unwrap();
+        // Once followed it is tiered like any account
+        db.update_contact_list(&b, &HashSet::from([unknown.clone()]))
+            .unwrap();
+        assert_eq!(Some(Tier::Tertiary), tier(&unknown));
+        assert_eq!(Some(Tier::Quaternary), tier(&c));
+    }
 
-        assert_eq!(a_new_contacts, db_follows);
+    // -----------------
+    // A -> B -> C -> D -> E -> C, A -> F -> D
+    // A = 0, B = 1, C = 2, D = 2, E = 3, F = 1
+    // -----------------
+    // A -> F -> D -> E -> C -> D
+    // A = 0, B = 4, C = grace, D = 2, E = 3, F = 1
+    // -----------------
+    // A
+    // A = 0, B = 4, C = 4, D = 4, E = 4, F = 4
+    // -----------------
+    #[test]
+    fn test_transitive_demotion() {
+        let a = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9".to_string();
+        let db = Db::new_in_memory(HashSet::from([a.clone()]));
 
-        assert_eq!(a_account, db.read_account(&a).unwrap().unwrap());
+        let b = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203".to_string();
+        let c = "7c27a04b7c27299f16dc07d3eb8f28544f188bc7a34982328b7d581edc405dc2".to_string();
+        let d = "5b3a49bcbdf41f511f5c9034dbe46240863b73b39a2fdfebfb611f23b88d3922".to_string();
+        let e = "04918dfc36c93e7db6cc0d60f37e1522f1c36b64d3f4b424c532d7c595febbc5".to_string();
+        let f = "82341f882b6eabcd2ba7f1ef90aad961cf074af15b9ef44a09f9d2a8fbfbe6a2".to_string();
 
-        let b_account = Account {
-            pubkey: b.clone(),
-            tier: Tier::Other,
-        };
-        assert_eq!(b_account, db.read_account(&b).unwrap().unwrap());
+        db.set_tier(&HashSet::from([a.clone()]), Tier::Primary)
+            .unwrap();
+        db.update_contact_list(&a, &HashSet::from([b.clone(), f.clone()]))
+            .unwrap();
+        for (follower, follow) in [(&b, &c), (&f, &d), (&c, &d), (&d, &e), (&e, &c)] {
+            db.update_contact_list(follower, &HashSet::from([follow.clone()]))
+                .unwrap();
+        }
+        let tier = |pubkey: &str| db.read_account(pubkey).unwrap().unwrap().tier;
+        assert_eq!(Tier::Tertiary, tier(&c));
+        assert_eq!(Tier::Tertiary, tier(&d));
+        assert_eq!(Tier::Quaternary, tier(&e));
 
-        let c_account = Account {
-            pubkey: c.clone(),
-            tier: Tier::Secondary,
-        };
-        assert_eq!(c_account, db.read_account(&c.clone()).unwrap().unwrap());
+        // C is now only reached around the cycle through F
+        db.update_contact_list(&a, &HashSet::from([f.clone()]))
+            .unwrap();
+        assert_eq!(Tier::Other, tier(&b));
+        assert_eq!(Tier::Grace, tier(&c));
+        assert_eq!(Tier::Tertiary, tier(&d));
+        assert_eq!(Tier::Quaternary, tier(&e));
+
+        // Nothing below A is reachable, the cycle settles at other
+        db.update_contact_list(&a, &HashSet::new()).unwrap();
+        for pubkey in [&b, &c, &d, &e, &f] {
+            assert_eq!(Tier::Other, tier(pubkey));
+        }
 
-        let d_account = Account {
-            pubkey: d.clone(),
-            tier: Tier::Other,
-        };
-        assert_eq!(d_account, db.read_account(&d.clone()).unwrap().unwrap());
-        // Unfollow sec should also set t to other
+        // Incremental tiers agree with a full recompute
+        db.update_contact_list(&a, &HashSet::from([f.clone()]))
+            .unwrap();
+        let incremental: Vec<Tier> = [&b, &c, &d, &e, &f]
+            .iter()
+            .map(|p| tier(p.as_str()))
+            .collect();
+        db.recompute_tiers().unwrap();
+        let recomputed: Vec<Tier> = [&b, &c, &d, &e, &f]
+            .iter()
+            .map(|p| tier(p.as_str()))
+            .collect();
+        assert_eq!(recomputed, incremental);
     }
 
-    // ----------
-    // A -> B, A -> C, B -> D
-    // A = 0, B = 1, C = 1, D = 2
-    // ----------
-    // A -> C, B -> D
-    // A = 0, B = 4, C = 1, D = 4
-    // ----------
-    // A -> B
-    // A = 0, B = 1, C = 1, D = 2
-    // ----------
+    // -----------------
+    // A -> B -> C -> D -> E -> F
+    // A = 0, B = 1, C = 2, D = 3, E = grace, F = other
+    // -----------------
     #[test]
-    #[serial]
-    // #[traced_test]
-    fn test_primary_unfollow_with_tier_refollow() {
+    fn test_grace_tier() {
         let a = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9".to_string();
-        let db = Db::new(HashSet::from([a.clone()]));
-        db.clear_tables().unwrap();
+        let db = Db::new_in_memory(HashSet::from([a.clone()]));
 
         let b = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203".to_string();
         let c = "7c27a04b7c27299f16dc07d3eb8f28544f188bc7a34982328b7d581edc405dc2".to_string();
+        let d = "5b3a49bcbdf41f511f5c9034dbe46240863b73b39a2fdfebfb611f23b88d3922".to_string();
+        let e = "04918dfc36c93e7db6cc0d60f37e1522f1c36b64d3f4b424c532d7c595febbc5".to_string();
+        let f = "82341f882b6eabcd2ba7f1ef90aad961cf074af15b9ef44a09f9d2a8fbfbe6a2".to_string();
 
-        let a_follows = HashSet::from([b.clone(), c.clone()]);
-
-        let a_account = Account {
-            pubkey: a.clone(),
-            tier: Tier::Primary,
-        };
-        db.write_account(&a_account).unwrap();
-        db.set_contact_list(&a, &a_follows.clone()).unwrap();
-        db.update_follows(a_follows.clone(), Tier::Secondary)
+        db.set_tier(&HashSet::from([a.clone()]), Tier::Primary)
             .unwrap();
+        for (follower, follow) in [(&a, &b), (&b, &c), (&c, &d), (&d, &e), (&e, &f)] {
+            db.update_contact_list(follower, &HashSet::from([follow.clone()]))
+                .unwrap();
+        }
 
-        let d = "5b3a49bcbdf41f511f5c9034dbe46240863b73b39a2fdfebfb611f23b88d3922".to_string();
+        assert_eq!(Tier::Quaternary, db.read_account(&d).unwrap().unwrap().tier);
+        assert_eq!(Tier::Grace, db.read_account(&e).unwrap().unwrap().tier);
+        // Nothing vouches for F so it is not added
+        assert_eq!(None, db.read_account(&f).unwrap());
+        assert!(Tier::Quaternary < Tier::Grace && Tier::Grace < Tier::Other);
+        assert_eq!(Tier::Grace, Tier::from(Tier::Grace as u8));
+    }
 
-        let c_follows = HashSet::from([d.clone()]);
-        db.set_contact_list(&b, &c_follows).unwrap();
-        db.update_follows(c_follows, Tier::Tertiary).unwrap();
+    // -----------------
+    // A -> B, A -> C, B -> A
+    // A = 0, B = 1, C = 4
+    // -----------------
+    #[test]
+    fn test_mutual_primary_follows() {
+        let a = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9".to_string();
+        let mut db = Db::new_in_memory(HashSet::from([a.clone()]));
+        db.set_mutual_primary_follows(true);
 
-        let c_account = Account {
-            pubkey: c.clone(),
-            tier: Tier::Secondary,
-        };
-        assert_eq!(c_account, db.read_account(&c.clone()).unwrap().unwrap());
+        let b = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203".to_string();
+        let c = "7c27a04b7c27299f16dc07d3eb8f28544f188bc7a34982328b7d581edc405dc2".to_string();
 
-        // -------------
+        db.set_tier(&HashSet::from([a.clone()]), Tier::Primary)
+            .unwrap();
+        db.update_contact_list(&a, &HashSet::from([b.clone(), c.clone()]))
+            .unwrap();
+        let tier = |pubkey: &str| db.read_account(pubkey).unwrap().map(|a| a.tier);
+        assert_eq!(None, tier(&b));
+        assert_eq!(None, tier(&c));
 
-        let a_new_contacts = HashSet::from([c.clone()]);
+        // B follows back
+        db.update_contact_list(&b, &HashSet::from([a.clone()]))
+            .unwrap();
+        assert_eq!(Some(Tier::Secondary), tier(&b));
+        assert_eq!(None, tier(&c));
 
-        // Unfollow secondary
-        db.update_contact_list(&a, &a_new_contacts).unwrap();
-        let db_follows = db.get_follows(&a).unwrap();
+        db.recompute_tiers().unwrap();
+        assert_eq!(Some(Tier::Secondary), tier(&b));
+        assert_eq!(None, tier(&c));
+    }
 
-        assert_eq!(a_new_contacts, db_follows);
+    #[test]
+    fn test_all_follow_edges() {
+        let db = Db::new_in_memory(HashSet::new());
 
-        // ----------------
+        let a = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9".to_string();
+        let b = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203".to_string();
+        let c = "7c27a04b7c27299f16dc07d3eb8f28544f188bc7a34982328b7d581edc405dc2".to_string();
 
-        // Refollow secondary
-        db.update_contact_list(&a, &a_follows).unwrap();
+        db.set_contact_list(&a, &HashSet::from([b.clone(), c.clone()]))
+            .unwrap();
+        db.set_contact_list(&b, &HashSet::from([c.clone()]))
+            .unwrap();
+        db.set_contact_list(&c, &HashSet::from([a.clone()]))
+            .unwrap();
 
-        assert_eq!(a_account, db.read_account(&a).unwrap().unwrap());
+        let expected = HashSet::from([
+            (a.clone(), b.clone()),
+            (a.clone(), c.clone()),
+            (b.clone(), c.clone()),
+            (c.clone(), a.clone()),
+        ]);
+        let edges = db.all_follow_edges().unwrap();
+        assert_eq!(4, edges.len());
+        assert_eq!(expected, edges.into_iter().collect());
+
+        // Pages end on a follower boundary
+        let first = db.follow_edges_page(None, 1).unwrap();
+        assert_eq!(2, first.len());
+        assert!(first.iter().all(|(follower, _)| follower == &a));
+        let second = db.follow_edges_page(Some(&a), 1).unwrap();
+        assert_eq!(vec![(c.clone(), a.clone())], second);
+    }
 
-        let b_account = Account {
-            pubkey: b.clone(),
-            tier: Tier::Secondary,
-        };
-        assert_eq!(b_account, db.read_account(&b).unwrap().unwrap());
+    #[test]
+    fn test_clear_tables() {
+        let db = Db::new_in_memory(HashSet::new());
 
-        let c_account = Account {
-            pubkey: c.clone(),
-            tier: Tier::Secondary,
-        };
-        assert_eq!(c_account, db.read_account(&c.clone()).unwrap().unwrap());
+        // 50k edges, 500 accounts following 100 each
+        let pubkeys: Vec<String> = (0..500u32).map(|n| format!("{n:064x}")).collect();
+        db.set_tier(&pubkeys.iter().cloned().collect(), Tier::Secondary)
+            .unwrap();
+        let contact_lists: Vec<(String, HashSet<String>)> = pubkeys
+            .iter()
+            .enumerate()
+            .map(|(i, p)| {
+                let follows = (1..=100).map(|n| pubkeys[(i + n) % 500].clone());
+                (p.clone(), follows.collect())
+            })
+            .collect();
+        db.write_contact_lists(&contact_lists).unwrap();
+        assert_eq!(50_000, db.count_follows().unwrap());
+
+        let start = std::time::Instant::now();
+        db.clear_tables().unwrap();
+        debug!("Cleared 50k edges in {:?}", start.elapsed());
 
-        let d_account = Account {
-            pubkey: d.clone(),
-            tier: Tier::Tertiary,
-        };
+        assert_eq!(0, db.count_follows().unwrap());
+        assert!(db.count_by_tier().unwrap().is_empty());
+        assert!(db.get_followers(&pubkeys[0]).unwrap().is_empty());
 
-        assert_eq!(d_account, db.read_account(&d.clone()).unwrap().unwrap());
-        // Unfollow sec should also set t to other
+        // The recreated tables take writes
+        db.set_tier(&HashSet::from([pubkeys[0].clone()]), Tier::Primary)
+            .unwrap();
+        db.set_contact_list(&pubkeys[0], &HashSet::from([pubkeys[1].clone()]))
+            .unwrap();
+        assert_eq!(1, db.count_follows().unwrap());
     }
 
-    // -----------------
-    // A -> B, B -> C
-    // A = 0, B = 1, C = 2
-    // -----------------
-    // A -> C
-    // A = 0, B = 1, C = 1
-    // -----------------
     #[test]
-    #[serial]
-    fn test_primary_follows_ter() {
-        let a = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9".to_string();
-        let db = Db::new(HashSet::from([a.clone()]));
-        db.clear_tables().unwrap();
+    fn test_many_followers() {
+        let pubkey = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9";
+        let db = Db::new_in_memory(HashSet::new());
 
-        let b = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203".to_string();
-        let c = "7c27a04b7c27299f16dc07d3eb8f28544f188bc7a34982328b7d581edc405dc2".to_string();
+        let followers: Vec<String> = (0..300u32).map(|n| format!("{n:064x}")).collect();
+        let (far, near) = followers.split_at(299);
+        db.set_tier(&far.iter().cloned().collect(), Tier::Quaternary)
+            .unwrap();
+        db.set_tier(&near.iter().cloned().collect(), Tier::Tertiary)
+            .unwrap();
+        let contact_lists: Vec<(String, HashSet<String>)> = followers
+            .iter()
+            .map(|f| (f.clone(), HashSet::from([pubkey.to_string()])))
+            .collect();
+        db.write_contact_lists(&contact_lists).unwrap();
+
+        let mut lookup: HashSet<String> = followers.iter().cloned().collect();
+        lookup.insert(pubkey.to_string());
+        let tiers = db.get_account_tiers(lookup).unwrap();
+        assert_eq!(301, tiers.len());
+        assert_eq!(Some(&Tier::Other), tiers.get(pubkey));
+        assert_eq!(Some(&Tier::Tertiary), tiers.get(&followers[299]));
+
+        // The single tertiary follower among hundreds sets the tier
+        db.update_account(pubkey, Tier::Other).unwrap();
+        assert_eq!(
+            Tier::Quaternary,
+            db.read_account(pubkey).unwrap().unwrap().tier
+        );
+    }
 
-        let a_follows = HashSet::from([b.clone()]);
+    #[test]
+    fn test_prune_events() {
+        let db = Db::new_in_memory(HashSet::new());
+        let pubkey = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9";
+        for timestamp in [100, 200, 300, 400] {
+            db.write_event(pubkey, timestamp).unwrap();
+            db.write_bytes(pubkey, timestamp, 10).unwrap();
+        }
 
-        let primary_account = Account {
-            pubkey: a.clone(),
-            tier: Tier::Primary,
-        };
-        db.write_account(&primary_account).unwrap();
-        db.set_contact_list(&a, &a_follows.clone()).unwrap();
-        db.update_follows(a_follows.clone(), Tier::Secondary)
+        assert_eq!(2, db.prune_events(300).unwrap());
+        let mut events = db.get_events(pubkey).unwrap();
+        events.sort();
+        assert_eq!(vec![300, 400], events);
+        assert_eq!(20, db.get_bytes(pubkey, 0).unwrap());
+        assert_eq!(0, db.prune_events(300).unwrap());
+    }
+
+    #[test]
+    fn test_new_in_memory() {
+        let a = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9".to_string();
+        let first = Db::new_in_memory(HashSet::new());
+        let second = Db::new_in_memory(HashSet::new());
+        assert_ne!(first.path, second.path);
+
+        first
+            .set_tier(&HashSet::from([a.clone()]), Tier::Primary)
             .unwrap();
+        assert_eq!(first.read_account(&a).unwrap().unwrap().tier, Tier::Primary);
+        assert!(second.read_account(&a).unwrap().is_none());
 
-        let b_follows = HashSet::from([c.clone()]);
+        let path = first.path.clone();
+        assert!(std::path::Path::new(&path).exists());
+        drop(first);
+        assert!(!std::path::Path::new(&path).exists());
+    }
 
-        db.update_contact_list(&b, &b_follows).unwrap();
-        let b_account = Account {
-            pubkey: b.clone(),
-            tier: Tier::Secondary,
+    #[test]
+    fn test_denials() {
+        let db = Db::new_in_memory(HashSet::new());
+        let pubkey = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9";
+        let denial = |timestamp: u64, message: &str| Denial {
+            timestamp,
+            pubkey: pubkey.to_string(),
+            tier: Tier::Other,
+            kind: 1,
+            message: message.to_string(),
         };
-        assert_eq!(b_account, db.read_account(&b).unwrap().unwrap());
+        for (timestamp, message) in [(100, "first"), (200, "second"), (200, "third")] {
+            db.write_denial(&denial(timestamp, message)).unwrap();
+        }
 
-        let c_account = Account {
-            pubkey: c.clone(),
-            tier: Tier::Tertiary,
+        let messages = |limit| -> Vec<String> {
+            db.read_denials(pubkey, limit)
+                .unwrap()
+                .into_iter()
+                .map(|d| d.message)
+                .collect()
         };
-        assert_eq!(c_account, db.read_account(&c.clone()).unwrap().unwrap());
-
-        let new_a_contacts = HashSet::from([b.clone(), c.clone()]);
+        assert_eq!(3, messages(10).len());
+        assert_eq!("first", messages(10)[2]);
+        assert_eq!(2, messages(2).len());
+        assert!(!messages(2).contains(&"first".to_string()));
+        assert!(db.read_denials("other", 10).unwrap().is_empty());
+
+        db.prune_events(200).unwrap();
+        assert_eq!(2, messages(10).len());
+    }
 
-        db.update_contact_list(&a, &new_a_contacts).unwrap();
+    #[test]
+    fn test_compact() {
+        let temp = temp_path(".redb");
+        let path = temp.to_str().unwrap();
+        let pubkey = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9";
+        let follow = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203";
+        let mut db = Db::open(path, HashSet::new());
+        db.set_tier(&HashSet::from([pubkey.to_string()]), Tier::Secondary)
+            .unwrap();
+        db.set_contact_list(pubkey, &HashSet::from([follow.to_string()]))
+            .unwrap();
+        db.write_pin(follow, Tier::Tertiary).unwrap();
+        db.write_event(pubkey, 5000).unwrap();
+        let write_txn = db.db.begin_write().unwrap();
+        {
+            let mut table = write_txn.open_multimap_table(EVENTTABLE).unwrap();
+            for n in 0..50_000u64 {
+                table
+                    .insert(format!("{n:064x}").as_str(), n % 1000)
+                    .unwrap();
+            }
+        }
+        write_txn.commit().unwrap();
+        assert_eq!(50_000, db.prune_events(1000).unwrap());
+
+        let (before, after) = db.compact().unwrap();
+        assert!(after < before);
+        assert_eq!(after, std::fs::metadata(path).unwrap().len());
+
+        // Still usable and everything kept is in the file that replaced it
+        db.write_event(pubkey, 6000).unwrap();
+        drop(db);
+        let db = Db::open(path, HashSet::new());
+        let mut events = db.get_events(pubkey).unwrap();
+        events.sort();
+        assert_eq!(vec![5000, 6000], events);
+        assert_eq!(
+            Some(Tier::Secondary),
+            db.read_account(pubkey).unwrap().map(|a| a.tier)
+        );
+        assert_eq!(
+            HashSet::from([follow.to_string()]),
+            db.get_follows(pubkey).unwrap()
+        );
+        assert_eq!(Some(Tier::Tertiary), db.read_pin(follow).unwrap());
+    }
 
-        assert_eq!(primary_account, db.read_account(&a).unwrap().unwrap());
+    #[test]
+    fn test_update_contact_list_edges() {
+        let a = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9".to_string();
+        let db = Db::new_in_memory(HashSet::from([a.clone()]));
 
-        let a_account = Account {
-            pubkey: a.clone(),
-            tier: Tier::Primary,
-        };
-        assert_eq!(a_account, db.read_account(&a).unwrap().unwrap());
+        let b = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203".to_string();
+        let c = "7c27a04b7c27299f16dc07d3eb8f28544f188bc7a34982328b7d581edc405dc2".to_string();
 
-        let b_account = Account {
-            pubkey: b.clone(),
-            tier: Tier::Secondary,
-        };
-        assert_eq!(b_account, db.read_account(&b.clone()).unwrap().unwrap());
+        db.set_tier(&HashSet::from([a.clone()]), Tier::Primary)
+            .unwrap();
+        db.update_contact_list(&a, &HashSet::from([b.clone()]))
+            .unwrap();
+        db.update_contact_list(&a, &HashSet::from([c.clone()]))
+            .unwrap();
 
-        let c_account = Account {
-            pubkey: c.clone(),
-            tier: Tier::Secondary,
-        };
-        assert_eq!(c_account, db.read_account(&c.clone()).unwrap().unwrap());
+        assert_eq!(HashSet::from([c.clone()]), db.get_follows(&a).unwrap());
+        assert!(db.get_followers(&b).unwrap().is_empty());
+        assert_eq!(HashSet::from([a.clone()]), db.get_followers(&c).unwrap());
     }
 }
@@ -10,18 +10,31 @@ pub enum Error {
     DBError(redb::Error),
     #[error("Not in db")]
     NotFound,
+    #[error("DB lock poisoned")]
+    LockPoisoned,
     #[error("Serde error")]
     SerdeError(serde_json::Error),
     #[error("Nostr error")]
     NostrError(nostr_sdk::client::Error),
+    #[error("Relay error: {0}")]
+    RelayError(String),
+    #[error("Relay did not answer within {0:?}")]
+    RelayTimeout(std::time::Duration),
     #[error("Join error")]
     JoinError(tokio::task::JoinError),
+    #[error("Subscriber error: {0}")]
+    SubscriberError(String),
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
     #[error("Tag error: {0}")]
     TagError(#[from] TagError),
     #[error("Secp256k1 error: {0}")]
     Secp256k1Error(#[from] Secp256k1Error),
     #[error("ID error: {0}")]
     IdError(#[from] IdError),
+    #[cfg(feature = "otel")]
+    #[error("Telemetry error: {0}")]
+    TelemetryError(#[from] opentelemetry::trace::TraceError),
 }
 
 impl From<redb::Error> for Error {
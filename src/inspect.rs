@@ -0,0 +1,119 @@
+//! Offline queries of the DB file for debugging tiers without the server
+use crate::db::Db;
+use crate::error::Error;
+
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::Path;
+
+pub const USAGE: &str =
+    "Usage: contact-group-auth --inspect <tier|follows|followers> <pubkey> [--db-path <path>]
+       contact-group-auth --inspect stats [--db-path <path>]";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Query {
+    /// Tier of an account and any pin on it
+    Tier(String),
+    Follows(String),
+    Followers(String),
+    /// Accounts per tier and total follows
+    Stats,
+}
+
+impl Query {
+    /// Query of the arguments after `--inspect`, `None` when they are not one
+    pub fn from_args(args: &[String]) -> Option<Self> {
+        let pubkey = || args.get(1).filter(|a| !a.starts_with("--")).cloned();
+        match args.first()?.as_str() {
+            "tier" => Some(Self::Tier(pubkey()?)),
+            "follows" => Some(Self::Follows(pubkey()?)),
+            "followers" => Some(Self::Followers(pubkey()?)),
+            "stats" => Some(Self::Stats),
+            _ => None,
+        }
+    }
+}
+
+/// Opens the DB at `path` without connecting to any relay and writes the answer to `out`
+pub fn run(path: &str, query: &Query, out: &mut impl Write) -> Result<(), Error> {
+    // Opening would create an empty DB in its place
+    if !Path::new(path).exists() {
+        return Err(Error::NotFound);
+    }
+    let db = Db::open(path, HashSet::new());
+
+    match query {
+        Query::Tier(pubkey) => match db.read_account(pubkey)? {
+            Some(account) => {
+                write!(out, "{:?}", account.tier)?;
+                if let Some(pin) = db.read_pin(pubkey)? {
+                    write!(out, " (pinned {pin:?})")?;
+                }
+                writeln!(out)?;
+            }
+            None => writeln!(out, "Not in graph")?,
+        },
+        Query::Follows(pubkey) => write_sorted(out, db.get_follows(pubkey)?)?,
+        Query::Followers(pubkey) => write_sorted(out, db.get_followers(pubkey)?)?,
+        Query::Stats => {
+            for (tier, count) in db.count_by_tier()? {
+                writeln!(out, "{tier:?}: {count}")?;
+            }
+            writeln!(out, "Follows: {}", db.count_follows()?)?;
+        }
+    }
+    Ok(())
+}
+
+fn write_sorted(out: &mut impl Write, pubkeys: HashSet<String>) -> Result<(), Error> {
+    let mut pubkeys: Vec<String> = pubkeys.into_iter().collect();
+    pubkeys.sort();
+    for pubkey in pubkeys {
+        writeln!(out, "{pubkey}")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Tier;
+    use crate::test_utils::temp_path;
+
+    #[test]
+    fn test_inspect() {
+        let a = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9".to_string();
+        let b = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203".to_string();
+        let temp = temp_path(".redb");
+        let path = temp.to_str().unwrap();
+        {
+            let db = Db::open(path, HashSet::from([a.clone()]));
+            db.set_tier(&HashSet::from([a.clone()]), Tier::Primary)
+                .unwrap();
+            db.update_contact_list(&a, &HashSet::from([b.clone()]))
+                .unwrap();
+        }
+
+        let query = |args: &[&str]| {
+            let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+            let mut out = Vec::new();
+            run(path, &Query::from_args(&args).unwrap(), &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(query(&["tier", &b]), "Secondary\n");
+        assert_eq!(query(&["tier", "unknown"]), "Not in graph\n");
+        assert_eq!(query(&["follows", &a]), format!("{b}\n"));
+        assert_eq!(query(&["followers", &b]), format!("{a}\n"));
+        assert_eq!(query(&["stats"]), "Primary: 1\nSecondary: 1\nFollows: 1\n");
+
+        assert_eq!(Query::from_args(&["tier".to_string()]), None);
+        assert_eq!(
+            Query::from_args(&["tier".to_string(), "--db-path".to_string()]),
+            None
+        );
+        assert!(matches!(
+            run("does_not_exist.redb", &Query::Stats, &mut Vec::new()),
+            Err(Error::NotFound)
+        ));
+    }
+}
@@ -1,34 +1,85 @@
 use db::Tier;
 use nostr_sdk::prelude::hex::ToHex;
-use tonic::{transport::Server, Request, Response, Status};
+use nostr_sdk::prelude::Kind;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tonic::transport::{Identity, Server, ServerTlsConfig};
+use tonic::{Request, Response, Status};
+use tonic_health::server::HealthReporter;
+use tonic_health::ServingStatus;
 
 use nauthz_grpc::authorization_server::{Authorization, AuthorizationServer};
 use nauthz_grpc::{Decision, EventReply, EventRequest};
 
-use crate::config::{Limitation, Settings};
+use crate::admin::AdminService;
+use crate::breaker::CircuitBreaker;
+use crate::config::{FailMode, InitFailurePolicy, InitPolicy, Limitation, Settings, Stage};
 use crate::error::Error;
-use crate::nostr::Nostr;
-use crate::repo::Repo;
+use crate::metrics::{DenyReason, Metrics};
+use crate::nostr::{ContactList, Nostr};
+use crate::repo::{InFlight, Repo, TierSource};
+use crate::snapshot::GraphSnapshot;
+use crate::summary::StartupSummary;
+use crate::updates::{ContactUpdate, UpdateQueue};
 
-use crate::nostr::follows_from_event;
+use crate::nostr::{
+    follows_from_event, relay_hints_from_event, relay_list_from_event, RELAY_LIST_KIND,
+};
 
-use tracing::{debug, info};
+use tracing::{debug, error, info, instrument, warn};
 
 pub mod nauthz_grpc {
     tonic::include_proto!("nauthz");
 }
 
+pub mod admin_grpc {
+    tonic::include_proto!("admin");
+}
+
+pub mod admin;
+pub mod breaker;
 pub mod config;
 pub mod db;
 pub mod error;
+pub mod inspect;
+pub mod metrics;
 pub mod nostr;
 pub mod repo;
+pub mod self_test;
+pub mod snapshot;
+pub mod status;
+pub mod summary;
+pub mod telemetry;
+#[cfg(test)]
+mod test_utils;
+pub mod tier_cache;
+pub mod updates;
 pub mod utils;
+pub mod webhook;
+
+/// Kinds whose `p` tags describe the follow graph rather than mentions
+const GRAPH_KINDS: [u64; 1] = [3];
+
+/// Kinds that change the graph, whose signatures are verified even from trusted peers
+const GRAPH_UPDATE_KINDS: [u64; 3] = [3, 5, RELAY_LIST_KIND];
 
+/// How often old event timestamps are pruned
+const EVENT_PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+
+#[derive(Clone)]
 pub struct EventAuthz {
     pub repo: Repo,
     pub settings: Settings,
     pub nos: Nostr,
+    pub metrics: Metrics,
+    pub updates: UpdateQueue,
+    /// Set once the initial graph build has finished
+    pub ready: Arc<AtomicBool>,
+    /// Stops DB calls while the DB keeps failing
+    pub breaker: CircuitBreaker,
 }
 
 #[tonic::async_trait]
@@ -37,192 +88,1910 @@ impl Authorization for EventAuthz {
         &self,
         request: Request<EventRequest>,
     ) -> Result<Response<EventReply>, Status> {
-        let reply;
+        let trusted_peer = self.trusted_peer(request.remote_addr());
         let req = request.into_inner();
-        let event = req.clone().event.unwrap();
+        let audited = match &req.event {
+            Some(event) if self.settings.info.audit_log => Some((author(&req, event), event.kind)),
+            _ => None,
+        };
+        let verdict = self.evaluate(req, true, trusted_peer).await;
+
+        match verdict.deny_reason {
+            None => self.metrics.record_permit(),
+            Some(reason) => {
+                self.metrics.record_deny(reason);
+                if let Some((author, kind)) = audited {
+                    let message = verdict.reply.message.as_deref().unwrap_or_default();
+                    self.audit_denial(&author, kind, message);
+                }
+            }
+        }
+
+        Ok(Response::new(verdict.reply))
+    }
+}
+
+impl EventAuthz {
+    /// Decides on an event from an untrusted peer and records it if permitted
+    #[cfg(test)]
+    async fn decide(&self, req: EventRequest) -> EventReply {
+        self.evaluate(req, true, false).await.reply
+    }
+
+    /// Decides on an event without recording it or queueing its contact list
+    pub async fn dry_run(&self, req: EventRequest) -> EventReply {
+        self.evaluate(req, false, false).await.reply
+    }
+
+    /// Whether the gRPC client at `addr`, a relay rather than its users,
+    /// is trusted to have verified signatures itself
+    fn trusted_peer(&self, addr: Option<SocketAddr>) -> bool {
+        addr.is_some_and(|addr| {
+            self.settings
+                .info
+                .trusted_verification_peers
+                .contains(&addr.ip())
+        })
+    }
+
+    /// `trusted_peer` skips signature verification except for kinds that change the graph
+    #[instrument(name = "event_admit", skip_all)]
+    async fn evaluate(&self, req: EventRequest, record: bool, trusted_peer: bool) -> Verdict {
+        let event = match req.event.clone() {
+            Some(event) => event,
+            None => return deny(DenyReason::MalformedEvent, "Malformed event"),
+        };
         let content_prefix: String = event.content.chars().take(40).collect();
         info!("recvd event, [kind={}, origin={:?}, nip05_domain={:?}, tag_count={}, content_sample={:?}]",
                  event.kind, req.origin, req.nip05.as_ref().map(|x| x.domain.clone()), event.tags.len(), content_prefix);
 
-        let author = match req.auth_pubkey {
-            Some(_) => req.auth_pubkey(),
-            None => &event.pubkey,
+        let nos_event: nostr_sdk::prelude::Event = match event.clone().try_into() {
+            Ok(nos_event) => nos_event,
+            Err(err) => {
+                info!("Malformed event: {err}");
+                return deny(DenyReason::MalformedEvent, "Malformed event");
+            }
         };
 
-        let author = author.to_hex();
+        if self.settings.info.verify_signatures {
+            let trusted = trusted_peer && !GRAPH_UPDATE_KINDS.contains(&event.kind);
+            if !trusted && nos_event.verify().is_err() {
+                return deny(DenyReason::InvalidSignature, "Invalid signature");
+            }
+        }
 
-        let tier = self.repo.get_account_tier(&author).unwrap();
+        if let Some(max_bytes) = self.settings.max_content_bytes(event.kind) {
+            if event.content.len() > max_bytes {
+                return deny(DenyReason::ContentTooLarge, "Content too large");
+            }
+        }
+
+        if self
+            .settings
+            .info
+            .require_content_kinds
+            .contains(&event.kind)
+            && event.content.trim().is_empty()
+        {
+            return deny(DenyReason::EmptyContent, "Empty content");
+        }
+
+        let author = author(&req, &event);
+        if author != nos_event.pubkey.to_hex() {
+            if self.settings.info.require_auth_match {
+                return deny(
+                    DenyReason::AuthMismatch,
+                    "Author does not match authenticated pubkey",
+                );
+            }
+            warn!(
+                "Event {} signed by {} is decided on for authenticated {author}",
+                nos_event.id, nos_event.pubkey
+            );
+        }
+
+        if !self.breaker.allow() {
+            return self.degraded();
+        }
 
-        // Check that tier against limits
-        let limitation = get_limitation(&self.settings, &tier).await;
+        // Blocked authors are denied before any stage so no tier or allowlist lets them through
+        match self.repo.is_blocked(&self.settings, &author) {
+            Ok(true) => return deny(DenyReason::BlockedPubkey, "Blocked pubkey"),
+            Ok(false) => (),
+            Err(err) => return self.internal_error(err),
+        }
 
-        if limitation.can_publish {
-            match self.repo.check_rate_limits(&limitation, &author).await {
-                Ok((true, msg)) => {
-                    // Record event in db
-                    self.repo.add_event(&author).unwrap();
+        let mut limitation = None;
+        // Held until the decision is returned
+        let mut in_flight = None;
+        let mut allowlisted = false;
+        for stage in self.settings.pipeline() {
+            let reply = match stage {
+                Stage::Denylist => self.check_denylist(&event),
+                // Later stages are skipped but the event is still recorded and applied
+                Stage::Allowlist if self.settings.info.allowlist.contains(&author) => {
+                    allowlisted = true;
+                    break;
+                }
+                Stage::Allowlist => None,
+                Stage::Tier => self.check_tier(&author, &event, &mut limitation, &mut in_flight),
+                Stage::RateLimit => match self.limitation(&author, &mut limitation) {
+                    Ok(limits) => match self
+                        .repo
+                        .check_rate_limits(&limits, &author, event.content.len())
+                        .await
+                    {
+                        Ok((false, Some(exceeded))) => {
+                            Some(deny(exceeded.reason, &exceeded.message))
+                        }
+                        Ok(_) => None,
+                        Err(err) => Some(self.internal_error(err)),
+                    },
+                    Err(err) => Some(self.internal_error(err)),
+                },
+            };
+            if let Some(reply) = reply {
+                return reply;
+            }
+        }
 
-                    if event.kind.eq(&3) {
-                        let _nos = self.nos.clone();
-                        // Spawn task to update contact list
-                        let repo = self.repo.clone();
-                        // let handle: task::JoinHandle<Result<(), Error>> = task::spawn(async move {
+        let limitation = match self.limitation(&author, &mut limitation) {
+            Ok(limitation) => limitation,
+            Err(err) => return self.internal_error(err),
+        };
 
-                        let nos_event = event.try_into().unwrap();
-                        let contacts = follows_from_event(&nos_event);
+        let duplicate_content = &self.settings.duplicate_content;
+        if !allowlisted
+            && !self
+                .repo
+                .allows_content(duplicate_content, &author, &event.content)
+        {
+            return deny(DenyReason::DuplicateContent, "Duplicate content");
+        }
 
-                        debug!("New contacts: {:?}", contacts);
-                        repo.update_contacts(&nos_event.pubkey.to_hex(), contacts)
-                            .await
-                            .unwrap();
+        if !record {
+            return permit(None);
+        }
 
-                        repo.get_all_accounts().unwrap();
+        // Taken before anything is applied, allowlisted authors are charged but never denied
+        match self.repo.take_token(&limitation, &author) {
+            Ok(false) if !allowlisted => {
+                return deny(DenyReason::RateLimit, "Rate limit exhausted")
+            }
+            Ok(_) => (),
+            Err(err) => return self.internal_error(err),
+        }
 
-                        //Ok(())
-                        // });
+        if event.kind.eq(&3) {
+            let update = ContactUpdate {
+                pubkey: nos_event.pubkey.to_hex(),
+                contacts: follows_from_event(&nos_event),
+                created_at: event.created_at,
+                relay_hints: relay_hints_from_event(&nos_event),
+                event_id: nos_event.id.to_hex(),
+                deletes: HashSet::new(),
+            };
+            if !self.updates.push(update).await {
+                return deny(
+                    DenyReason::UpdateQueueFull,
+                    "Too many pending contact list updates",
+                );
+            }
+        }
 
-                        // drop(handle)
-                        // handle.await.unwrap().unwrap();
-                    }
+        // Relay lists are kept for accounts in the graph, whose contact lists are fetched
+        if event.kind == RELAY_LIST_KIND && self.settings.info.nip65_relays {
+            let pubkey = nos_event.pubkey.to_hex();
+            let stored = self
+                .repo
+                .get_account(&pubkey)
+                .and_then(|account| match account {
+                    Some(_) => self
+                        .repo
+                        .set_relay_list(&pubkey, &relay_list_from_event(&nos_event)),
+                    None => Ok(false),
+                });
+            if let Err(err) = stored {
+                return self.internal_error(err);
+            }
+        }
 
-                    reply = nauthz_grpc::EventReply {
-                        decision: Decision::Permit as i32,
-                        message: msg,
-                    };
+        // A deletion naming the author's applied contact list drops its follows,
+        // queued so it applies after any contact list ahead of it
+        if event.kind.eq(&5) {
+            let deletes: HashSet<String> = event.tag_values("e").map(String::from).collect();
+            if !deletes.is_empty() {
+                let update = ContactUpdate {
+                    pubkey: nos_event.pubkey.to_hex(),
+                    contacts: HashSet::new(),
+                    created_at: event.created_at,
+                    relay_hints: HashMap::new(),
+                    event_id: nos_event.id.to_hex(),
+                    deletes,
+                };
+                if !self.updates.push(update).await {
+                    return deny(
+                        DenyReason::UpdateQueueFull,
+                        "Too many pending contact list updates",
+                    );
                 }
-                Ok((false, msg)) => {
-                    reply = nauthz_grpc::EventReply {
-                        decision: Decision::Deny as i32,
-                        message: msg,
-                    };
+            }
+        }
+
+        // Record event in db
+        if let Err(err) = self.repo.add_event(&author) {
+            return self.internal_error(err);
+        }
+        if limitation.limits_bytes() {
+            if let Err(err) = self.repo.add_bytes(&author, event.content.len()) {
+                return self.internal_error(err);
+            }
+        }
+        if limitation.max_distinct_kinds.is_some() {
+            if let Err(err) = self.repo.add_kind(&author, event.kind) {
+                return self.internal_error(err);
+            }
+        }
+        if !allowlisted {
+            self.repo
+                .record_content(duplicate_content, &author, &event.content);
+        }
+
+        permit(None)
+    }
+
+    /// Records a denial in the audit log, failing to is only logged
+    fn audit_denial(&self, author: &str, kind: u64, message: &str) {
+        // Nothing is written while the DB is failing
+        if self.breaker.is_open() {
+            return;
+        }
+        let recorded = self
+            .repo
+            .get_account_tier(author)
+            .and_then(|tier| self.repo.add_denial(author, tier, kind, message));
+        if let Err(err) = recorded {
+            warn!("Could not record denial for {author}: {err}");
+        }
+    }
+
+    /// Denies references to blocked pubkeys or events
+    fn check_denylist(&self, event: &nauthz_grpc::Event) -> Option<Verdict> {
+        let blocklist = &self.settings.info.blocklist;
+        if self.settings.info.deny_blocked_tags {
+            if event.tag_values("p").any(|p| blocklist.contains(p)) {
+                return Some(deny(
+                    DenyReason::BlockedReference,
+                    "Mentions blocked pubkey",
+                ));
+            }
+            let blocked_events = &self.settings.info.blocked_events;
+            if event.tag_values("e").any(|e| blocked_events.contains(e)) {
+                return Some(deny(
+                    DenyReason::BlockedReference,
+                    "References blocked event",
+                ));
+            }
+        }
+        None
+    }
+
+    /// Applies the init policy and the limitation of the author's tier
+    fn check_tier(
+        &self,
+        author: &str,
+        event: &nauthz_grpc::Event,
+        limitation: &mut Option<Limitation>,
+        in_flight: &mut Option<InFlight>,
+    ) -> Option<Verdict> {
+        let is_primary = self.settings.info.primary_keys.contains(author);
+        if !self.ready.load(Ordering::SeqCst) && !is_primary {
+            match self.settings.info.init_policy {
+                InitPolicy::Deny => {
+                    return Some(deny(DenyReason::StartingUp, "Relay is starting up"))
                 }
-                Err(_) => {
-                    reply = nauthz_grpc::EventReply {
-                        decision: Decision::Deny as i32,
-                        message: Some("Error".to_string()),
-                    };
+                InitPolicy::Permit => return Some(permit(None)),
+                InitPolicy::Tier => (),
+            }
+        }
+
+        if self.settings.info.deny_unknown {
+            match self.repo.get_tier_with_source(author) {
+                Ok((_, TierSource::Default)) => {
+                    return Some(deny(DenyReason::UnknownAccount, "Unknown account"))
                 }
+                Ok(_) => (),
+                Err(err) => return Some(self.internal_error(err)),
             }
-        } else {
-            reply = nauthz_grpc::EventReply {
-                decision: Decision::Deny as i32,
-                message: Some("Not allowed to publish".to_string()),
-            };
         }
 
-        Ok(Response::new(reply))
+        let limitation = match self.limitation(author, limitation) {
+            Ok(limitation) => limitation,
+            Err(err) => return Some(self.internal_error(err)),
+        };
+
+        if !limitation.can_publish {
+            return Some(deny(
+                DenyReason::NotAllowedToPublish,
+                "Not allowed to publish",
+            ));
+        }
+
+        if let Some(max) = limitation.max_concurrent.filter(|_| !limitation.unlimited) {
+            match self.repo.begin_publish(author, max) {
+                Some(guard) => *in_flight = Some(guard),
+                None => {
+                    return Some(deny(
+                        DenyReason::TooManyConcurrentEvents,
+                        "Too many concurrent events",
+                    ))
+                }
+            }
+        }
+
+        if !limitation.allows_kind(event.kind) {
+            return Some(deny(
+                DenyReason::KindNotAllowed,
+                &format!("Kind {} not allowed", event.kind),
+            ));
+        }
+
+        if let Some(max_p_tags) = limitation.max_p_tags_per_event {
+            if !GRAPH_KINDS.contains(&event.kind) && event.tag_values("p").count() > max_p_tags {
+                return Some(deny(DenyReason::TooManyMentions, "Too many mentions"));
+            }
+        }
+
+        match self
+            .repo
+            .check_distinct_kinds(&limitation, author, event.kind)
+        {
+            Ok(true) => None,
+            Ok(false) => Some(deny(
+                DenyReason::TooManyDistinctKinds,
+                "Too many distinct kinds",
+            )),
+            Err(err) => Some(self.internal_error(err)),
+        }
+    }
+
+    /// Limitation of the author's tier, looked up once per event
+    fn limitation(
+        &self,
+        author: &str,
+        cached: &mut Option<Limitation>,
+    ) -> Result<Limitation, Error> {
+        if let Some(limitation) = cached {
+            return Ok(limitation.clone());
+        }
+        let (tier, limitation) = self.repo.resolve_policy(&self.settings, author)?;
+        self.breaker.record_success();
+        debug!("{author} is {tier:?}");
+        *cached = Some(limitation.clone());
+        Ok(limitation)
+    }
+
+    /// Reply when an error prevents a decision, following `fail_mode`
+    fn internal_error(&self, err: Error) -> Verdict {
+        error!("Could not decide on event: {err}");
+        self.breaker.record_failure();
+        match self.settings.info.fail_mode {
+            FailMode::FailOpen => permit(Some("Permitted despite internal error".to_string())),
+            FailMode::FailClosed => deny(DenyReason::InternalError, "Internal error"),
+        }
+    }
+
+    /// Reply while the circuit breaker keeps events away from the DB
+    fn degraded(&self) -> Verdict {
+        match self.settings.circuit_breaker.degraded_mode {
+            FailMode::FailOpen => permit(None),
+            FailMode::FailClosed => deny(DenyReason::DatabaseUnavailable, "Database unavailable"),
+        }
     }
 }
 
-async fn get_limitation(settings: &Settings, tier: &Tier) -> Limitation {
-    match tier {
-        Tier::Primary => settings.primary,
-        Tier::Secondary => settings.secondary,
-        Tier::Tertiary => settings.tertiary,
-        Tier::Quaternary => settings.quaternary,
-        Tier::Other => settings.other,
+/// Hex pubkey decided on, the authenticated pubkey when there is one else the event author
+fn author(req: &EventRequest, event: &nauthz_grpc::Event) -> String {
+    match req.auth_pubkey {
+        Some(_) => req.auth_pubkey(),
+        None => &event.pubkey,
+    }
+    .to_hex()
+}
+
+/// Reply to the relay along with the reason a denial is counted under
+struct Verdict {
+    reply: EventReply,
+    deny_reason: Option<DenyReason>,
+}
+
+fn permit(message: Option<String>) -> Verdict {
+    Verdict {
+        reply: EventReply {
+            decision: Decision::Permit as i32,
+            message,
+        },
+        deny_reason: None,
+    }
+}
+
+fn deny(reason: DenyReason, message: &str) -> Verdict {
+    Verdict {
+        reply: EventReply {
+            decision: Decision::Deny as i32,
+            message: Some(message.to_string()),
+        },
+        deny_reason: Some(reason),
     }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let addr = "[::1]:50051".parse().unwrap();
+    // The subscriber depends on the settings, warnings while loading them go
+    // to a plain one
+    let settings = telemetry::bootstrap(|| config::Settings::new(&arg_value("--config")));
+    let addr: SocketAddr = settings
+        .info
+        .listen_addr
+        .as_deref()
+        .unwrap_or("[::1]:50051")
+        .parse()?;
 
-    tracing_subscriber::fmt::try_init().unwrap();
+    telemetry::init(&settings)?;
 
-    let settings = config::Settings::new(&None);
+    if std::env::args().any(|a| a == "--self-test") {
+        let passed = self_test::run().await;
+        std::process::exit(if passed { 0 } else { 1 });
+    }
+
+    if let Some(i) = std::env::args().position(|a| a == "--inspect") {
+        let args: Vec<String> = std::env::args().skip(i + 1).collect();
+        let query = match inspect::Query::from_args(&args) {
+            Some(query) => query,
+            None => {
+                eprintln!("{}", inspect::USAGE);
+                std::process::exit(2);
+            }
+        };
+        let path = arg_value("--db-path").unwrap_or_else(|| "my_db.redb".to_string());
+        inspect::run(&path, &query, &mut std::io::stdout().lock())?;
+        return Ok(());
+    }
+
+    if std::env::args().any(|a| a == "--export-events") {
+        let repo = Repo::new(settings.info.primary_keys.clone());
+        let pubkey = arg_value("--pubkey");
+        let since = arg_value("--since").and_then(|s| s.parse().ok());
+        let until = arg_value("--until").and_then(|s| s.parse().ok());
+        let range = (since.is_some() || until.is_some())
+            .then(|| since.unwrap_or(0)..=until.unwrap_or(u64::MAX));
+        repo.export_events_csv(std::io::stdout().lock(), pubkey.as_deref(), range)?;
+        return Ok(());
+    }
+
+    if let Some(path) = arg_value("--export-graph") {
+        let repo = Repo::new(settings.info.primary_keys.clone());
+        repo.export_graph()?.save(&path)?;
+        return Ok(());
+    }
+
+    if let Some(path) = arg_value("--import-graph") {
+        let repo = Repo::new(settings.info.primary_keys.clone());
+        repo.import_graph(&GraphSnapshot::load(&path)?).await?;
+        return Ok(());
+    }
 
     debug!("{:?}", settings);
 
-    let nos = Nostr::new(&settings.info.relay_url, &settings.info.nostr_key).await?;
+    let nos = match Nostr::new(&settings.info).await {
+        Ok(nos) => Some(nos),
+        Err(err) if settings.info.init_failure_policy == InitFailurePolicy::Degraded => {
+            warn!("Could not connect to relays, serving existing state: {err}");
+            None
+        }
+        Err(err) => return Err(err.into()),
+    };
 
-    let repo = Repo::new(settings.info.primary_keys.clone());
+    let repo = if settings.info.ephemeral_db {
+        Repo::new_in_memory(settings.info.primary_keys.clone())
+    } else {
+        Repo::new(settings.info.primary_keys.clone())
+    };
+    let mut repo = repo
+        .with_contact_update_mode(settings.info.contact_update_mode)
+        .with_mutual_primary_follows(settings.info.mutual_primary_follows)
+        .with_max_batch_size(settings.info.max_batch_size)
+        .with_tier_cache(&settings.tier_cache);
+    if let Some(url) = settings.info.tier_webhook_url.clone() {
+        let (sender, changes) = tokio::sync::mpsc::unbounded_channel();
+        repo = repo.with_tier_changes(sender);
+        webhook::spawn(url, changes);
+    }
+
+    let ready = Arc::new(AtomicBool::new(nos.is_none()));
+    let (mut health, health_service) = tonic_health::server::health_reporter();
+    report_health(&mut health, nos.is_none()).await;
+    if let Some(nos) = nos.clone() {
+        let (settings, repo, ready) = (settings.clone(), repo.clone(), ready.clone());
+        tokio::spawn(async move {
+            match init(&settings, &repo, &nos).await {
+                Ok(rebuilt) => {
+                    if tracing::enabled!(tracing::Level::DEBUG) {
+                        match repo.get_all_accounts() {
+                            Ok(accounts) => {
+                                debug!("Registered accounts");
+                                for account in accounts {
+                                    debug!("{}, {:?}", account.pubkey, account.tier);
+                                }
+                            }
+                            Err(err) => warn!("Could not read accounts: {err}"),
+                        }
+                    }
+                    let path = settings.info.startup_summary_path.as_deref();
+                    if let Err(err) = StartupSummary::collect(&settings, &repo, rebuilt)
+                        .and_then(|summary| summary.emit(path))
+                    {
+                        warn!("Could not emit startup summary: {err}");
+                    }
+                }
+                Err(err) => match settings.info.init_failure_policy {
+                    InitFailurePolicy::Degraded => {
+                        error!("Init failed, serving existing state: {err}")
+                    }
+                    InitFailurePolicy::Exit => {
+                        error!("Init failed: {err}");
+                        std::process::exit(1);
+                    }
+                },
+            }
+            ready.store(true, Ordering::SeqCst);
+            report_health(&mut health, true).await;
+        });
+    }
+
+    spawn_event_pruning(repo.clone(), settings.event_retention());
+
+    if let Some(secs) = settings.info.demotion_sweep_secs.filter(|s| *s > 0) {
+        spawn_demotion_sweep(repo.clone(), Duration::from_secs(secs));
+    }
 
-    init(&settings, &repo, &nos).await?;
+    if let Some(secs) = settings.info.compact_interval_secs.filter(|s| *s > 0) {
+        spawn_compaction(repo.clone(), Duration::from_secs(secs));
+    }
+
+    let metrics = Metrics::new(settings.info.persist_metrics.then(|| repo.clone()))?;
+    if settings.info.persist_metrics {
+        let secs = settings
+            .info
+            .metrics_flush_secs
+            .unwrap_or(DEFAULT_METRICS_FLUSH_SECS)
+            .max(1);
+        spawn_metrics_flush(metrics.clone(), Duration::from_secs(secs));
+    }
+
+    let updates = UpdateQueue::new(&settings.update_queue, metrics.clone());
+    updates.spawn_worker(repo.clone());
+
+    if let Some(nos) = nos
+        .clone()
+        .filter(|_| settings.info.subscribe_contact_lists)
+    {
+        spawn_contact_list_subscription(&settings, repo.clone(), nos, updates.clone());
+    }
 
-    repo.get_all_accounts()?;
+    let mut server = Server::builder();
+    if let (Some(cert), Some(key)) = (&settings.info.tls_cert, &settings.info.tls_key) {
+        let identity = Identity::from_pem(std::fs::read(cert)?, std::fs::read(key)?);
+        server = server.tls_config(ServerTlsConfig::new().identity(identity))?;
+        info!("Serving over TLS with {cert}");
+    }
 
+    let drain_timeout = Duration::from_secs(settings.update_queue.shutdown_drain_timeout);
     let checker = EventAuthz {
-        repo,
+        repo: repo.clone(),
+        breaker: CircuitBreaker::new(&settings.circuit_breaker),
         settings,
-        nos,
+        nos: nos.unwrap_or_else(Nostr::offline),
+        metrics: metrics.clone(),
+        updates: updates.clone(),
+        ready,
     };
+    if let Some(status_addr) = &checker.settings.info.status_addr {
+        status::spawn(status_addr.parse()?, checker.clone())?;
+    }
+    if checker.settings.info.enable_admin_api {
+        let admin_addr: SocketAddr = checker
+            .settings
+            .info
+            .admin_addr
+            .as_deref()
+            .unwrap_or("[::1]:50052")
+            .parse()?;
+        if admin_addr == addr {
+            return Err(format!("admin_addr {admin_addr} is the public listen_addr").into());
+        }
+        let admin = AdminService {
+            checker: checker.clone(),
+        };
+        admin.spawn(server.clone(), admin_addr);
+    }
 
     info!("EventAuthz Server listening on {addr}");
     // Start serving
-    Server::builder()
+    server
+        .add_service(health_service)
         .add_service(AuthorizationServer::new(checker))
-        .serve(addr)
+        .serve_with_shutdown(addr, shutdown_signal())
         .await?;
+
+    info!("Shutting down");
+    updates.shutdown(&repo, drain_timeout).await;
+    if let Err(err) = metrics.flush() {
+        warn!("Could not persist counters: {err}");
+    }
+    telemetry::shutdown();
     Ok(())
 }
 
-async fn init(settings: &Settings, repo: &Repo, nos: &Nostr) -> Result<(), Error> {
-    repo.clear_accounts().await?;
-    let primary = settings.info.primary_keys.to_owned();
+/// Reports the server and the authorization service as serving once the
+/// initial graph build has finished, not serving until then
+async fn report_health(health: &mut HealthReporter, ready: bool) {
+    let status = if ready {
+        ServingStatus::Serving
+    } else {
+        ServingStatus::NotServing
+    };
+    health.set_service_status("", status).await;
+    health
+        .set_service_status(
+            <AuthorizationServer<EventAuthz> as tonic::server::NamedService>::NAME,
+            status,
+        )
+        .await;
+}
 
-    repo.set_tier(&primary, Tier::Primary).await?;
-    debug!("{} primary accounts set", primary.len());
+/// Resolves on ctrl-c or, on unix, SIGTERM
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            error!("Could not listen for ctrl-c: {err}");
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(err) => {
+                error!("Could not listen for SIGTERM: {err}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => info!("Received ctrl-c"),
+        _ = terminate => info!("Received SIGTERM"),
+    }
+}
 
-    let nos_clone = nos.clone();
-    let primary_contacts = nos_clone.get_contact_lists(&primary).await?;
-    // Add primary keys to DB
+/// Value following `name` on the command line
+fn arg_value(name: &str) -> Option<String> {
+    let mut args = std::env::args();
+    args.find(|a| a == name)?;
+    args.next()
+}
 
-    // Filters out events that already have a higher status
-    // 1let one: HashMap<String, HashSet<String>> = one.into_iter().filter(|(k, _)| !primary.contains(k)).collect();
-    let primary_follows = primary_contacts
+/// Primaries are pinned to `Tier::Primary` from config whether or not their
+/// contact list is found, a primary without one just has no follows
+/// Returns the primaries with no fetched contact list
+fn missing_contact_lists(
+    settings: &Settings,
+    primary: &HashSet<String>,
+    primary_contacts: &HashMap<String, ContactList>,
+) -> HashSet<String> {
+    let missing: HashSet<String> = primary
         .iter()
-        .flat_map(|(_k, f)| f.clone())
-        .filter(|k| !primary.contains(k))
+        .filter(|k| !primary_contacts.contains_key(*k))
+        .cloned()
         .collect();
 
-    info!("Prima");
-    // TODO: Spawn this so next request can start
-    repo.set_tier(&primary_follows, Tier::Secondary).await?;
-    info!("{} secondary accounts set", primary_follows.len());
+    for pubkey in &missing {
+        if settings.info.warn_missing_primary_contacts {
+            warn!("No contact list found for primary {pubkey}, it will have no follows");
+        } else {
+            debug!("No contact list found for primary {pubkey}");
+        }
+    }
+
+    missing
+}
+
+/// Periodically prunes event timestamps older than any rate limit window
+fn spawn_event_pruning(repo: Repo, retention: u64) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(EVENT_PRUNE_INTERVAL);
+        loop {
+            interval.tick().await;
+            match repo.prune_events(retention) {
+                Ok(pruned) => debug!("Pruned {pruned} event timestamps"),
+                Err(err) => warn!("Event pruning failed: {err}"),
+            }
+        }
+    })
+}
+
+/// Periodically demotes accounts whose promoters have all left
+fn spawn_demotion_sweep(repo: Repo, period: Duration) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            match repo.demote_unvouched() {
+                Ok(demoted) if !demoted.is_empty() => {
+                    info!("Demoted {} unvouched accounts", demoted.len())
+                }
+                Ok(_) => (),
+                Err(err) => warn!("Demotion sweep failed: {err}"),
+            }
+        }
+    })
+}
+
+/// Queues contact lists published by accounts within `graph_depth` hops as
+/// relays send them, resubscribing with the current accounts when relays drop
+fn spawn_contact_list_subscription(
+    settings: &Settings,
+    repo: Repo,
+    nos: Nostr,
+    updates: UpdateQueue,
+) -> tokio::task::JoinHandle<()> {
+    let depth = settings.info.graph_depth.unwrap_or(DEFAULT_GRAPH_DEPTH);
+    let delay = Duration::from_millis(settings.info.relay_retry_delay_ms);
+    tokio::spawn(async move {
+        loop {
+            let authors = match repo.pubkeys_within(depth) {
+                Ok(authors) => authors,
+                Err(err) => {
+                    warn!("Could not read accounts to subscribe to: {err}");
+                    tokio::time::sleep(delay).await;
+                    continue;
+                }
+            };
+
+            match nos.subscribe_contact_lists(&authors).await {
+                Ok(mut events) => {
+                    info!("Subscribed to contact lists of {} accounts", authors.len());
+                    while let Some(event) = events.recv().await {
+                        let pubkey = event.pubkey.to_string();
+                        if event.kind != Kind::ContactList
+                            || !authors.contains(&pubkey)
+                            || event.verify().is_err()
+                        {
+                            continue;
+                        }
+                        let update = ContactUpdate {
+                            pubkey,
+                            contacts: follows_from_event(&event),
+                            created_at: event.created_at.as_u64(),
+                            relay_hints: relay_hints_from_event(&event),
+                            event_id: event.id.to_hex(),
+                            deletes: HashSet::new(),
+                        };
+                        if !updates.push(update).await {
+                            warn!("Update queue full, dropped subscribed contact list");
+                        }
+                    }
+                    warn!("Contact list subscription closed, resubscribing in {delay:?}");
+                }
+                Err(err) => {
+                    warn!("Could not subscribe to contact lists, retrying in {delay:?}: {err}")
+                }
+            }
+            tokio::time::sleep(delay).await;
+        }
+    })
+}
 
-    for (pubkey, contacts) in primary_contacts {
-        repo.update_contacts(&pubkey, contacts).await?;
+/// Periodically compacts the DB, the first run is one period after start
+fn spawn_compaction(repo: Repo, period: Duration) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        loop {
+            interval.tick().await;
+            let repo = repo.clone();
+            match tokio::task::spawn_blocking(move || repo.compact()).await {
+                Ok(Ok(_)) => (),
+                Ok(Err(err)) => warn!("Compaction failed: {err}"),
+                Err(err) => warn!("Compaction task failed: {err}"),
+            }
+        }
+    })
+}
+
+/// Seconds between counter writes when `metrics_flush_secs` is not set
+const DEFAULT_METRICS_FLUSH_SECS: u64 = 60;
+
+/// Writes counters to the DB every `period` instead of on each decision
+fn spawn_metrics_flush(metrics: Metrics, period: Duration) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        loop {
+            interval.tick().await;
+            if let Err(err) = metrics.flush() {
+                warn!("Could not persist counters: {err}");
+            }
+        }
+    })
+}
+
+/// Contact lists fetched outward from the primaries when `graph_depth` is not set
+const DEFAULT_GRAPH_DEPTH: u8 = 2;
+
+/// Walks the follow graph out from the primaries `graph_depth` hops
+/// Each hop assigns the next tier to pubkeys not seen at a lower tier
+/// Unless `rebuild_on_start` is set or the db is empty the existing graph is kept
+/// and only the primaries' contact lists are refreshed
+/// Returns true if the graph was rebuilt
+async fn init(settings: &Settings, repo: &Repo, nos: &Nostr) -> Result<bool, Error> {
+    let rebuild = settings.info.rebuild_on_start || repo.count_by_tier()?.is_empty();
+    if rebuild {
+        build_graph(settings, repo, nos).await?;
+        return Ok(true);
     }
 
-    // Add keys from contacts lists to db as One
-    let mut secondary_contacts = nos.get_contact_lists(&primary_follows).await?;
-    secondary_contacts.retain(|k, _| !primary.contains(k) || !primary_follows.contains(k));
-    let secondary_follows = &secondary_contacts
-        .iter()
-        .flat_map(|(_k, f)| f.clone())
-        .filter(|k| !primary.contains(k) || !primary_follows.contains(k))
-        .collect();
+    let primary = settings.info.primary_keys.to_owned();
+    repo.set_tier(&primary, Tier::Primary).await?;
+    debug!("{} primary accounts set", primary.len());
+
+    let contacts = fetch_contact_lists(settings, repo, nos, &primary).await?;
+    missing_contact_lists(settings, &primary, &contacts);
+    apply_contact_lists(repo, contacts)?;
+    info!("Primary contact lists reconciled");
+    Ok(false)
+}
 
-    // TODO: Spawn this so next request can start
-    repo.set_tier(secondary_follows, Tier::Tertiary).await?;
-    info!("{} tertiary accounts set", secondary_follows.len());
-    for (pubkey, contacts) in secondary_contacts {
-        repo.update_contacts(&pubkey, contacts).await?;
+/// Contact lists of `keys`, none if the relays time out so the graph is
+/// built from what was fetched before
+async fn fetch_contact_lists(
+    settings: &Settings,
+    repo: &Repo,
+    nos: &Nostr,
+    keys: &HashSet<String>,
+) -> Result<HashMap<String, ContactList>, Error> {
+    if settings.info.nip65_relays {
+        add_write_relays(repo, nos, keys).await;
+    }
+    match nos.get_contact_lists(keys).await {
+        Err(Error::RelayTimeout(timeout)) => {
+            warn!(
+                "Contact list fetch timed out after {timeout:?}, skipped {} authors: {keys:?}",
+                keys.len()
+            );
+            Ok(HashMap::new())
+        }
+        fetched => fetched,
     }
+}
 
-    /*
+/// Applies fetched contact lists like streamed ones, a list not newer than
+/// the one applied for its author is ignored
+fn apply_contact_lists(repo: &Repo, lists: HashMap<String, ContactList>) -> Result<(), Error> {
+    for (pubkey, list) in lists {
+        if repo.update_contacts_at(&pubkey, list.contacts, list.created_at)? {
+            repo.set_contact_list_id(&pubkey, &list.event_id)?;
+        }
+    }
+    Ok(())
+}
 
-    let mut tertiary_contacts = nos.get_contact_lists(secondary_follows).await?;
-    tertiary_contacts.retain(|k, _| !primary.contains(k) || !primary_follows.contains(k) || !secondary_follows.contains(k));
-    let tertiary_follows = tertiary_contacts
-        .iter()
-        .flat_map(|(_k, f)| f.clone())
-        .filter(|k| {
-            !primary.contains(k) || !primary_follows.contains(k) || !secondary_follows.contains(k) || !tertiary_contacts.contains_key(k)
-        })
-        .collect();
+/// Stores the NIP-65 relay lists of `keys` and queries their write relays from now on
+/// Failures are only logged so contact lists are still fetched from the relays known
+async fn add_write_relays(repo: &Repo, nos: &Nostr, keys: &HashSet<String>) {
+    match nos.get_relay_lists(keys).await {
+        Ok(relay_lists) => {
+            for (pubkey, relay_list) in relay_lists {
+                if let Err(err) = repo.set_relay_list(&pubkey, &relay_list) {
+                    warn!("Could not store relay list of {pubkey}: {err}");
+                }
+            }
+        }
+        Err(err) => warn!("Could not fetch relay lists: {err}"),
+    }
 
-    // TODO: Spawn this so next request can start
-    repo.set_tier(&tertiary_follows, Tier::Quaternary).await?;
-    for (pubkey, contacts) in tertiary_contacts {
-        // repo.set_contact_list(&pubkey, &contacts).await?;
-        debug!("Updating");
-        repo.update_contacts(&pubkey, contacts).await.unwrap();
-        debug!("Updated");
+    match repo.write_relays(keys) {
+        Ok(relays) if !relays.is_empty() => {
+            debug!(
+                "Adding {} write relays of {} authors",
+                relays.len(),
+                keys.len()
+            );
+            nos.add_relays(&relays).await;
+        }
+        Ok(_) => (),
+        Err(err) => warn!("Could not read write relays: {err}"),
+    }
+}
+
+/// Clears the stored graph and walks it again from the primary keys' contact lists
+pub async fn build_graph(settings: &Settings, repo: &Repo, nos: &Nostr) -> Result<(), Error> {
+    repo.clear_accounts().await?;
+    let primary = settings.info.primary_keys.to_owned();
+
+    repo.set_tier(&primary, Tier::Primary).await?;
+    debug!("{} primary accounts set", primary.len());
+
+    let depth = settings.info.graph_depth.unwrap_or(DEFAULT_GRAPH_DEPTH);
+    let mut seen = primary.clone();
+    let mut frontier = primary.clone();
+    let mut tier = Tier::Primary;
+    for hop in 0..depth {
+        let next_tier = tier.raise_tier();
+        if frontier.is_empty() || next_tier == Tier::Other {
+            break;
+        }
+
+        let contacts = fetch_contact_lists(settings, repo, nos, &frontier).await?;
+        if hop == 0 {
+            missing_contact_lists(settings, &primary, &contacts);
+        }
+
+        let follows: HashSet<String> = contacts
+            .values()
+            .flat_map(|list| &list.contacts)
+            .filter(|k| !seen.contains(*k))
+            .cloned()
+            .collect();
+
+        // TODO: Spawn this so next request can start
+        repo.set_tier(&follows, next_tier).await?;
+        info!("{} {next_tier:?} accounts set", follows.len());
+
+        apply_contact_lists(repo, contacts)?;
+
+        seen.extend(follows.iter().cloned());
+        frontier = follows;
+        tier = next_tier;
+    }
+
+    if settings.info.mutual_primary_follows || !repo.pins()?.is_empty() {
+        // Follows are applied in fetch order so back follows may have been missed
+        // and the walk does not start from pinned accounts
+        repo.recompute_tiers()?;
     }
-    */
+    repo.set_graph_built()?;
 
     info!("Accounts set");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use nostr_sdk::prelude::{EventBuilder, Keys, Kind, Tag, XOnlyPublicKey};
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::config::{CircuitBreakerSettings, Info};
+    use crate::nostr::MemoryRelay;
+    use crate::test_utils::{admit, event};
+
+    const PUBKEY: &str = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9";
+
+    fn authz(settings: Settings) -> EventAuthz {
+        EventAuthz {
+            repo: Repo::new_in_memory(settings.info.primary_keys.clone()),
+            breaker: CircuitBreaker::new(&settings.circuit_breaker),
+            settings,
+            nos: Nostr::offline(),
+            metrics: Metrics::default(),
+            updates: UpdateQueue::new(&Default::default(), Metrics::default()),
+            ready: Arc::new(AtomicBool::new(true)),
+        }
+    }
+
+    fn open_settings() -> Settings {
+        Settings {
+            other: Limitation {
+                can_publish: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_audit_log() {
+        let mut settings = open_settings();
+        settings.other.can_publish = false;
+        settings.info.audit_log = true;
+        let checker = authz(settings);
+
+        assert_eq!(
+            Decision::Deny as i32,
+            admit(&checker, event(PUBKEY, 7, "+", &[])).await
+        );
+        let denials = checker.repo.recent_denials(PUBKEY, 10).unwrap();
+        assert_eq!(1, denials.len());
+        assert_eq!(Tier::Other, denials[0].tier);
+        assert_eq!(7, denials[0].kind);
+        assert_eq!("Not allowed to publish", denials[0].message);
+
+        // Dry runs are not audited
+        checker
+            .dry_run(request(event(PUBKEY, 1, "hey", &[]), None))
+            .await;
+        assert_eq!(1, checker.repo.recent_denials(PUBKEY, 10).unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn test_deny_unknown() {
+        let unknown = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203";
+        let mut settings = open_settings();
+        settings.info.deny_unknown = true;
+        settings.other.events_per_hour = Some(10);
+        let checker = authz(settings);
+
+        let reply = checker
+            .decide(request(event(unknown, 1, "hey", &[]), None))
+            .await;
+        assert_eq!(Some("Unknown account".to_string()), reply.message);
+        // Nothing is counted against an unknown account
+        let (remaining_hour, _, _) = checker
+            .repo
+            .remaining_quota(unknown, &checker.settings.other)
+            .unwrap();
+        assert_eq!(Some(10), remaining_hour);
+
+        // Known accounts in the other tier still get its limitation
+        checker
+            .repo
+            .set_tier(&HashSet::from([unknown.to_string()]), Tier::Other)
+            .await
+            .unwrap();
+        assert_eq!(
+            Decision::Permit as i32,
+            admit(&checker, event(unknown, 1, "hey", &[])).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_malformed_event() {
+        let checker = authz(open_settings());
+
+        let mut short_pubkey = event(PUBKEY, 1, "hey", &[]);
+        short_pubkey.pubkey.truncate(31);
+        let reply = checker.decide(request(short_pubkey, None)).await;
+        assert_eq!(Decision::Deny as i32, reply.decision);
+        assert_eq!(Some("Malformed event".to_string()), reply.message);
+
+        let mut req = request(event(PUBKEY, 1, "hey", &[]), None);
+        req.event = None;
+        let reply = checker.decide(req).await;
+        assert_eq!(Some("Malformed event".to_string()), reply.message);
+
+        // Still serving afterwards
+        assert_eq!(
+            Decision::Permit as i32,
+            admit(&checker, event(PUBKEY, 1, "hey", &[])).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_nip65_relay_list() {
+        let mut settings = open_settings();
+        settings.info.nip65_relays = true;
+        settings.secondary.can_publish = true;
+        let checker = authz(settings);
+        let relay_list = |url: &str, created_at: u64| {
+            let mut event = event(PUBKEY, RELAY_LIST_KIND, "", &[]);
+            event.tags = vec![nauthz_grpc::event::TagEntry {
+                values: vec!["r".to_string(), url.to_string()],
+            }];
+            event.created_at = created_at;
+            event
+        };
+        let keys = HashSet::from([PUBKEY.to_string()]);
+        let now = utils::unix_time();
+
+        // Only kept for accounts in the graph
+        admit(&checker, relay_list("wss://one.example.com", now)).await;
+        assert!(checker.repo.write_relays(&keys).unwrap().is_empty());
+
+        checker.repo.set_tier(&keys, Tier::Secondary).await.unwrap();
+        assert_eq!(
+            Decision::Permit as i32,
+            admit(&checker, relay_list("wss://one.example.com", now)).await
+        );
+        // An older list does not replace it
+        admit(&checker, relay_list("wss://old.example.com", now - 60)).await;
+        assert_eq!(
+            HashSet::from(["wss://one.example.com".to_string()]),
+            checker.repo.write_relays(&keys).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_require_auth_match() {
+        let signer = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203";
+        let auth_request = |auth: &str| {
+            let mut req = request(event(signer, 1, "hey", &[]), None);
+            req.auth_pubkey = Some(XOnlyPublicKey::from_str(auth).unwrap().serialize().to_vec());
+            req
+        };
+
+        // Decided on for the authenticated pubkey unless required to match
+        let checker = authz(open_settings());
+        let reply = checker.decide(auth_request(PUBKEY)).await;
+        assert_eq!(Decision::Permit as i32, reply.decision);
+
+        let mut settings = open_settings();
+        settings.info.require_auth_match = true;
+        let checker = authz(settings);
+        let reply = checker.decide(auth_request(PUBKEY)).await;
+        assert_eq!(Decision::Deny as i32, reply.decision);
+        assert_eq!(
+            Some("Author does not match authenticated pubkey".to_string()),
+            reply.message
+        );
+        let reply = checker.decide(auth_request(signer)).await;
+        assert_eq!(Decision::Permit as i32, reply.decision);
+    }
+
+    #[tokio::test]
+    async fn test_deny_unknown_stranger_follows() {
+        let stranger = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203";
+        let follow = "7c27a04b7c27299f16dc07d3eb8f28544f188bc7a34982328b7d581edc405dc2";
+        let mut settings = open_settings();
+        settings.info.deny_unknown = true;
+        let checker = authz(settings);
+
+        // A contact list of someone outside the graph, as from a relay subscription
+        checker
+            .repo
+            .update_contacts(stranger, HashSet::from([follow.to_string()]))
+            .await
+            .unwrap();
+
+        for pubkey in [stranger, follow] {
+            let reply = checker
+                .decide(request(event(pubkey, 1, "hey", &[]), None))
+                .await;
+            assert_eq!(Some("Unknown account".to_string()), reply.message);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_max_content_bytes_per_kind() {
+        let mut settings = open_settings();
+        settings.info.max_content_bytes = Some(1000);
+        settings.max_content_bytes_per_kind = HashMap::from([(1, 100), (30023, 100_000)]);
+        let checker = authz(settings);
+
+        let content = "a".repeat(500);
+        assert_eq!(
+            Decision::Deny as i32,
+            admit(&checker, event(PUBKEY, 1, &content, &[])).await
+        );
+        assert_eq!(
+            Decision::Permit as i32,
+            admit(&checker, event(PUBKEY, 30023, &content, &[])).await
+        );
+
+        // Kinds without their own cap use the global cap
+        let content = "a".repeat(2000);
+        assert_eq!(
+            Decision::Deny as i32,
+            admit(&checker, event(PUBKEY, 7, &content, &[])).await
+        );
+    }
+
+    fn request(event: nauthz_grpc::Event, origin: Option<&str>) -> EventRequest {
+        EventRequest {
+            event: Some(event),
+            ip_addr: None,
+            origin: origin.map(String::from),
+            user_agent: None,
+            auth_pubkey: None,
+            nip05: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_trusted_verification_peers() {
+        let mut settings = open_settings();
+        settings.info.verify_signatures = true;
+        settings.info.trusted_verification_peers = HashSet::from(["10.0.0.1".parse().unwrap()]);
+        let checker = authz(settings);
+
+        // The client's origin header is never trusted
+        assert!(!checker.trusted_peer(None));
+        assert!(!checker.trusted_peer(Some("10.0.0.2:4000".parse().unwrap())));
+        assert!(checker.trusted_peer(Some("10.0.0.1:4000".parse().unwrap())));
+
+        // Zero signature
+        let unsigned = event(PUBKEY, 1, "hey", &[]);
+        let reply = checker
+            .evaluate(request(unsigned.clone(), None), true, false)
+            .await
+            .reply;
+        assert_eq!(Decision::Deny as i32, reply.decision);
+        assert_eq!(Some("Invalid signature".to_string()), reply.message);
+
+        let reply = checker
+            .evaluate(request(unsigned, None), true, true)
+            .await
+            .reply;
+        assert_eq!(Decision::Permit as i32, reply.decision);
+
+        // Graph updates are verified even from a trusted peer
+        let follow = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203";
+        for kind in GRAPH_UPDATE_KINDS {
+            let reply = checker
+                .evaluate(
+                    request(event(PUBKEY, kind, "", &[follow]), None),
+                    true,
+                    true,
+                )
+                .await
+                .reply;
+            assert_eq!(Some("Invalid signature".to_string()), reply.message);
+        }
+        assert!(checker.updates.is_empty());
+
+        let signed = EventBuilder::new_text_note("hey", &[])
+            .to_event(&Keys::generate())
+            .unwrap();
+        let reply = checker.decide(request((&signed).into(), None)).await;
+        assert_eq!(Decision::Permit as i32, reply.decision);
+    }
+
+    #[tokio::test]
+    async fn test_db_circuit_breaker() {
+        let mut settings = open_settings();
+        settings.circuit_breaker = CircuitBreakerSettings {
+            failure_threshold: Some(2),
+            probe_interval_ms: 50,
+            degraded_mode: FailMode::FailClosed,
+        };
+        let mut checker = authz(settings);
+        checker.repo.poison();
+
+        for _ in 0..2 {
+            let reply = checker
+                .decide(request(event(PUBKEY, 1, "hey", &[]), None))
+                .await;
+            assert_eq!(Some("Internal error".to_string()), reply.message);
+        }
+        assert!(checker.breaker.is_open());
+        let reply = checker
+            .decide(request(event(PUBKEY, 1, "hey", &[]), None))
+            .await;
+        assert_eq!(Some("Database unavailable".to_string()), reply.message);
+
+        // The DB comes back and the next probe closes the breaker
+        checker.repo = Repo::new_in_memory(HashSet::new());
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        let reply = checker
+            .decide(request(event(PUBKEY, 1, "hey", &[]), None))
+            .await;
+        assert_eq!(Decision::Permit as i32, reply.decision);
+        assert!(!checker.breaker.is_open());
+    }
+
+    #[tokio::test]
+    async fn test_forged_contact_list() {
+        let follow = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203";
+        let mut settings = open_settings();
+        settings.info.verify_signatures = true;
+        let checker = authz(settings);
+
+        let reply = checker
+            .decide(request(event(PUBKEY, 3, "", &[follow]), None))
+            .await;
+        assert_eq!(Some("Invalid signature".to_string()), reply.message);
+        assert!(checker.updates.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_order() {
+        let mut settings = open_settings();
+        settings.info.allowlist = HashSet::from([PUBKEY.to_string()]);
+        settings.other.events_per_hour = Some(0);
+
+        // Allowlist before rate limits bypasses them
+        let checker = authz(settings.clone());
+        checker.repo.add_event(PUBKEY).unwrap();
+        assert_eq!(
+            Decision::Permit as i32,
+            admit(&checker, event(PUBKEY, 1, "hey", &[])).await
+        );
+
+        // Rate limits before allowlist apply to allowlisted accounts
+        settings.info.pipeline = Some(vec![
+            Stage::Denylist,
+            Stage::Tier,
+            Stage::RateLimit,
+            Stage::Allowlist,
+        ]);
+        let checker = authz(settings);
+        checker.repo.add_event(PUBKEY).unwrap();
+        assert_eq!(
+            Decision::Deny as i32,
+            admit(&checker, event(PUBKEY, 1, "hey", &[])).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_require_content_kinds() {
+        let mut settings = open_settings();
+        settings.info.require_content_kinds = HashSet::from([1]);
+        let checker = authz(settings);
+
+        assert_eq!(
+            Decision::Deny as i32,
+            admit(&checker, event(PUBKEY, 1, "", &[])).await
+        );
+        assert_eq!(
+            Decision::Permit as i32,
+            admit(&checker, event(PUBKEY, 7, "", &[])).await
+        );
+        assert_eq!(
+            Decision::Permit as i32,
+            admit(&checker, event(PUBKEY, 1, "hey", &[])).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_deny_blocked_tags() {
+        let blocked = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203";
+        let mut settings = open_settings();
+        settings.info.blocklist = HashSet::from([blocked.to_string()]);
+        let checker = authz(settings.clone());
+        assert_eq!(
+            Decision::Permit as i32,
+            admit(&checker, event(PUBKEY, 1, "hey", &[blocked])).await
+        );
+
+        settings.info.deny_blocked_tags = true;
+        let checker = authz(settings);
+        assert_eq!(
+            Decision::Deny as i32,
+            admit(&checker, event(PUBKEY, 1, "hey", &[blocked])).await
+        );
+        assert_eq!(
+            Decision::Permit as i32,
+            admit(&checker, event(PUBKEY, 1, "hey", &[])).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_blocked_primary() {
+        let allowed = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203";
+        let mut settings = open_settings();
+        settings.info.primary_keys = HashSet::from([PUBKEY.to_string(), allowed.to_string()]);
+        settings.info.allowlist = HashSet::from([allowed.to_string()]);
+        settings.info.blocklist = HashSet::from([PUBKEY.to_string()]);
+        // Blocking still applies when the allowlist runs first
+        settings.info.pipeline = Some(vec![Stage::Allowlist, Stage::Denylist, Stage::Tier]);
+        let checker = authz(settings);
+        assert_eq!(
+            Decision::Deny as i32,
+            admit(&checker, event(PUBKEY, 1, "hey", &[])).await
+        );
+
+        // Blocked in the db, checked against the authenticated pubkey
+        checker.repo.block(allowed).unwrap();
+        let other = "7c27a04b7c27299f16dc07d3eb8f28544f188bc7a34982328b7d581edc405dc2";
+        let mut req = request(event(other, 1, "hey", &[]), None);
+        req.auth_pubkey = Some(
+            XOnlyPublicKey::from_str(allowed)
+                .unwrap()
+                .serialize()
+                .to_vec(),
+        );
+        let reply = checker.decide(req).await;
+        assert_eq!(Decision::Deny as i32, reply.decision);
+        assert_eq!(Some("Blocked pubkey".to_string()), reply.message);
+
+        checker.repo.unblock(allowed).unwrap();
+        assert_eq!(
+            Decision::Permit as i32,
+            admit(&checker, event(allowed, 1, "hey", &[])).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_other_read_only() {
+        let mut settings = open_settings();
+        settings.info.other_read_only = true;
+        let checker = authz(settings);
+
+        assert_eq!(
+            Decision::Deny as i32,
+            admit(&checker, event(PUBKEY, 1, "hey", &[])).await
+        );
+    }
+
+    #[test]
+    fn test_missing_contact_lists() {
+        let absent = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203";
+        let primary = HashSet::from([PUBKEY.to_string(), absent.to_string()]);
+        let primary_contacts = HashMap::from([(
+            PUBKEY.to_string(),
+            ContactList {
+                contacts: HashSet::new(),
+                created_at: 1,
+                event_id: String::new(),
+            },
+        )]);
+
+        let mut settings = open_settings();
+        settings.info.warn_missing_primary_contacts = true;
+
+        assert_eq!(
+            HashSet::from([absent.to_string()]),
+            missing_contact_lists(&settings, &primary, &primary_contacts)
+        );
+    }
+
+    #[test]
+    fn test_apply_stale_contact_list() {
+        let follow = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203";
+        let stale_follow = "7c27a04b7c27299f16dc07d3eb8f28544f188bc7a34982328b7d581edc405dc2";
+        let checker = authz(open_settings());
+        let list = |follow: &str, created_at, event_id: &str| {
+            HashMap::from([(
+                PUBKEY.to_string(),
+                ContactList {
+                    contacts: HashSet::from([follow.to_string()]),
+                    created_at,
+                    event_id: event_id.to_string(),
+                },
+            )])
+        };
+
+        apply_contact_lists(&checker.repo, list(follow, 200, "newer")).unwrap();
+        // A relay returning an older list does not roll the follows back
+        apply_contact_lists(&checker.repo, list(stale_follow, 100, "stale")).unwrap();
+
+        assert_eq!(
+            vec![(PUBKEY.to_string(), follow.to_string())],
+            checker.repo.all_follow_edges().unwrap()
+        );
+        // The id of the applied list is recorded so a deletion of it applies
+        assert!(checker
+            .repo
+            .delete_contact_list(PUBKEY, &HashSet::from(["newer".to_string()]))
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_admit_during_init() {
+        let unknown = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203";
+        let mut settings = open_settings();
+        settings.info.primary_keys = HashSet::from([PUBKEY.to_string()]);
+        settings.primary.can_publish = true;
+        let checker = authz(settings);
+        checker.ready.store(false, Ordering::SeqCst);
+
+        assert_eq!(
+            Decision::Permit as i32,
+            admit(&checker, event(PUBKEY, 1, "hey", &[])).await
+        );
+        assert_eq!(
+            Decision::Deny as i32,
+            admit(&checker, event(unknown, 1, "hey", &[])).await
+        );
+
+        checker.ready.store(true, Ordering::SeqCst);
+        assert_eq!(
+            Decision::Permit as i32,
+            admit(&checker, event(unknown, 1, "hey", &[])).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_poisoned_db() {
+        let checker = authz(open_settings());
+        checker.repo.poison();
+
+        assert_eq!(
+            Decision::Deny as i32,
+            admit(&checker, event(PUBKEY, 1, "hey", &[])).await
+        );
+        assert_eq!(1, checker.metrics.denials(DenyReason::InternalError));
+
+        let mut settings = open_settings();
+        settings.info.fail_mode = FailMode::FailOpen;
+        let checker = authz(settings);
+        checker.repo.poison();
+        let reply = checker
+            .decide(request(event(PUBKEY, 1, "hey", &[]), None))
+            .await;
+        assert_eq!(Decision::Permit as i32, reply.decision);
+        assert_eq!(
+            Some("Permitted despite internal error".to_string()),
+            reply.message
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unseeded_primary() {
+        let settings = Settings {
+            info: Info {
+                primary_keys: HashSet::from([PUBKEY.to_string()]),
+                ..Default::default()
+            },
+            primary: Limitation {
+                can_publish: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        // Ready but the primary was never written to the db
+        let checker = authz(settings);
+
+        assert_eq!(
+            Decision::Permit as i32,
+            admit(&checker, event(PUBKEY, 1, "hey", &[])).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unlimited_primary() {
+        let mut settings = Settings {
+            info: Info {
+                primary_keys: HashSet::from([PUBKEY.to_string()]),
+                ..Default::default()
+            },
+            primary: Limitation {
+                can_publish: true,
+                unlimited: true,
+                events_per_hour: Some(0),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let checker = authz(settings.clone());
+        for content in ["gm", "gn"] {
+            assert_eq!(
+                Decision::Permit as i32,
+                admit(&checker, event(PUBKEY, 1, content, &[])).await
+            );
+        }
+
+        // Unlimited does not override can_publish
+        settings.primary.can_publish = false;
+        let checker = authz(settings);
+        assert_eq!(
+            Decision::Deny as i32,
+            admit(&checker, event(PUBKEY, 1, "gm", &[])).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_allowed_and_denied_kinds() {
+        let mut settings = open_settings();
+        settings.other.allowed_kinds = Some(vec![1, 7]);
+        let checker = authz(settings.clone());
+        assert_eq!(
+            Decision::Permit as i32,
+            admit(&checker, event(PUBKEY, 7, "+", &[])).await
+        );
+        let reply = checker
+            .decide(request(event(PUBKEY, 30023, "long form", &[]), None))
+            .await;
+        assert_eq!(Some("Kind 30023 not allowed".to_string()), reply.message);
+
+        settings.other.allowed_kinds = None;
+        settings.other.denied_kinds = Some(vec![30023]);
+        let checker = authz(settings);
+        assert_eq!(
+            Decision::Permit as i32,
+            admit(&checker, event(PUBKEY, 1, "hey", &[])).await
+        );
+        assert_eq!(
+            Decision::Deny as i32,
+            admit(&checker, event(PUBKEY, 30023, "long form", &[])).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_max_p_tags_per_event() {
+        let mentions = [
+            "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203",
+            "7c27a04b7c27299f16dc07d3eb8f28544f188bc7a34982328b7d581edc405dc2",
+            "04918dfc36c93e7db6cc0d60f37e1522f1c36b64d3f4b424c532d7c595febbc5",
+        ];
+        let mut settings = open_settings();
+        settings.other.max_p_tags_per_event = Some(2);
+        let checker = authz(settings);
+
+        let reply = checker
+            .decide(request(event(PUBKEY, 1, "hey", &mentions), None))
+            .await;
+        assert_eq!(Some("Too many mentions".to_string()), reply.message);
+        assert_eq!(
+            Decision::Permit as i32,
+            admit(&checker, event(PUBKEY, 3, "", &mentions)).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_max_concurrent() {
+        let mut settings = open_settings();
+        settings.other.max_concurrent = Some(1);
+        let checker = authz(settings);
+
+        // An event of the author still being decided on
+        let in_flight = checker.repo.begin_publish(PUBKEY, 1).unwrap();
+        assert_eq!(
+            Decision::Deny as i32,
+            admit(&checker, event(PUBKEY, 1, "first", &[])).await
+        );
+
+        // Slots are given back once decided, whether permitted or denied
+        drop(in_flight);
+        assert_eq!(
+            Decision::Permit as i32,
+            admit(&checker, event(PUBKEY, 1, "second", &[])).await
+        );
+        assert_eq!(
+            Decision::Permit as i32,
+            admit(&checker, event(PUBKEY, 1, "third", &[])).await
+        );
+    }
+
+    fn contact_list(author: &Keys, follows: &[&Keys]) -> nostr_sdk::prelude::Event {
+        let tags: Vec<Tag> = follows
+            .iter()
+            .map(|f| Tag::PubKey(f.public_key(), None))
+            .collect();
+        EventBuilder::new(Kind::ContactList, "", &tags)
+            .to_event(author)
+            .unwrap()
+    }
+
+    /// Tiers of `keys` after init to `depth` with the first key as primary
+    async fn graph_tiers(nos: &Nostr, keys: &[Keys], depth: u8) -> Vec<Tier> {
+        let mut settings = open_settings();
+        settings.info.primary_keys = HashSet::from([keys[0].public_key().to_string()]);
+        settings.info.graph_depth = Some(depth);
+        let repo = Repo::new_in_memory(settings.info.primary_keys.clone());
+        init(&settings, &repo, nos).await.unwrap();
+        keys.iter()
+            .map(|k| repo.get_account_tier(&k.public_key().to_string()).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_allowlisted_contact_list() {
+        let keys: Vec<Keys> = (0..2).map(|_| Keys::generate()).collect();
+        let (p, a) = (&keys[0], &keys[1]);
+        let primary = p.public_key().to_string();
+        let mut settings = open_settings();
+        // Only the allowlist lets the primary publish
+        settings.primary.can_publish = false;
+        settings.info.primary_keys = HashSet::from([primary.clone()]);
+        settings.info.allowlist = HashSet::from([primary.clone()]);
+        let checker = authz(settings);
+        checker
+            .repo
+            .set_tier(&HashSet::from([primary.clone()]), Tier::Primary)
+            .await
+            .unwrap();
+
+        let list = contact_list(p, &[a]);
+        assert_eq!(
+            Decision::Permit as i32,
+            admit(&checker, (&list).into()).await
+        );
+        checker.updates.drain(&checker.repo).await;
+        assert_eq!(
+            Tier::Secondary,
+            checker
+                .repo
+                .get_account_tier(&a.public_key().to_string())
+                .unwrap()
+        );
+        assert_eq!((0, 1), checker.repo.follow_counts(&primary).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_contact_list_deletion() {
+        let keys: Vec<Keys> = (0..2).map(|_| Keys::generate()).collect();
+        let (p, a) = (&keys[0], &keys[1]);
+        let primary = p.public_key().to_string();
+        let mut settings = open_settings();
+        settings.primary.can_publish = true;
+        settings.info.primary_keys = HashSet::from([primary.clone()]);
+        let checker = authz(settings);
+        checker
+            .repo
+            .set_tier(&HashSet::from([primary.clone()]), Tier::Primary)
+            .await
+            .unwrap();
+        let tier = || checker.repo.get_account_tier(&a.public_key().to_string());
+
+        let list = contact_list(p, &[a]);
+        assert_eq!(
+            Decision::Permit as i32,
+            admit(&checker, (&list).into()).await
+        );
+        checker.updates.drain(&checker.repo).await;
+        assert_eq!(Tier::Secondary, tier().unwrap());
+
+        // Deleting some other event keeps the list
+        let note = EventBuilder::new_text_note("hey", &[]).to_event(p).unwrap();
+        let deletion = EventBuilder::delete::<String>(vec![note.id], None)
+            .to_event(p)
+            .unwrap();
+        assert_eq!(
+            Decision::Permit as i32,
+            admit(&checker, (&deletion).into()).await
+        );
+        checker.updates.drain(&checker.repo).await;
+        assert_eq!(Tier::Secondary, tier().unwrap());
+
+        let deletion = EventBuilder::delete::<String>(vec![list.id], None)
+            .to_event(p)
+            .unwrap();
+        assert_eq!(
+            Decision::Permit as i32,
+            admit(&checker, (&deletion).into()).await
+        );
+        checker.updates.drain(&checker.repo).await;
+        assert_eq!(Tier::Other, tier().unwrap());
+        assert_eq!((0, 0), checker.repo.follow_counts(&primary).unwrap());
+
+        // The deleted list is not applied again when rebroadcast
+        assert_eq!(
+            Decision::Permit as i32,
+            admit(&checker, (&list).into()).await
+        );
+        checker.updates.drain(&checker.repo).await;
+        assert_eq!(Tier::Other, tier().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_graph_depth() {
+        // P -> A -> B -> C, B -> P must not demote the primary
+        let keys: Vec<Keys> = (0..4).map(|_| Keys::generate()).collect();
+        let (p, a, b, c) = (&keys[0], &keys[1], &keys[2], &keys[3]);
+        let relay = Arc::new(MemoryRelay::default());
+        relay.publish(contact_list(p, &[a]));
+        relay.publish(contact_list(a, &[b]));
+        relay.publish(contact_list(b, &[c, p]));
+        let nos = Nostr::with_client(relay);
+
+        assert_eq!(
+            vec![Tier::Primary, Tier::Secondary, Tier::Other, Tier::Other],
+            graph_tiers(&nos, &keys, 1).await
+        );
+        assert_eq!(
+            vec![
+                Tier::Primary,
+                Tier::Secondary,
+                Tier::Tertiary,
+                Tier::Quaternary
+            ],
+            graph_tiers(&nos, &keys, 3).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_contact_list_subscription() {
+        let keys: Vec<Keys> = (0..3).map(|_| Keys::generate()).collect();
+        let (p, a, b) = (&keys[0], &keys[1], &keys[2]);
+        let relay = Arc::new(MemoryRelay::default());
+        relay.publish(contact_list(p, &[a]));
+        let nos = Nostr::with_client(relay.clone());
+
+        let mut settings = open_settings();
+        settings.info.primary_keys = HashSet::from([p.public_key().to_string()]);
+        settings.info.relay_retry_delay_ms = 1;
+        let repo = Repo::new_in_memory(settings.info.primary_keys.clone());
+        init(&settings, &repo, &nos).await.unwrap();
+
+        let updates = UpdateQueue::new(&Default::default(), Metrics::default());
+        updates.spawn_worker(repo.clone());
+        let subscription =
+            spawn_contact_list_subscription(&settings, repo.clone(), nos, updates.clone());
+        relay.disconnect();
+
+        // A secondary follows B on another relay after startup, published
+        // until the subscription is open again after the relay dropped it
+        let followed = async {
+            let pubkey = b.public_key().to_string();
+            while repo.get_account_tier(&pubkey).unwrap() != Tier::Tertiary {
+                relay.publish(contact_list(a, &[b]));
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), followed)
+            .await
+            .unwrap();
+        subscription.abort();
+        updates.shutdown(&repo, Duration::from_secs(1)).await;
+    }
+
+    #[tokio::test]
+    async fn test_rebuild_on_start() {
+        let keys: Vec<Keys> = (0..3).map(|_| Keys::generate()).collect();
+        let (p, a, kept) = (&keys[0], &keys[1], &keys[2]);
+        let relay = Arc::new(MemoryRelay::default());
+        relay.publish(contact_list(p, &[a]));
+        let nos = Nostr::with_client(relay);
+
+        let mut settings = open_settings();
+        settings.info.primary_keys = HashSet::from([p.public_key().to_string()]);
+        let repo = Repo::new_in_memory(settings.info.primary_keys.clone());
+        let kept = kept.public_key().to_string();
+
+        // Empty db is always rebuilt
+        assert!(init(&settings, &repo, &nos).await.unwrap());
+        repo.set_tier(&HashSet::from([kept.clone()]), Tier::Tertiary)
+            .await
+            .unwrap();
+
+        assert!(!init(&settings, &repo, &nos).await.unwrap());
+        assert_eq!(Tier::Tertiary, repo.get_account_tier(&kept).unwrap());
+        assert_eq!(
+            Tier::Secondary,
+            repo.get_account_tier(&a.public_key().to_string()).unwrap()
+        );
+
+        settings.info.rebuild_on_start = true;
+        assert!(init(&settings, &repo, &nos).await.unwrap());
+        assert_eq!(Tier::Other, repo.get_account_tier(&kept).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_startup_summary() {
+        let primary = Keys::generate();
+        let follow = Keys::generate();
+        let relay = MemoryRelay::default();
+        relay.publish(
+            EventBuilder::new(
+                Kind::ContactList,
+                "",
+                &[Tag::PubKey(follow.public_key(), None)],
+            )
+            .to_event(&primary)
+            .unwrap(),
+        );
+        let nos = Nostr::with_client(Arc::new(relay));
+
+        let mut settings = open_settings();
+        settings.info.relay_url = vec!["ws://localhost:8080/".to_string()];
+        settings.info.primary_keys = HashSet::from([primary.public_key().to_string()]);
+        let repo = Repo::new_in_memory(settings.info.primary_keys.clone());
+        let rebuilt = init(&settings, &repo, &nos).await.unwrap();
+
+        let summary = StartupSummary::collect(&settings, &repo, rebuilt).unwrap();
+        assert_eq!(1, summary.primary_count);
+        assert_eq!(Some(&1), summary.accounts_per_tier.get(&Tier::Primary));
+        assert_eq!(Some(&1), summary.accounts_per_tier.get(&Tier::Secondary));
+        assert_eq!(1, summary.total_follows);
+        assert_eq!(vec!["ws://localhost:8080/".to_string()], summary.relays);
+        assert!(summary.rebuilt);
+
+        let json = serde_json::to_value(&summary).unwrap();
+        for field in [
+            "primary_count",
+            "accounts_per_tier",
+            "total_follows",
+            "relays",
+            "config_source",
+            "rebuilt",
+        ] {
+            assert!(json.get(field).is_some(), "missing {field}");
+        }
+    }
+}
@@ -0,0 +1,234 @@
+//! Counters of admission decisions
+use crate::error::Error;
+use crate::repo::Repo;
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+pub const PERMIT: &str = "permit";
+pub const QUEUE_DROPPED: &str = "update_queue_dropped";
+pub const QUEUE_REJECTED: &str = "update_queue_rejected";
+const DENY_PREFIX: &str = "deny:";
+
+#[derive(Clone, Default)]
+pub struct Metrics {
+    counters: Arc<Mutex<HashMap<String, u64>>>,
+    /// Increments since the last flush, written to the repo by `flush`
+    pending: Arc<Mutex<HashMap<String, u64>>>,
+    /// When set counters are persisted so they stay monotonic across restarts
+    repo: Option<Repo>,
+}
+
+impl Metrics {
+    /// Creates metrics, restoring persisted counters if a repo is given
+    pub fn new(repo: Option<Repo>) -> Result<Self, Error> {
+        let counters = match &repo {
+            Some(repo) => repo.get_counters()?,
+            None => HashMap::new(),
+        };
+
+        Ok(Self {
+            counters: Arc::new(Mutex::new(counters)),
+            pending: Arc::default(),
+            repo,
+        })
+    }
+
+    pub fn record_permit(&self) {
+        self.increment(PERMIT);
+    }
+
+    pub fn record_deny(&self, reason: DenyReason) {
+        self.increment(&format!("{DENY_PREFIX}{}", reason.as_str()));
+    }
+
+    pub fn record(&self, name: &str) {
+        self.increment(name);
+    }
+
+    pub fn get(&self, name: &str) -> u64 {
+        self.counters
+            .lock()
+            .unwrap()
+            .get(name)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    pub fn denials(&self, reason: DenyReason) -> u64 {
+        self.get(&format!("{DENY_PREFIX}{}", reason.as_str()))
+    }
+
+    pub fn counters(&self) -> HashMap<String, u64> {
+        self.counters.lock().unwrap().clone()
+    }
+
+    /// Counters in the Prometheus text exposition format, denials as one
+    /// counter labelled by reason
+    pub fn prometheus(&self) -> String {
+        let counters: BTreeMap<String, u64> = self.counters().into_iter().collect();
+        let mut out = String::new();
+        let mut denials = counters
+            .iter()
+            .filter_map(|(name, count)| Some((name.strip_prefix(DENY_PREFIX)?, count)))
+            .peekable();
+        if denials.peek().is_some() {
+            out.push_str("# TYPE nauthz_denials_total counter\n");
+            for (reason, count) in denials {
+                out.push_str(&format!(
+                    "nauthz_denials_total{{reason=\"{reason}\"}} {count}\n"
+                ));
+            }
+        }
+        for (name, count) in counters.iter().filter(|(n, _)| !n.starts_with(DENY_PREFIX)) {
+            out.push_str(&format!("# TYPE nauthz_{name}_total counter\n"));
+            out.push_str(&format!("nauthz_{name}_total {count}\n"));
+        }
+        out
+    }
+
+    /// Writes the increments since the last flush to the repo
+    /// They are kept for the next flush if the write fails
+    pub fn flush(&self) -> Result<(), Error> {
+        let repo = match &self.repo {
+            Some(repo) => repo,
+            None => return Ok(()),
+        };
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        if let Err(err) = repo.add_counters(&pending) {
+            let mut kept = self.pending.lock().unwrap();
+            for (name, count) in pending {
+                *kept.entry(name).or_insert(0) += count;
+            }
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    fn increment(&self, name: &str) {
+        *self
+            .counters
+            .lock()
+            .unwrap()
+            .entry(name.to_string())
+            .or_insert(0) += 1;
+
+        if self.repo.is_some() {
+            *self
+                .pending
+                .lock()
+                .unwrap()
+                .entry(name.to_string())
+                .or_insert(0) += 1;
+        }
+    }
+}
+
+/// Reason a denial is counted under, fixed so messages carrying kinds or
+/// byte counts do not each get a counter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DenyReason {
+    MalformedEvent,
+    InvalidSignature,
+    AuthMismatch,
+    ContentTooLarge,
+    EmptyContent,
+    BlockedPubkey,
+    BlockedReference,
+    StartingUp,
+    UnknownAccount,
+    NotAllowedToPublish,
+    TooManyConcurrentEvents,
+    TooManyMentions,
+    TooManyDistinctKinds,
+    DuplicateContent,
+    UpdateQueueFull,
+    InternalError,
+    DatabaseUnavailable,
+    KindNotAllowed,
+    RateLimit,
+    ByteLimit,
+}
+
+impl DenyReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::MalformedEvent => "malformed_event",
+            Self::InvalidSignature => "invalid_signature",
+            Self::AuthMismatch => "auth_mismatch",
+            Self::ContentTooLarge => "content_too_large",
+            Self::EmptyContent => "empty_content",
+            Self::BlockedPubkey => "blocked_pubkey",
+            Self::BlockedReference => "blocked_reference",
+            Self::StartingUp => "starting_up",
+            Self::UnknownAccount => "unknown_account",
+            Self::NotAllowedToPublish => "not_allowed_to_publish",
+            Self::TooManyConcurrentEvents => "too_many_concurrent_events",
+            Self::TooManyMentions => "too_many_mentions",
+            Self::TooManyDistinctKinds => "too_many_distinct_kinds",
+            Self::DuplicateContent => "duplicate_content",
+            Self::UpdateQueueFull => "update_queue_full",
+            Self::InternalError => "internal_error",
+            Self::DatabaseUnavailable => "database_unavailable",
+            Self::KindNotAllowed => "kind_not_allowed",
+            Self::RateLimit => "rate_limit",
+            Self::ByteLimit => "byte_limit",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn test_persisted_counters() {
+        let repo = Repo::new_in_memory(HashSet::new());
+        let metrics = Metrics::new(Some(repo.clone())).unwrap();
+        let permits = metrics.get(PERMIT);
+        let denials = metrics.denials(DenyReason::NotAllowedToPublish);
+
+        metrics.record_permit();
+        metrics.record_permit();
+        metrics.record_deny(DenyReason::NotAllowedToPublish);
+        // Nothing is written until flushed
+        assert_eq!(None, repo.get_counters().unwrap().get(PERMIT));
+        metrics.flush().unwrap();
+        drop(metrics);
+
+        // Restart
+        let metrics = Metrics::new(Some(repo)).unwrap();
+        assert_eq!(permits + 2, metrics.get(PERMIT));
+        assert_eq!(
+            denials + 1,
+            metrics.denials(DenyReason::NotAllowedToPublish)
+        );
+    }
+
+    #[test]
+    fn test_prometheus() {
+        let metrics = Metrics::default();
+        metrics.record_permit();
+        metrics.record_permit();
+        metrics.record_deny(DenyReason::KindNotAllowed);
+        metrics.record_deny(DenyReason::RateLimit);
+        metrics.record(QUEUE_DROPPED);
+
+        assert_eq!(
+            "# TYPE nauthz_denials_total counter\n\
+             nauthz_denials_total{reason=\"kind_not_allowed\"} 1\n\
+             nauthz_denials_total{reason=\"rate_limit\"} 1\n\
+             # TYPE nauthz_permit_total counter\n\
+             nauthz_permit_total 2\n\
+             # TYPE nauthz_update_queue_dropped_total counter\n\
+             nauthz_update_queue_dropped_total 1\n",
+            metrics.prometheus()
+        );
+    }
+}
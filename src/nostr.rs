@@ -4,29 +4,295 @@ use nostr_sdk::prelude::*;
 
 use crate::nauthz_grpc::event::TagEntry;
 
+use crate::config::Info;
+use crate::db::RelayList;
 use crate::error::Error;
-use crate::utils::{create_client, handle_keys};
+use crate::utils::{create_client, handle_keys, unix_time};
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+use tracing::warn;
 
 use crate::nauthz_grpc;
 
+/// Kind of NIP-65 relay list events
+pub const RELAY_LIST_KIND: u64 = 10002;
+
+/// Fetches events from relays
+/// Implemented for the nostr-sdk `Client` and by `MemoryRelay` for use without a network
+#[tonic::async_trait]
+pub trait RelayClient: Send + Sync {
+    async fn get_events_of(
+        &self,
+        filters: Vec<SubscriptionFilter>,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<Event>, Error>;
+
+    /// Streams events matching `filters` as relays send them
+    /// The receiver closes once the relays stop sending
+    async fn subscribe(
+        &self,
+        filters: Vec<SubscriptionFilter>,
+    ) -> Result<mpsc::UnboundedReceiver<Event>, Error>;
+
+    /// Connection state of each relay by URL, none for clients without relays
+    async fn relay_status(&self) -> BTreeMap<String, String> {
+        BTreeMap::new()
+    }
+
+    /// Queries `urls` as well from now on
+    async fn extend_relays(&self, _urls: &HashSet<String>) {}
+}
+
+#[tonic::async_trait]
+impl RelayClient for Client {
+    async fn get_events_of(
+        &self,
+        filters: Vec<SubscriptionFilter>,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<Event>, Error> {
+        Ok(Client::get_events_of(self, filters, timeout).await?)
+    }
+
+    async fn subscribe(
+        &self,
+        filters: Vec<SubscriptionFilter>,
+    ) -> Result<mpsc::UnboundedReceiver<Event>, Error> {
+        let mut notifications = self.notifications();
+        Client::subscribe(self, filters).await;
+
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                match notifications.recv().await {
+                    Ok(RelayPoolNotification::Event(_, event)) => {
+                        if sender.send(event).is_err() {
+                            break;
+                        }
+                    }
+                    Ok(RelayPoolNotification::Message(..)) => (),
+                    Ok(RelayPoolNotification::Shutdown) | Err(RecvError::Closed) => break,
+                    Err(RecvError::Lagged(missed)) => warn!("Missed {missed} relay notifications"),
+                }
+            }
+        });
+        Ok(receiver)
+    }
+
+    async fn extend_relays(&self, urls: &HashSet<String>) {
+        for url in urls {
+            if let Err(err) = self.add_relay(url.as_str(), None).await {
+                warn!("Could not add relay {url}: {err}");
+            }
+        }
+        self.connect().await;
+    }
+
+    async fn relay_status(&self) -> BTreeMap<String, String> {
+        let mut status = BTreeMap::new();
+        for (url, relay) in self.relays().await {
+            status.insert(url.to_string(), format!("{:?}", relay.status().await));
+        }
+        status
+    }
+}
+
+/// Relay held in memory that answers queries from the events published to it
+#[derive(Default)]
+pub struct MemoryRelay {
+    events: Mutex<Vec<Event>>,
+    subscriptions: Mutex<Vec<(Vec<SubscriptionFilter>, mpsc::UnboundedSender<Event>)>>,
+}
+
+impl MemoryRelay {
+    pub fn publish(&self, event: Event) {
+        self.subscriptions
+            .lock()
+            .unwrap()
+            .retain(|(filters, sender)| {
+                if !filters.iter().any(|f| filter_matches(f, &event)) {
+                    return !sender.is_closed();
+                }
+                sender.send(event.clone()).is_ok()
+            });
+        self.events.lock().unwrap().push(event);
+    }
+
+    /// Closes every subscription as if the relay went away
+    pub fn disconnect(&self) {
+        self.subscriptions.lock().unwrap().clear();
+    }
+}
+
+#[tonic::async_trait]
+impl RelayClient for MemoryRelay {
+    async fn get_events_of(
+        &self,
+        filters: Vec<SubscriptionFilter>,
+        _timeout: Option<Duration>,
+    ) -> Result<Vec<Event>, Error> {
+        let events = self.events.lock().unwrap();
+        Ok(events
+            .iter()
+            .filter(|e| filters.iter().any(|f| filter_matches(f, e)))
+            .cloned()
+            .collect())
+    }
+
+    async fn subscribe(
+        &self,
+        filters: Vec<SubscriptionFilter>,
+    ) -> Result<mpsc::UnboundedReceiver<Event>, Error> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.subscriptions.lock().unwrap().push((filters, sender));
+        Ok(receiver)
+    }
+}
+
+fn filter_matches(filter: &SubscriptionFilter, event: &Event) -> bool {
+    filter
+        .authors
+        .as_ref()
+        .is_none_or(|a| a.contains(&event.pubkey))
+        && filter
+            .kinds
+            .as_ref()
+            .is_none_or(|k| k.contains(&event.kind))
+        && filter
+            .since
+            .is_none_or(|s| event.created_at.as_u64() >= s.as_u64())
+}
+
 #[derive(Clone)]
 pub struct Nostr {
-    client: Client,
+    client: Arc<dyn RelayClient>,
+    /// Only fetch contact lists updated within this many days
+    contact_list_since_days: Option<u64>,
+    /// Max contact list events fetched per query
+    contact_list_limit: Option<usize>,
+    /// Times a failed fetch is retried
+    retry_count: u32,
+    /// Wait before the first retry, doubled for each retry after
+    retry_delay: Duration,
+    /// Time a query may take before it counts as failed
+    timeout: Option<Duration>,
 }
 
 impl Nostr {
-    pub async fn new(relay_url: &str, key: &Option<String>) -> Result<Self, Error> {
-        let key = key.to_owned();
-        let keys = handle_keys(key).unwrap();
+    /// Connects to the configured relays, retrying with the relay retry settings
+    pub async fn new(info: &Info) -> Result<Self, Error> {
+        let key = info.nostr_key.to_owned();
+        let keys =
+            handle_keys(key).map_err(|err| Error::RelayError(format!("Invalid key: {err}")))?;
 
-        let client = create_client(&keys, vec![relay_url.to_string()])
-            .await
-            .unwrap();
+        let mut delay = Duration::from_millis(info.relay_retry_delay_ms);
+        let mut attempt = 0;
+        let client = loop {
+            let client = create_client(&keys, info.relay_url.clone(), info.relay_auth)
+                .await
+                .map_err(|err| err.to_string());
+            match client {
+                Ok(client) => break client,
+                Err(err) if attempt < info.relay_retry_count => {
+                    attempt += 1;
+                    warn!(
+                        "Relay connect failed, retry {attempt} of {} in {delay:?}: {err}",
+                        info.relay_retry_count
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+                Err(err) => return Err(Error::RelayError(err)),
+            }
+        };
+
+        let mut nostr = Self::with_client(Arc::new(client));
+        nostr.contact_list_since_days = info.contact_list_since_days;
+        nostr.contact_list_limit = info.contact_list_limit;
+        Ok(nostr
+            .with_retries(
+                info.relay_retry_count,
+                Duration::from_millis(info.relay_retry_delay_ms),
+            )
+            .with_timeout(info.relay_timeout_secs.map(Duration::from_secs)))
+    }
 
-        Ok(Self { client })
+    /// Nostr backed by any relay client
+    pub fn with_client(client: Arc<dyn RelayClient>) -> Self {
+        Self {
+            client,
+            contact_list_since_days: None,
+            contact_list_limit: None,
+            retry_count: 0,
+            retry_delay: Duration::ZERO,
+            timeout: None,
+        }
+    }
+
+    /// Fails queries that take longer than `timeout`
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Retries failed fetches `count` times with exponential backoff from `delay`
+    pub fn with_retries(mut self, count: u32, delay: Duration) -> Self {
+        self.retry_count = count;
+        self.retry_delay = delay;
+        self
+    }
+
+    /// Client without any relays, for running without network access
+    pub fn offline() -> Self {
+        Self::with_client(Arc::new(MemoryRelay::default()))
+    }
+
+    fn contact_list_filter(&self, authors: Vec<XOnlyPublicKey>) -> SubscriptionFilter {
+        let since = self
+            .contact_list_since_days
+            .map(|days| Timestamp::from(unix_time().saturating_sub(days * 86400)));
+
+        SubscriptionFilter {
+            ids: None,
+            authors: Some(authors),
+            kinds: Some(vec![Kind::ContactList]),
+            events: None,
+            pubkeys: None,
+            hashtags: None,
+            references: None,
+            search: None,
+            since,
+            until: None,
+            limit: self.contact_list_limit,
+        }
+    }
+
+    /// Contact lists of `keys` published from now on
+    pub async fn subscribe_contact_lists(
+        &self,
+        keys: &HashSet<String>,
+    ) -> Result<mpsc::UnboundedReceiver<Event>, Error> {
+        let filter = SubscriptionFilter {
+            since: Some(Timestamp::from(unix_time())),
+            limit: None,
+            ..self.contact_list_filter(authors(keys))
+        };
+        self.client.subscribe(vec![filter]).await
+    }
+
+    /// Connection state of each relay by URL
+    pub async fn relay_status(&self) -> BTreeMap<String, String> {
+        self.client.relay_status().await
+    }
+
+    /// Adds `urls` to the relays queried, skipping any that are invalid
+    pub async fn add_relays(&self, urls: &HashSet<String>) {
+        self.client.extend_relays(urls).await
     }
 
     /// Accepts a list of keys
@@ -34,66 +300,190 @@ impl Nostr {
     pub async fn get_contact_lists(
         &self,
         keys: &HashSet<String>,
-    ) -> Result<HashMap<String, HashSet<String>>, Error> {
-        let authors: Vec<XOnlyPublicKey> = keys
-            .iter()
-            .flat_map(|a| XOnlyPublicKey::from_str(a.as_str()))
-            .collect();
+    ) -> Result<HashMap<String, ContactList>, Error> {
+        let filter = self.contact_list_filter(authors(keys));
+        let events = self.fetch(filter).await?;
 
-        let events: Vec<Event> = self
-            .client
-            .get_events_of(
-                vec![SubscriptionFilter {
-                    ids: None,
-                    authors: Some(authors),
-                    kinds: Some(vec![Kind::ContactList]),
-                    events: None,
-                    pubkeys: None,
-                    hashtags: None,
-                    references: None,
-                    search: None,
-                    since: None,
-                    until: None,
-                    limit: None,
-                }],
-                None,
-            )
-            .await?;
+        Ok(newest_per_author(&events)
+            .into_values()
+            .map(|e| (e.pubkey.to_string(), ContactList::from(e)))
+            .collect())
+    }
 
-        Ok(events
-            .iter()
-            .map(|e| {
-                let follows = follows_from_event(e);
-                (e.pubkey.to_string(), follows)
-            })
+    /// NIP-65 relay lists of `keys`
+    pub async fn get_relay_lists(
+        &self,
+        keys: &HashSet<String>,
+    ) -> Result<HashMap<String, RelayList>, Error> {
+        let filter = SubscriptionFilter {
+            kinds: Some(vec![Kind::from(RELAY_LIST_KIND)]),
+            since: None,
+            limit: None,
+            ..self.contact_list_filter(authors(keys))
+        };
+        let events = self.fetch(filter).await?;
+
+        Ok(newest_per_author(&events)
+            .into_values()
+            .map(|e| (e.pubkey.to_string(), relay_list_from_event(e)))
             .collect())
+    }
+
+    /// Events matching `filter`, retried with backoff on failure
+    async fn fetch(&self, filter: SubscriptionFilter) -> Result<Vec<Event>, Error> {
+        let mut delay = self.retry_delay;
+        let mut attempt = 0;
+        loop {
+            let fetch = self
+                .client
+                .get_events_of(vec![filter.clone()], self.timeout);
+            let fetched = match self.timeout {
+                Some(timeout) => tokio::time::timeout(timeout, fetch)
+                    .await
+                    .unwrap_or(Err(Error::RelayTimeout(timeout))),
+                None => fetch.await,
+            };
+            match fetched {
+                Ok(events) => return Ok(events),
+                Err(err) if attempt < self.retry_count => {
+                    attempt += 1;
+                    warn!(
+                        "Relay fetch failed, retry {attempt} of {} in {delay:?}: {err}",
+                        self.retry_count
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+fn authors(keys: &HashSet<String>) -> Vec<XOnlyPublicKey> {
+    keys.iter()
+        .flat_map(|a| XOnlyPublicKey::from_str(a.as_str()))
+        .collect()
+}
 
-        // Ok(follows_from_events(events))
+/// Relays may each return an event of the same replaceable kind for an author, keeps the newest
+fn newest_per_author(events: &[Event]) -> HashMap<XOnlyPublicKey, &Event> {
+    let mut newest: HashMap<XOnlyPublicKey, &Event> = HashMap::new();
+    for event in events {
+        match newest.get(&event.pubkey) {
+            Some(current) if current.created_at.as_u64() >= event.created_at.as_u64() => (),
+            _ => {
+                newest.insert(event.pubkey, event);
+            }
+        }
+    }
+    newest
+}
 
-        // Create query for the contact list of each key in list
+/// Write relays of a NIP-65 relay list, `r` tags without a marker are read and write
+pub fn relay_list_from_event(event: &Event) -> RelayList {
+    let write = event
+        .tags
+        .iter()
+        .map(|tag| tag.as_vec())
+        .filter(|values| values.len() > 1 && values[0] == "r")
+        .filter(|values| values.get(2).is_none_or(|marker| marker == "write"))
+        .map(|values| values[1].clone())
+        .collect();
+    RelayList {
+        write,
+        created_at: event.created_at.as_u64(),
     }
 }
 
+/// Pubkeys of the `p` tags, other tags such as relays or `d` tags are ignored
 pub fn follows_from_event(event: &Event) -> HashSet<String> {
     event
         .tags
         .iter()
-        .map(|tag| tag.as_vec()[1].clone())
+        .map(|tag| tag.as_vec())
+        .filter(|values| values.len() > 1 && values[0] == "p")
+        .map(|values| values[1].clone())
         .collect()
 }
 
-impl From<nauthz_grpc::Event> for Event {
-    fn from(event: nauthz_grpc::Event) -> Event {
-        let id = EventId::from_slice(&event.id).unwrap();
-        let pubkey = XOnlyPublicKey::from_slice(&event.pubkey).unwrap();
-        let sig = Signature::from_slice(&event.sig).unwrap();
+/// Newest contact list of an author fetched from the relays
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContactList {
+    pub contacts: HashSet<String>,
+    pub created_at: u64,
+    pub event_id: String,
+}
+
+impl From<&Event> for ContactList {
+    fn from(event: &Event) -> Self {
+        Self {
+            contacts: follows_from_event(event),
+            created_at: event.created_at.as_u64(),
+            event_id: event.id.to_hex(),
+        }
+    }
+}
+
+/// Entry of a contact list `p` tag
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Contact {
+    pub pubkey: String,
+    /// Relay the followee can be found on
+    pub relay: Option<String>,
+    pub petname: Option<String>,
+}
+
+/// Contacts of the `p` tags with any relay hint and petname, empty ones are unset
+pub fn contacts_from_event(event: &Event) -> Vec<Contact> {
+    event
+        .tags
+        .iter()
+        .map(|tag| tag.as_vec())
+        .filter(|values| values.len() > 1 && values[0] == "p")
+        .map(|values| {
+            let value = |i: usize| values.get(i).filter(|v| !v.is_empty()).cloned();
+            Contact {
+                pubkey: values[1].clone(),
+                relay: value(2),
+                petname: value(3),
+            }
+        })
+        .collect()
+}
+
+/// Relay hints of the `p` tags by followee
+pub fn relay_hints_from_event(event: &Event) -> HashMap<String, String> {
+    contacts_from_event(event)
+        .into_iter()
+        .filter_map(|contact| Some((contact.pubkey, contact.relay?)))
+        .collect()
+}
+
+impl nauthz_grpc::Event {
+    /// Values of the tags named `name`, e.g. the pubkeys of `p` tags
+    pub fn tag_values<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.tags
+            .iter()
+            .filter(move |t| t.values.len() > 1 && t.values[0] == name)
+            .map(|t| t.values[1].as_str())
+    }
+}
+
+impl TryFrom<nauthz_grpc::Event> for Event {
+    type Error = Error;
+
+    fn try_from(event: nauthz_grpc::Event) -> Result<Event, Error> {
+        let id = EventId::from_slice(&event.id)?;
+        let pubkey = XOnlyPublicKey::from_slice(&event.pubkey)?;
+        let sig = Signature::from_slice(&event.sig)?;
         let tags = event
             .tags
-            .iter()
-            .map(|t| <TagEntry as Into<Tag>>::into(t.clone()))
-            .collect();
+            .into_iter()
+            .map(Tag::try_from)
+            .collect::<Result<Vec<Tag>, Error>>()?;
 
-        Event {
+        Ok(Event {
             id,
             pubkey,
             created_at: event.created_at.into(),
@@ -102,12 +492,392 @@ impl From<nauthz_grpc::Event> for Event {
             sig,
             ots: None,
             tags,
+        })
+    }
+}
+
+impl From<&Event> for nauthz_grpc::Event {
+    fn from(event: &Event) -> nauthz_grpc::Event {
+        nauthz_grpc::Event {
+            id: event.id.as_bytes().to_vec(),
+            pubkey: event.pubkey.serialize().to_vec(),
+            created_at: event.created_at.as_u64(),
+            kind: event.kind.as_u64(),
+            content: event.content.clone(),
+            tags: event
+                .tags
+                .iter()
+                .map(|tag| TagEntry {
+                    values: tag.as_vec(),
+                })
+                .collect(),
+            sig: event.sig.as_ref().to_vec(),
         }
     }
 }
 
-impl From<TagEntry> for Tag {
-    fn from(tag: TagEntry) -> Tag {
-        Tag::parse(tag.values).unwrap()
+impl TryFrom<TagEntry> for Tag {
+    type Error = Error;
+
+    fn try_from(tag: TagEntry) -> Result<Tag, Error> {
+        Ok(Tag::parse(tag.values)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::event;
+
+    const PUBKEY: &str = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9";
+
+    #[test]
+    fn test_malformed_event() {
+        assert!(Event::try_from(event(PUBKEY, 1, "gm", &[])).is_ok());
+
+        let mut short_pubkey = event(PUBKEY, 1, "gm", &[]);
+        short_pubkey.pubkey.truncate(31);
+        assert!(matches!(
+            Event::try_from(short_pubkey),
+            Err(Error::Secp256k1Error(_))
+        ));
+
+        let mut short_id = event(PUBKEY, 1, "gm", &[]);
+        short_id.id.truncate(30);
+        assert!(matches!(Event::try_from(short_id), Err(Error::IdError(_))));
+    }
+
+    fn contact_list(keys: &Keys, follows: &[XOnlyPublicKey]) -> Event {
+        let tags: Vec<Tag> = follows.iter().map(|f| Tag::PubKey(*f, None)).collect();
+        EventBuilder::new(Kind::ContactList, "", &tags)
+            .to_event(keys)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_follows_from_event() {
+        let tags: Vec<Tag> = [
+            vec!["p", PUBKEY],
+            vec!["r", "wss://relay.example.com"],
+            vec!["d", "follows"],
+            vec!["client"],
+        ]
+        .into_iter()
+        .map(|values| Tag::parse(values.into_iter().map(String::from).collect()).unwrap())
+        .collect();
+        let event = EventBuilder::new(Kind::ContactList, "", &tags)
+            .to_event(&Keys::generate())
+            .unwrap();
+
+        assert_eq!(
+            HashSet::from([PUBKEY.to_string()]),
+            follows_from_event(&event)
+        );
+    }
+
+    #[test]
+    fn test_contacts_from_event() {
+        let other = Keys::generate().public_key().to_string();
+        let tags: Vec<Tag> = [
+            vec!["p", PUBKEY, "wss://relay.example.com", "alice"],
+            vec!["p", &other, "", "bob"],
+        ]
+        .into_iter()
+        .map(|values| Tag::parse(values.into_iter().map(String::from).collect()).unwrap())
+        .collect();
+        let event = EventBuilder::new(Kind::ContactList, "", &tags)
+            .to_event(&Keys::generate())
+            .unwrap();
+
+        let mut contacts = contacts_from_event(&event);
+        contacts.sort_by_key(|c| c.pubkey != PUBKEY);
+        assert_eq!(
+            vec![
+                Contact {
+                    pubkey: PUBKEY.to_string(),
+                    relay: Some("wss://relay.example.com".to_string()),
+                    petname: Some("alice".to_string()),
+                },
+                Contact {
+                    pubkey: other,
+                    relay: None,
+                    petname: Some("bob".to_string()),
+                },
+            ],
+            contacts
+        );
+        assert_eq!(
+            HashMap::from([(PUBKEY.to_string(), "wss://relay.example.com".to_string())]),
+            relay_hints_from_event(&event)
+        );
+    }
+
+    /// Fails the first `failures` queries
+    struct FlakyRelay {
+        failures: Mutex<usize>,
+        relay: MemoryRelay,
+    }
+
+    #[tonic::async_trait]
+    impl RelayClient for FlakyRelay {
+        async fn get_events_of(
+            &self,
+            filters: Vec<SubscriptionFilter>,
+            timeout: Option<Duration>,
+        ) -> Result<Vec<Event>, Error> {
+            {
+                let mut failures = self.failures.lock().unwrap();
+                if *failures > 0 {
+                    *failures -= 1;
+                    return Err(std::io::Error::other("relay down").into());
+                }
+            }
+            self.relay.get_events_of(filters, timeout).await
+        }
+
+        async fn subscribe(
+            &self,
+            filters: Vec<SubscriptionFilter>,
+        ) -> Result<mpsc::UnboundedReceiver<Event>, Error> {
+            self.relay.subscribe(filters).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fetch_retries() {
+        let alice = Keys::generate();
+        let bob = Keys::generate();
+        let keys = HashSet::from([alice.public_key().to_string()]);
+        let flaky = || {
+            let relay = MemoryRelay::default();
+            relay.publish(contact_list(&alice, &[bob.public_key()]));
+            Arc::new(FlakyRelay {
+                failures: Mutex::new(2),
+                relay,
+            })
+        };
+
+        let nos = Nostr::with_client(flaky()).with_retries(1, Duration::from_millis(1));
+        assert!(nos.get_contact_lists(&keys).await.is_err());
+
+        let nos = Nostr::with_client(flaky()).with_retries(2, Duration::from_millis(1));
+        let contact_lists = nos.get_contact_lists(&keys).await.unwrap();
+        assert_eq!(
+            Some(&HashSet::from([bob.public_key().to_string()])),
+            contact_lists
+                .get(&alice.public_key().to_string())
+                .map(|list| &list.contacts)
+        );
+    }
+
+    /// Never answers a query
+    struct SilentRelay;
+
+    #[tonic::async_trait]
+    impl RelayClient for SilentRelay {
+        async fn get_events_of(
+            &self,
+            _filters: Vec<SubscriptionFilter>,
+            _timeout: Option<Duration>,
+        ) -> Result<Vec<Event>, Error> {
+            std::future::pending().await
+        }
+
+        async fn subscribe(
+            &self,
+            filters: Vec<SubscriptionFilter>,
+        ) -> Result<mpsc::UnboundedReceiver<Event>, Error> {
+            MemoryRelay::default().subscribe(filters).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fetch_timeout() {
+        let keys = HashSet::from([PUBKEY.to_string()]);
+        let nos = Nostr::with_client(Arc::new(SilentRelay))
+            .with_retries(1, Duration::from_millis(1))
+            .with_timeout(Some(Duration::from_millis(10)));
+
+        let fetched = tokio::time::timeout(Duration::from_secs(5), nos.get_contact_lists(&keys))
+            .await
+            .unwrap();
+        assert!(matches!(fetched, Err(Error::RelayTimeout(_))));
+    }
+
+    #[tokio::test]
+    async fn test_connect_error() {
+        let info = Info {
+            relay_url: vec!["not a relay".to_string()],
+            relay_retry_count: 2,
+            relay_retry_delay_ms: 1,
+            ..Default::default()
+        };
+        assert!(matches!(Nostr::new(&info).await, Err(Error::RelayError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_memory_relay_contact_lists() {
+        let alice = Keys::generate();
+        let bob = Keys::generate();
+        let carol = Keys::generate();
+
+        let relay = MemoryRelay::default();
+        relay.publish(contact_list(
+            &alice,
+            &[bob.public_key(), carol.public_key()],
+        ));
+        relay.publish(contact_list(&bob, &[carol.public_key()]));
+        relay.publish(contact_list(&carol, &[alice.public_key()]));
+        relay.publish(
+            EventBuilder::new_text_note("gm", &[])
+                .to_event(&alice)
+                .unwrap(),
+        );
+
+        let nos = Nostr::with_client(Arc::new(relay));
+        let keys = HashSet::from([alice.public_key().to_string(), bob.public_key().to_string()]);
+        let contact_lists: HashMap<String, HashSet<String>> = nos
+            .get_contact_lists(&keys)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(pubkey, list)| (pubkey, list.contacts))
+            .collect();
+
+        let expected = HashMap::from([
+            (
+                alice.public_key().to_string(),
+                HashSet::from([bob.public_key().to_string(), carol.public_key().to_string()]),
+            ),
+            (
+                bob.public_key().to_string(),
+                HashSet::from([carol.public_key().to_string()]),
+            ),
+        ]);
+        assert_eq!(expected, contact_lists);
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_contact_lists() {
+        let alice = Keys::generate();
+        let bob = Keys::generate();
+        let relay = Arc::new(MemoryRelay::default());
+        let nos = Nostr::with_client(relay.clone());
+
+        let keys = HashSet::from([alice.public_key().to_string()]);
+        let mut events = nos.subscribe_contact_lists(&keys).await.unwrap();
+
+        // Only contact lists of the subscribed authors are streamed
+        relay.publish(contact_list(&bob, &[alice.public_key()]));
+        relay.publish(
+            EventBuilder::new_text_note("gm", &[])
+                .to_event(&alice)
+                .unwrap(),
+        );
+        let list = contact_list(&alice, &[bob.public_key()]);
+        relay.publish(list.clone());
+        assert_eq!(Some(list), events.recv().await);
+
+        relay.disconnect();
+        assert_eq!(None, events.recv().await);
+    }
+
+    #[tokio::test]
+    async fn test_newest_contact_list_wins() {
+        let alice = Keys::generate();
+        let bob = Keys::generate();
+        let carol = Keys::generate();
+
+        let mut newer = contact_list(&alice, &[carol.public_key()]);
+        newer.created_at = Timestamp::from(unix_time() - 60);
+        let (newer_id, newer_created_at) = (newer.id, newer.created_at.as_u64());
+        let mut stale = contact_list(&alice, &[bob.public_key()]);
+        stale.created_at = Timestamp::from(unix_time() - 3600);
+
+        // The stale list comes last as if a second relay returned it
+        let relay = MemoryRelay::default();
+        relay.publish(newer);
+        relay.publish(stale);
+
+        let nos = Nostr::with_client(Arc::new(relay));
+        let keys = HashSet::from([alice.public_key().to_string()]);
+        let contact_lists = nos.get_contact_lists(&keys).await.unwrap();
+
+        let list = &contact_lists[&alice.public_key().to_string()];
+        assert_eq!(1, contact_lists.len());
+        assert_eq!(
+            HashSet::from([carol.public_key().to_string()]),
+            list.contacts
+        );
+        assert_eq!(newer_id.to_hex(), list.event_id);
+        assert_eq!(newer_created_at, list.created_at);
+    }
+
+    fn relay_list(keys: &Keys, tags: &[&[&str]], created_at: u64) -> Event {
+        let tags: Vec<Tag> = tags
+            .iter()
+            .map(|values| Tag::parse(values.iter().map(|v| v.to_string()).collect()).unwrap())
+            .collect();
+        let mut event = EventBuilder::new(Kind::from(RELAY_LIST_KIND), "", &tags)
+            .to_event(keys)
+            .unwrap();
+        event.created_at = Timestamp::from(created_at);
+        event
+    }
+
+    #[tokio::test]
+    async fn test_relay_lists() {
+        let alice = Keys::generate();
+        let now = unix_time();
+
+        let relay = MemoryRelay::default();
+        relay.publish(relay_list(
+            &alice,
+            &[
+                &["r", "wss://both.example.com"],
+                &["r", "wss://write.example.com", "write"],
+                &["r", "wss://read.example.com", "read"],
+            ],
+            now - 60,
+        ));
+        relay.publish(relay_list(
+            &alice,
+            &[&["r", "wss://old.example.com"]],
+            now - 3600,
+        ));
+
+        let nos = Nostr::with_client(Arc::new(relay));
+        let keys = HashSet::from([alice.public_key().to_string()]);
+        let relay_lists = nos.get_relay_lists(&keys).await.unwrap();
+
+        assert_eq!(
+            HashMap::from([(
+                alice.public_key().to_string(),
+                RelayList {
+                    write: vec![
+                        "wss://both.example.com".to_string(),
+                        "wss://write.example.com".to_string()
+                    ],
+                    created_at: now - 60,
+                }
+            )]),
+            relay_lists
+        );
+    }
+
+    #[tokio::test]
+    async fn test_contact_list_filter() {
+        let mut nos = Nostr::offline();
+        nos.contact_list_since_days = Some(90);
+        nos.contact_list_limit = Some(500);
+
+        let pubkey = XOnlyPublicKey::from_str(PUBKEY).unwrap();
+        let filter = nos.contact_list_filter(vec![pubkey]);
+
+        let since = filter.since.unwrap().as_u64();
+        let expected = unix_time() - 90 * 86400;
+        assert!(since <= expected && expected - since < 5);
+        assert_eq!(Some(500), filter.limit);
+        assert_eq!(Some(vec![pubkey]), filter.authors);
     }
 }
@@ -1,18 +1,207 @@
-use crate::config::Limitation;
+use crate::config::{
+    ContactUpdateMode, DuplicateContent, Limitation, Settings, TierCacheSettings, MONTH,
+};
 use crate::db::Db;
-use crate::db::{Account, Tier};
+use crate::db::{Account, Denial, RelayList, Tier, TierChange, TokenBucket};
 use crate::error::Error;
+use crate::metrics::DenyReason;
+use crate::snapshot::{GraphDiff, GraphSnapshot};
+use crate::tier_cache::TierCache;
 use crate::utils::unix_time;
 
-use std::collections::HashSet;
-use std::sync::{Arc, Mutex};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::io::{BufRead, Write};
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::Duration;
 
+use linked_hash_map::LinkedHashMap;
 use nostr_sdk::prelude::*;
-use tracing::info;
+use serde::Deserialize;
+use tokio::sync::mpsc;
+use tracing::{debug, info};
 
 #[derive(Clone)]
 pub struct Repo {
-    db: Arc<Mutex<Db>>,
+    /// Reads share the lock as redb runs read transactions concurrently
+    /// Writes are exclusive, redb panics on overlapping write transactions
+    /// and updates spanning several transactions must not interleave
+    db: Arc<RwLock<Db>>,
+    /// Recent content hashes and the time they were seen per pubkey
+    content_hashes: Arc<Mutex<LinkedHashMap<(String, u64), SeenContent>>>,
+    contact_update_mode: ContactUpdateMode,
+    /// Window during which rate limits are lifted for some tiers
+    event_window: Arc<Mutex<Option<EventWindow>>>,
+    /// Max accounts written per transaction by bulk operations
+    max_batch_size: Option<usize>,
+    /// Events being decided on per pubkey
+    in_flight: Arc<Mutex<HashMap<String, usize>>>,
+    /// Recent tier lookups, `None` when caching is off
+    tier_cache: Option<Arc<TierCache>>,
+}
+
+/// An event counted as in flight until dropped, so a decision that errors
+/// or is cancelled still gives back its slot
+pub struct InFlight {
+    pubkey: String,
+    in_flight: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if let Some(count) = in_flight.get_mut(&self.pubkey) {
+            *count -= 1;
+            if *count == 0 {
+                in_flight.remove(&self.pubkey);
+            }
+        }
+    }
+}
+
+/// Tiers exempt from rate limits until `expires_at`
+#[derive(Debug, Clone)]
+struct EventWindow {
+    tiers: HashSet<Tier>,
+    expires_at: u64,
+}
+
+/// Why an account has its tier
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TierSource {
+    /// In the configured primary keys
+    Primary,
+    /// Pinned by an admin
+    Pinned,
+    /// Computed from the follow graph
+    Computed,
+    /// Not in the graph
+    Default,
+}
+
+/// Remaining hourly, daily and monthly events, see `Repo::remaining_quota`
+pub type RemainingQuota = (Option<usize>, Option<usize>, Option<usize>);
+
+/// Rate or byte limit an event would exceed, see `Repo::check_rate_limits`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LimitExceeded {
+    pub reason: DenyReason,
+    /// Message for the relay, naming the limit
+    pub message: String,
+}
+
+impl LimitExceeded {
+    fn rate(message: &str) -> Self {
+        Self {
+            reason: DenyReason::RateLimit,
+            message: message.to_string(),
+        }
+    }
+}
+
+/// Everything known about an account, see `Repo::account_summary`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccountSummary {
+    pub tier: Tier,
+    pub source: TierSource,
+    /// Whether the account's tier can publish
+    pub can_publish: bool,
+    /// Remaining events this hour, `None` when there is no hourly limit
+    pub remaining_hour: Option<usize>,
+    /// Remaining events today, `None` when there is no daily limit
+    pub remaining_day: Option<usize>,
+    /// Remaining events in the past 30 days, `None` when there is no monthly limit
+    pub remaining_month: Option<usize>,
+    pub follower_count: usize,
+    pub follow_count: usize,
+    pub first_seen: Option<u64>,
+    /// Time of the latest event still kept
+    pub last_seen: Option<u64>,
+    pub allowlisted: bool,
+    pub blocklisted: bool,
+    /// Tier pinned by an admin, set even when a primary ignores it
+    pub pinned: Option<Tier>,
+}
+
+/// Iterator over chunks of follow edges, see `Repo::follow_edges_chunks`
+pub struct FollowEdges {
+    repo: Repo,
+    after: Option<String>,
+    chunk_size: usize,
+    done: bool,
+}
+
+impl Iterator for FollowEdges {
+    type Item = Result<Vec<(String, String)>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let page = self
+            .repo
+            .read()
+            .and_then(|db| db.follow_edges_page(self.after.as_deref(), self.chunk_size));
+        match page {
+            Ok(page) if page.is_empty() => {
+                self.done = true;
+                None
+            }
+            Ok(page) => {
+                self.after = page.last().map(|(follower, _)| follower.clone());
+                Some(Ok(page))
+            }
+            Err(err) => {
+                self.done = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+/// A line of a follows import
+#[derive(Deserialize)]
+struct FollowsRecord {
+    pubkey: String,
+    follows: HashSet<String>,
+}
+
+/// Window distinct kinds are counted over
+const DISTINCT_KINDS_WINDOW: u64 = 86400;
+
+/// Max content hashes tracked for duplicate content, across all pubkeys,
+/// before the least recently posted are dropped
+const MAX_TRACKED_CONTENT: usize = 100_000;
+
+/// Times a pubkey posted the same content, see `Repo::record_content`
+#[derive(Debug, Clone, Copy)]
+struct SeenContent {
+    count: usize,
+    last_seen: u64,
+}
+
+fn content_hash(content: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    content.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Drops content hashes not posted within the window, then the least
+/// recently posted over `MAX_TRACKED_CONTENT`
+/// Entries are in the order they were last posted so only the front is visited
+fn evict_content(
+    content_hashes: &mut LinkedHashMap<(String, u64), SeenContent>,
+    limits: &DuplicateContent,
+) {
+    let since_time = unix_time().saturating_sub(limits.window);
+    while let Some((_, seen)) = content_hashes.front() {
+        if seen.last_seen > since_time && content_hashes.len() <= MAX_TRACKED_CONTENT {
+            break;
+        }
+        content_hashes.pop_front();
+    }
 }
 
 impl Default for Repo {
@@ -23,13 +212,108 @@ impl Default for Repo {
 
 impl Repo {
     pub fn new(primary: HashSet<String>) -> Self {
+        Self::from_db(Db::new(primary))
+    }
+
+    /// Repo backed by a DB of its own that is gone once the repo is dropped
+    pub fn new_in_memory(primary: HashSet<String>) -> Self {
+        Self::from_db(Db::new_in_memory(primary))
+    }
+
+    /// Repo backed by the DB at `path`
+    pub fn open(path: &str, primary: HashSet<String>) -> Self {
+        Self::from_db(Db::open(path, primary))
+    }
+
+    fn from_db(db: Db) -> Self {
         Repo {
-            db: Arc::new(Mutex::new(Db::new(primary))),
+            db: Arc::new(RwLock::new(db)),
+            content_hashes: Arc::new(Mutex::new(LinkedHashMap::new())),
+            contact_update_mode: ContactUpdateMode::default(),
+            event_window: Arc::new(Mutex::new(None)),
+            max_batch_size: None,
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+            tier_cache: None,
+        }
+    }
+
+    /// Commits bulk writes every `max_batch_size` accounts instead of in one transaction
+    pub fn with_max_batch_size(mut self, max_batch_size: Option<usize>) -> Self {
+        self.max_batch_size = max_batch_size.filter(|max| *max > 0);
+        self
+    }
+
+    /// Caches tier lookups as set by `settings`, off when its size or ttl is 0
+    pub fn with_tier_cache(mut self, settings: &TierCacheSettings) -> Self {
+        if settings.size == 0 || settings.ttl_secs == 0 {
+            return self;
         }
+        let cache = Arc::new(TierCache::new(
+            settings.size,
+            Duration::from_secs(settings.ttl_secs),
+        ));
+        self.db.write().unwrap().set_tier_cache(cache.clone());
+        self.tier_cache = Some(cache);
+        self
+    }
+
+    /// Sends the tier changes follow updates make to `sender`
+    pub fn with_tier_changes(self, sender: mpsc::UnboundedSender<TierChange>) -> Self {
+        self.db.write().unwrap().set_tier_changes(sender);
+        self
+    }
+
+    /// Only count a primary's follow if it is followed back
+    pub fn with_mutual_primary_follows(self, mutual: bool) -> Self {
+        self.db.write().unwrap().set_mutual_primary_follows(mutual);
+        self
     }
 
+    /// Recomputes every tier from the follow graph
+    pub fn recompute_tiers(&self) -> Result<(), Error> {
+        self.write()?.recompute_tiers()
+    }
+
+    /// Sets how contact list updates are applied
+    pub fn with_contact_update_mode(mut self, mode: ContactUpdateMode) -> Self {
+        self.contact_update_mode = mode;
+        self
+    }
+
+    /// Shared access to the DB, failing instead of panicking if a previous holder panicked
+    fn read(&self) -> Result<RwLockReadGuard<'_, Db>, Error> {
+        self.db.read().map_err(|_| Error::LockPoisoned)
+    }
+
+    /// Exclusive access to the DB for anything that writes
+    fn write(&self) -> Result<RwLockWriteGuard<'_, Db>, Error> {
+        self.db.write().map_err(|_| Error::LockPoisoned)
+    }
+
+    /// Poisons the DB lock so every later DB call fails
+    #[cfg(test)]
+    pub(crate) fn poison(&self) {
+        let db = self.db.clone();
+        let _ = std::thread::spawn(move || {
+            let _guard = db.write().unwrap();
+            panic!("poisoning db lock");
+        })
+        .join();
+    }
+
+    /// Sets the tier of `keys`, committing every `max_batch_size` keys
     pub async fn set_tier(&self, keys: &HashSet<String>, tier: Tier) -> Result<(), Error> {
-        self.db.lock().unwrap().set_tier(keys, tier)
+        let max = match self.max_batch_size {
+            Some(max) if keys.len() > max => max,
+            _ => return self.write()?.set_tier(keys, tier),
+        };
+
+        let keys: Vec<&String> = keys.iter().collect();
+        for chunk in keys.chunks(max) {
+            let chunk: HashSet<String> = chunk.iter().map(|k| k.to_string()).collect();
+            self.write()?.set_tier(&chunk, tier)?;
+        }
+        Ok(())
     }
 
     pub async fn set_contact_list(
@@ -37,53 +321,339 @@ impl Repo {
         pubkey: &str,
         contacts: &HashSet<String>,
     ) -> Result<(), Error> {
-        self.db.lock().unwrap().set_contact_list(pubkey, contacts)
+        self.write()?.set_contact_list(pubkey, contacts)
     }
 
     pub fn add_account(&self, account: &Account) -> Result<(), Error> {
-        self.db.lock().unwrap().write_account(account)
+        self.write()?.write_account(account)
     }
 
     pub fn get_account(&self, pubkey: &str) -> Result<Option<Account>, Error> {
-        self.db.lock().unwrap().read_account(pubkey)
+        self.read()?.read_account(pubkey)
     }
 
     pub fn get_account_tier(&self, pubkey: &str) -> Result<Tier, Error> {
-        if let Some(account) = self.get_account(pubkey)? {
-            Ok(account.tier)
-        } else {
-            Ok(Tier::Other)
+        Ok(self.get_tier_with_source(pubkey)?.0)
+    }
+
+    /// Tier of `pubkey` and why it has that tier
+    pub fn get_tier_with_source(&self, pubkey: &str) -> Result<(Tier, TierSource), Error> {
+        let cache = match &self.tier_cache {
+            Some(cache) => cache,
+            None => return self.read_tier_with_source(pubkey),
+        };
+        if let Some(found) = cache.get(pubkey) {
+            return Ok(found);
+        }
+        // Taken before reading so a write landing in between keeps the tier out
+        let generation = cache.generation();
+        let found = self.read_tier_with_source(pubkey)?;
+        cache.insert(pubkey, generation, found);
+        Ok(found)
+    }
+
+    fn read_tier_with_source(&self, pubkey: &str) -> Result<(Tier, TierSource), Error> {
+        let db = self.read()?;
+        // Configured primaries may not be seeded in the db yet
+        if db.is_primary(pubkey) {
+            return Ok((Tier::Primary, TierSource::Primary));
+        }
+        if let Some(tier) = db.read_pin(pubkey)? {
+            return Ok((tier, TierSource::Pinned));
+        }
+        match db.read_account(pubkey)? {
+            Some(account) => Ok((account.tier, TierSource::Computed)),
+            None => Ok((Tier::Other, TierSource::Default)),
+        }
+    }
+
+    /// Pins `pubkey` to `tier` regardless of its place in the graph
+    /// Pins are kept when the graph is cleared or recomputed and its follows
+    /// are tiered from the pinned tier
+    pub fn pin_tier(&self, pubkey: &str, tier: Tier) -> Result<(), Error> {
+        let db = self.write()?;
+        db.write_pin(pubkey, tier)?;
+        db.recompute_tiers()
+    }
+
+    pub fn unpin_tier(&self, pubkey: &str) -> Result<(), Error> {
+        let db = self.write()?;
+        db.remove_pin(pubkey)?;
+        db.recompute_tiers()
+    }
+
+    /// Every pinned account and its tier
+    pub fn pins(&self) -> Result<HashMap<String, Tier>, Error> {
+        self.read()?.read_pins()
+    }
+
+    /// Blocks `pubkey` from publishing whatever its tier
+    /// Blocks are kept when the graph is cleared or recomputed
+    pub fn block(&self, pubkey: &str) -> Result<(), Error> {
+        self.write()?.write_block(pubkey)
+    }
+
+    pub fn unblock(&self, pubkey: &str) -> Result<(), Error> {
+        self.write()?.remove_block(pubkey)
+    }
+
+    /// Whether `pubkey` is in the configured blocklist or blocked in the db
+    pub fn is_blocked(&self, settings: &Settings, pubkey: &str) -> Result<bool, Error> {
+        if settings.info.blocklist.contains(pubkey) {
+            return Ok(true);
         }
+        self.read()?.is_blocked(pubkey)
+    }
+
+    /// Tier, limits, graph position and lists of `pubkey` in one place for debugging
+    pub fn account_summary(
+        &self,
+        settings: &Settings,
+        pubkey: &str,
+    ) -> Result<AccountSummary, Error> {
+        let (tier, source) = self.get_tier_with_source(pubkey)?;
+        let limitation = settings.limitation(tier);
+        let (remaining_hour, remaining_day, remaining_month) =
+            self.remaining_quota(pubkey, &limitation)?;
+        let (follower_count, follow_count) = self.follow_counts(pubkey)?;
+        let (pinned, last_seen) = {
+            let db = self.read()?;
+            (
+                db.read_pin(pubkey)?,
+                db.get_events(pubkey)?.into_iter().max(),
+            )
+        };
+
+        Ok(AccountSummary {
+            tier,
+            source,
+            can_publish: limitation.can_publish,
+            remaining_hour,
+            remaining_day,
+            remaining_month,
+            follower_count,
+            follow_count,
+            first_seen: self.first_seen(pubkey)?,
+            last_seen,
+            allowlisted: settings.info.allowlist.contains(pubkey),
+            blocklisted: self.is_blocked(settings, pubkey)?,
+            pinned,
+        })
+    }
+
+    /// Tier of `pubkey` and the limitation configured for it
+    pub fn resolve_policy(
+        &self,
+        settings: &Settings,
+        pubkey: &str,
+    ) -> Result<(Tier, Limitation), Error> {
+        let tier = self.get_account_tier(pubkey)?;
+        Ok((tier, settings.limitation(tier)))
+    }
+
+    pub fn get_all_accounts(&self) -> Result<Vec<Account>, Error> {
+        self.read()?.read_all_accounts()
+    }
+
+    /// Accounts with no follow path from any primary
+    pub fn orphans(&self) -> Result<Vec<String>, Error> {
+        self.read()?.orphans()
+    }
+
+    /// Demotes accounts none of whose followers vouch for their tier
+    pub fn demote_unvouched(&self) -> Result<Vec<String>, Error> {
+        self.write()?.demote_unvouched()
+    }
+
+    /// When `pubkey` was first seen publishing or in the graph
+    pub fn first_seen(&self, pubkey: &str) -> Result<Option<u64>, Error> {
+        self.read()?.read_first_seen(pubkey)
+    }
+
+    /// Appends a denied event of `pubkey` to the audit log
+    pub fn add_denial(
+        &self,
+        pubkey: &str,
+        tier: Tier,
+        kind: u64,
+        message: &str,
+    ) -> Result<(), Error> {
+        self.write()?.write_denial(&Denial {
+            timestamp: unix_time(),
+            pubkey: pubkey.to_string(),
+            tier,
+            kind,
+            message: message.to_string(),
+        })
     }
 
-    pub fn get_all_accounts(&self) -> Result<(), Error> {
-        self.db.lock().unwrap().read_all_accounts()
+    /// Latest `limit` denials of `pubkey` in the audit log, newest first
+    pub fn recent_denials(&self, pubkey: &str, limit: usize) -> Result<Vec<Denial>, Error> {
+        self.read()?.read_denials(pubkey, limit)
     }
 
     pub fn add_event(&self, author: &str) -> Result<(), Error> {
-        self.db.lock().unwrap().write_event(author, unix_time())
+        self.write()?.write_event(author, unix_time())
     }
 
+    /// Records `bytes` of content published by `author`
+    pub fn add_bytes(&self, author: &str, bytes: usize) -> Result<(), Error> {
+        self.write()?.write_bytes(author, unix_time(), bytes as u64)
+    }
+
+    /// Drops event timestamps no rate limit looks at any more
+    pub fn prune_events(&self, retention: u64) -> Result<usize, Error> {
+        let cutoff = unix_time().saturating_sub(retention);
+        self.write()?.prune_events(cutoff)
+    }
+
+    /// Records that `pubkey` published an event of `kind`
+    pub fn add_kind(&self, author: &str, kind: u64) -> Result<(), Error> {
+        self.write()?.write_kind(author, kind, unix_time())
+    }
+
+    /// Checks that a new kind would not take the account over its distinct kind limit
+    /// Kinds already published within the window are always allowed
+    pub fn check_distinct_kinds(
+        &self,
+        limits: &Limitation,
+        pubkey: &str,
+        kind: u64,
+    ) -> Result<bool, Error> {
+        if let Some(max_kinds) = limits.max_distinct_kinds {
+            let since_time = unix_time().saturating_sub(DISTINCT_KINDS_WINDOW);
+            let kinds = self.read()?.get_kinds(pubkey, since_time)?;
+            info!("Distinct kinds past day: {} for {pubkey}", kinds.len());
+            if !kinds.contains(&kind) && kinds.len() >= max_kinds {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Remaining hourly, daily and monthly events for `pubkey` without recording anything
+    /// `None` where there is no limit
+    pub fn remaining_quota(
+        &self,
+        pubkey: &str,
+        limits: &Limitation,
+    ) -> Result<RemainingQuota, Error> {
+        // Nothing to count down, also in bucket mode
+        if !limits.limits_events() {
+            return Ok((None, None, None));
+        }
+        let db = self.read()?;
+        let remaining = |max: Option<usize>, range: u64| -> Result<Option<usize>, Error> {
+            Ok(match max {
+                Some(max) => Some(max.saturating_sub(db.count_events_in_range(pubkey, range)?)),
+                None => None,
+            })
+        };
+
+        Ok((
+            remaining(limits.events_per_hour, 3600)?,
+            remaining(limits.events_per_day, 86400)?,
+            remaining(limits.events_per_month, MONTH)?,
+        ))
+    }
+
+    /// Lifts rate limits for `tiers` for the next `duration` seconds
+    /// Replaces any window already open, returns when the window expires
+    pub fn open_event_window(&self, duration: u64, tiers: HashSet<Tier>) -> u64 {
+        let expires_at = unix_time() + duration;
+        info!("Event window open for {tiers:?} until {expires_at}");
+        *self.event_window.lock().unwrap() = Some(EventWindow { tiers, expires_at });
+        expires_at
+    }
+
+    /// Whether `pubkey` is in a tier exempt from rate limits by an open event window
+    fn in_event_window(&self, pubkey: &str) -> Result<bool, Error> {
+        let window = self.event_window.lock().unwrap().clone();
+        match window {
+            Some(window) if window.expires_at > unix_time() => {
+                Ok(window.tiers.contains(&self.get_account_tier(pubkey)?))
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Whether `pubkey` may publish an event with `size` content bytes
+    /// under the rate and byte limits of its tier
     pub async fn check_rate_limits(
         &self,
         limits: &Limitation,
         pubkey: &str,
-    ) -> Result<(bool, Option<String>), Error> {
-        if limits.events_per_day.is_some() || limits.events_per_day.is_some() {
-            let events = self.db.lock().unwrap().get_events(pubkey)?;
+        size: usize,
+    ) -> Result<(bool, Option<LimitExceeded>), Error> {
+        if limits.unlimited {
+            return Ok((true, None));
+        }
+
+        if limits.is_rate_limited() && self.in_event_window(pubkey)? {
+            debug!("{pubkey} exempt from rate limits by event window");
+            return Ok((true, None));
+        }
+
+        if limits.limits_events() {
+            let db = self.read()?;
+            if let Some(max_per_month) = limits.events_per_month {
+                let past_month = db.count_events_in_range(pubkey, MONTH)?;
+                info!("Events past month: {past_month} for {pubkey}");
+                if past_month >= max_per_month {
+                    return Ok((false, Some(LimitExceeded::rate("30 days limit exhausted"))));
+                }
+            }
+
             if let Some(max_per_day) = limits.events_per_day {
-                let past_day = count_events_in_range(&events, 86400);
+                let past_day = db.count_events_in_range(pubkey, 86400)?;
                 info!("Events past day: {past_day} for {pubkey}");
-                if past_day > max_per_day {
-                    return Ok((false, Some("24 hours limit exhausted".to_string())));
+                if past_day >= max_per_day {
+                    return Ok((false, Some(LimitExceeded::rate("24 hours limit exhausted"))));
                 }
             }
 
             if let Some(max_per_hour) = limits.events_per_hour {
-                let past_hour = count_events_in_range(&events, 3600);
+                let past_hour = db.count_events_in_range(pubkey, 3600)?;
                 info!("Events past hour: {past_hour} for {pubkey}");
-                if past_hour > max_per_hour {
-                    return Ok((false, Some("Hour limit exhausted".to_string())));
+                if past_hour >= max_per_hour {
+                    return Ok((false, Some(LimitExceeded::rate("Hour limit exhausted"))));
+                }
+            }
+        }
+
+        if let Some((per_hour, burst)) = limits.token_bucket() {
+            let db = self.read()?;
+            let bucket = refilled_bucket(&db, pubkey, per_hour, burst)?;
+            debug!("{} tokens left for {pubkey}", bucket.tokens);
+            if bucket.tokens < 1.0 {
+                return Ok((false, Some(LimitExceeded::rate("Rate limit exhausted"))));
+            }
+        }
+
+        if limits.limits_bytes() {
+            let now = unix_time();
+            let db = self.read()?;
+            for (max_bytes, range, name) in [
+                (limits.bytes_per_day, 86400, "24 hours"),
+                (limits.bytes_per_hour, 3600, "Hour"),
+            ] {
+                if let Some(max_bytes) = max_bytes {
+                    let bytes = db.get_bytes(pubkey, now.saturating_sub(range))?;
+                    info!("Bytes past {range}s: {bytes} for {pubkey}");
+                    // The incoming event counts toward the limit
+                    let bytes = bytes + size as u64;
+                    if bytes > max_bytes {
+                        let over = bytes - max_bytes;
+                        let message = format!("{name} byte limit exceeded by {over} bytes");
+                        return Ok((
+                            false,
+                            Some(LimitExceeded {
+                                reason: DenyReason::ByteLimit,
+                                message,
+                            }),
+                        ));
+                    }
                 }
             }
         }
@@ -91,42 +661,383 @@ impl Repo {
         Ok((true, None))
     }
 
+    /// Takes a token from the bucket of `pubkey` for a published event
+    /// Returns false, taking nothing, when the bucket is empty
+    /// Always true unless `limits` is in bucket mode
+    pub fn take_token(&self, limits: &Limitation, pubkey: &str) -> Result<bool, Error> {
+        let (per_hour, burst) = match limits.token_bucket() {
+            Some(bucket) => bucket,
+            None => return Ok(true),
+        };
+        if limits.unlimited || self.in_event_window(pubkey)? {
+            return Ok(true);
+        }
+        // Refilled, checked and taken under one lock so concurrent events
+        // can not spend the same token
+        let db = self.write()?;
+        let bucket = refilled_bucket(&db, pubkey, per_hour, burst)?;
+        if bucket.tokens < 1.0 {
+            return Ok(false);
+        }
+        let bucket = TokenBucket {
+            tokens: bucket.tokens - 1.0,
+            ..bucket
+        };
+        db.write_bucket(pubkey, &bucket)?;
+        Ok(true)
+    }
+
+    pub fn get_counters(&self) -> Result<HashMap<String, u64>, Error> {
+        self.read()?.read_counters()
+    }
+
+    pub fn add_counters(&self, counts: &HashMap<String, u64>) -> Result<(), Error> {
+        self.write()?.add_counters(counts)
+    }
+
+    /// Whether `content` has been repeated by `pubkey` no more than `max_dupes`
+    /// times within the window
+    pub fn allows_content(&self, limits: &DuplicateContent, pubkey: &str, content: &str) -> bool {
+        if !limits.enabled {
+            return true;
+        }
+
+        let key = (pubkey.to_string(), content_hash(content));
+        let mut content_hashes = self.content_hashes.lock().unwrap();
+        evict_content(&mut content_hashes, limits);
+        let repeats = content_hashes.get(&key).map_or(0, |seen| seen.count);
+        debug!("Content repeated {repeats} times for {pubkey}");
+        repeats <= limits.max_dupes
+    }
+
+    /// Counts permitted `content` for `pubkey`, the count is dropped once the
+    /// content has not been posted for the window
+    pub fn record_content(&self, limits: &DuplicateContent, pubkey: &str, content: &str) {
+        if !limits.enabled {
+            return;
+        }
+
+        let key = (pubkey.to_string(), content_hash(content));
+        let mut content_hashes = self.content_hashes.lock().unwrap();
+        evict_content(&mut content_hashes, limits);
+        let count = content_hashes.get(&key).map_or(0, |seen| seen.count) + 1;
+        // Moves the entry to the back as the most recently posted
+        content_hashes.insert(
+            key,
+            SeenContent {
+                count,
+                last_seen: unix_time(),
+            },
+        );
+        evict_content(&mut content_hashes, limits);
+    }
+
+    /// Counts an event of `pubkey` as in flight while the returned guard lives
+    /// `None` when `max` of its events are already in flight
+    pub fn begin_publish(&self, pubkey: &str, max: usize) -> Option<InFlight> {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        let count = in_flight.entry(pubkey.to_string()).or_default();
+        if *count >= max {
+            if *count == 0 {
+                in_flight.remove(pubkey);
+            }
+            return None;
+        }
+        *count += 1;
+        Some(InFlight {
+            pubkey: pubkey.to_string(),
+            in_flight: self.in_flight.clone(),
+        })
+    }
+
     pub async fn update_contacts(
         &self,
         pubkey: &str,
         contacts: HashSet<String>,
     ) -> Result<(), Error> {
-        self.db
-            .lock()
-            .unwrap()
-            .update_contact_list(pubkey, &contacts)
+        let db = self.write()?;
+        self.apply_contacts(&db, pubkey, &contacts)
+    }
+
+    /// Applies the contact list of `pubkey` published at `created_at`
+    /// Like any replaceable event a list not newer than the last one applied
+    /// is ignored, returns false when it was
+    pub fn update_contacts_at(
+        &self,
+        pubkey: &str,
+        contacts: HashSet<String>,
+        created_at: u64,
+    ) -> Result<bool, Error> {
+        let db = self.write()?;
+        if let Some(applied_at) = db.read_contact_list_time(pubkey)? {
+            if created_at <= applied_at {
+                debug!("Ignoring contact list of {pubkey} from {created_at}, have {applied_at}");
+                return Ok(false);
+            }
+        }
+        self.apply_contacts(&db, pubkey, &contacts)?;
+        db.write_contact_list_time(pubkey, created_at)?;
+        Ok(true)
+    }
+
+    /// Records `id` as the event of the contact list applied for `pubkey`
+    pub fn set_contact_list_id(&self, pubkey: &str, id: &str) -> Result<(), Error> {
+        self.write()?.write_contact_list_id(pubkey, Some(id))
+    }
+
+    /// Drops the follows and relay hints of `pubkey` if its applied contact
+    /// list is one of `ids`, whatever the contact update mode
+    /// Returns false when it is not
+    pub fn delete_contact_list(&self, pubkey: &str, ids: &HashSet<String>) -> Result<bool, Error> {
+        let db = self.write()?;
+        match db.read_contact_list_id(pubkey)? {
+            Some(id) if ids.contains(&id) => (),
+            _ => return Ok(false),
+        }
+        // The time is kept so the deleted list is not applied again if rebroadcast
+        db.update_contact_list(pubkey, &HashSet::new())?;
+        db.write_relay_hints(pubkey, &HashMap::new())?;
+        db.write_contact_list_id(pubkey, None)?;
+        Ok(true)
+    }
+
+    /// Relay hints `pubkey` gave for its follows, by followee
+    pub fn relay_hints(&self, pubkey: &str) -> Result<HashMap<String, String>, Error> {
+        self.read()?.read_relay_hints(pubkey)
+    }
+
+    /// Stores relay hints of `pubkey` for those it follows, dropping hints
+    /// for accounts it no longer follows
+    pub fn set_relay_hints(
+        &self,
+        pubkey: &str,
+        hints: &HashMap<String, String>,
+    ) -> Result<(), Error> {
+        self.write()?.write_relay_hints(pubkey, hints)
+    }
+
+    /// Stores the relay list of `pubkey` unless one at least as new is stored
+    /// Returns whether it was stored
+    pub fn set_relay_list(&self, pubkey: &str, relay_list: &RelayList) -> Result<bool, Error> {
+        let db = self.write()?;
+        if let Some(stored) = db.read_relay_list(pubkey)? {
+            if stored.created_at >= relay_list.created_at {
+                return Ok(false);
+            }
+        }
+        db.write_relay_list(pubkey, relay_list)?;
+        Ok(true)
+    }
+
+    /// Write relays of every one of `pubkeys` with a stored relay list
+    pub fn write_relays(&self, pubkeys: &HashSet<String>) -> Result<HashSet<String>, Error> {
+        let db = self.read()?;
+        let mut relays = HashSet::new();
+        for pubkey in pubkeys {
+            if let Some(relay_list) = db.read_relay_list(pubkey)? {
+                relays.extend(relay_list.write);
+            }
+        }
+        Ok(relays)
+    }
+
+    fn apply_contacts(
+        &self,
+        db: &Db,
+        pubkey: &str,
+        contacts: &HashSet<String>,
+    ) -> Result<(), Error> {
+        match self.contact_update_mode {
+            ContactUpdateMode::Replace => db.update_contact_list(pubkey, contacts),
+            ContactUpdateMode::Merge => db.merge_contact_list(pubkey, contacts),
+        }
+    }
+
+    /// Imports newline delimited JSON contact lists, one `{"pubkey", "follows"}` per line
+    /// Lines are applied in batches of `batch_size` so memory stays bounded
+    /// Batches are capped at `max_batch_size` when it is set
+    /// Returns the number of contact lists imported
+    pub async fn import_follows_stream<R: BufRead>(
+        &self,
+        reader: R,
+        batch_size: usize,
+    ) -> Result<usize, Error> {
+        let batch_size = self
+            .max_batch_size
+            .map_or(batch_size, |max| batch_size.min(max))
+            .max(1);
+        let mut imported = 0;
+        let mut batch = Vec::with_capacity(batch_size);
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record: FollowsRecord = serde_json::from_str(&line)?;
+            batch.push((record.pubkey, record.follows));
+
+            if batch.len() >= batch_size {
+                imported += batch.len();
+                self.write()?.write_contact_lists(&batch)?;
+                batch.clear();
+            }
+        }
+        imported += batch.len();
+        let db = self.write()?;
+        db.write_contact_lists(&batch)?;
+        db.recompute_tiers()?;
+
+        info!("Imported {imported} contact lists");
+        Ok(imported)
+    }
+
+    /// Writes `pubkey,timestamp` event rows as CSV
+    /// Returns the number of rows written
+    pub fn export_events_csv<W: Write>(
+        &self,
+        mut writer: W,
+        pubkey: Option<&str>,
+        range: Option<RangeInclusive<u64>>,
+    ) -> Result<usize, Error> {
+        let mut events = self.read()?.read_all_events(pubkey)?;
+        events.retain(|(_, t)| range.as_ref().is_none_or(|r| r.contains(t)));
+        events.sort();
+
+        writeln!(writer, "pubkey,timestamp")?;
+        for (pubkey, timestamp) in &events {
+            writeln!(writer, "{pubkey},{timestamp}")?;
+        }
+        writer.flush()?;
+        Ok(events.len())
+    }
+
+    /// Number of direct followers of `pubkey` and accounts it follows
+    pub fn follow_counts(&self, pubkey: &str) -> Result<(usize, usize), Error> {
+        let db = self.read()?;
+        Ok((
+            db.get_followers(pubkey)?.len(),
+            db.get_follows(pubkey)?.len(),
+        ))
+    }
+
+    /// Every (follower, followee) edge
+    pub fn all_follow_edges(&self) -> Result<Vec<(String, String)>, Error> {
+        self.read()?.all_follow_edges()
+    }
+
+    /// Follow edges in chunks of about `chunk_size`, locking the db per chunk
+    pub fn follow_edges_chunks(&self, chunk_size: usize) -> FollowEdges {
+        FollowEdges {
+            repo: self.clone(),
+            after: None,
+            chunk_size,
+            done: false,
+        }
+    }
+
+    /// Accounts less than `hops` hops from a primary, the accounts whose
+    /// contact lists the graph is built from
+    pub fn pubkeys_within(&self, hops: u8) -> Result<HashSet<String>, Error> {
+        let tier = (0..hops).fold(Tier::Primary, |tier, _| tier.raise_tier());
+        self.read()?.pubkeys_before(tier)
+    }
+
+    pub fn count_by_tier(&self) -> Result<BTreeMap<Tier, usize>, Error> {
+        self.read()?.count_by_tier()
+    }
+
+    /// Pubkeys of the accounts at `tier`
+    pub fn accounts_by_tier(&self, tier: Tier) -> Result<Vec<String>, Error> {
+        self.read()?.accounts_by_tier(tier)
+    }
+
+    pub fn count_follows(&self) -> Result<usize, Error> {
+        self.read()?.count_follows()
+    }
+
+    pub fn graph_built_at(&self) -> Result<Option<u64>, Error> {
+        self.read()?.read_graph_built_at()
+    }
+
+    /// Records that the graph was built from the relays now
+    pub fn set_graph_built(&self) -> Result<(), Error> {
+        self.write()?.write_graph_built_at(unix_time())
+    }
+
+    /// Size in bytes of the DB file
+    pub fn db_size(&self) -> Result<u64, Error> {
+        self.read()?.file_size()
+    }
+
+    /// Accounts with their tiers and every follow edge
+    pub fn export_graph(&self) -> Result<GraphSnapshot, Error> {
+        self.read()?.read_snapshot()
+    }
+
+    /// Replaces the accounts and follows with those of `snapshot`
+    pub async fn import_graph(&self, snapshot: &GraphSnapshot) -> Result<(), Error> {
+        self.write()?.write_snapshot(snapshot)?;
+        info!(
+            "Imported {} accounts and {} follows",
+            snapshot.accounts.len(),
+            snapshot.follows.len()
+        );
+        Ok(())
+    }
+
+    /// Compares the live graph to the snapshot saved at `snapshot_path`
+    pub fn diff_against(&self, snapshot_path: &str) -> Result<GraphDiff, Error> {
+        let snapshot = GraphSnapshot::load(snapshot_path)?;
+        Ok(self.export_graph()?.diff(&snapshot))
+    }
+
+    /// Rewrites the DB file to reclaim the space of pruned and removed entries
+    /// Holds the exclusive lock for the whole rewrite so admission waits on it
+    pub fn compact(&self) -> Result<(u64, u64), Error> {
+        let mut db = self.write()?;
+        info!("Compacting DB");
+        let (before, after) = db.compact()?;
+        info!("DB compacted from {before} to {after} bytes");
+        Ok((before, after))
     }
 
     /// Clears account tables
     pub async fn clear_accounts(&self) -> Result<(), Error> {
-        self.db.lock().unwrap().clear_tables()
+        self.write()?.clear_tables()
     }
 }
 
-fn count_events_in_range(events: &[u64], range: u64) -> usize {
-    let since_time = unix_time() - range;
-    events.iter().filter(|&t| *t > since_time).count()
+/// Bucket of `pubkey` refilled to now, full if it has none stored
+fn refilled_bucket(db: &Db, pubkey: &str, per_hour: u64, burst: u64) -> Result<TokenBucket, Error> {
+    let now = unix_time();
+    let full = TokenBucket {
+        tokens: burst as f64,
+        refilled_at: now,
+    };
+    let bucket = db.read_bucket(pubkey)?.unwrap_or(full);
+    Ok(bucket.refill(per_hour, burst, now))
 }
 
 #[cfg(test)]
 mod tests {
 
-    use serial_test::serial;
-
     use super::*;
+    use crate::config::RateLimitMode;
+    use crate::test_utils::temp_path;
+
+    fn bytes_exceeded(message: &str) -> LimitExceeded {
+        LimitExceeded {
+            reason: DenyReason::ByteLimit,
+            message: message.to_string(),
+        }
+    }
 
     #[test]
-    #[serial]
     fn test_set_get_account() {
         let _primary_acounts = HashSet::from([
             "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9".to_string(),
         ]);
-        let repo = Repo::new(HashSet::new());
+        let repo = Repo::new_in_memory(HashSet::new());
         let pubkey = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9";
         let account = Account {
             pubkey: pubkey.to_string(),
@@ -139,9 +1050,8 @@ mod tests {
     }
 
     #[test]
-    #[serial]
     fn test_get_account_tier() {
-        let repo = Repo::new(HashSet::new());
+        let repo = Repo::new_in_memory(HashSet::new());
         let pubkey = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9";
         let account = Account {
             pubkey: pubkey.to_string(),
@@ -152,4 +1062,981 @@ mod tests {
 
         assert_eq!(Tier::Primary, account_tier);
     }
+
+    #[test]
+    fn test_poisoned_db() {
+        let pubkey = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9";
+        let repo = Repo::new_in_memory(HashSet::new());
+        repo.poison();
+
+        assert!(matches!(
+            repo.get_account_tier(pubkey),
+            Err(Error::LockPoisoned)
+        ));
+        assert!(repo.add_event(pubkey).is_err());
+    }
+
+    #[test]
+    fn test_concurrent_reads() {
+        let pubkey = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9";
+        let repo = Repo::new_in_memory(HashSet::new());
+
+        // A read in progress does not block another
+        let _reading = repo.read().unwrap();
+        let other = repo.clone();
+        let tier = std::thread::spawn(move || other.get_account_tier(pubkey))
+            .join()
+            .unwrap();
+        assert_eq!(Tier::Other, tier.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_tier_with_source() {
+        let a = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9";
+        let b = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203";
+        let c = "7c27a04b7c27299f16dc07d3eb8f28544f188bc7a34982328b7d581edc405dc2";
+        let d = "5b3a49bcbdf41f511f5c9034dbe46240863b73b39a2fdfebfb611f23b88d3922";
+        let repo = Repo::new_in_memory(HashSet::from([a.into()]));
+        repo.set_tier(&HashSet::from([a.to_string()]), Tier::Primary)
+            .await
+            .unwrap();
+        repo.update_contacts(a, HashSet::from([b.to_string(), c.to_string()]))
+            .await
+            .unwrap();
+        repo.pin_tier(c, Tier::Quaternary).unwrap();
+
+        assert_eq!(
+            (Tier::Primary, TierSource::Primary),
+            repo.get_tier_with_source(a).unwrap()
+        );
+        assert_eq!(
+            (Tier::Secondary, TierSource::Computed),
+            repo.get_tier_with_source(b).unwrap()
+        );
+        assert_eq!(
+            (Tier::Quaternary, TierSource::Pinned),
+            repo.get_tier_with_source(c).unwrap()
+        );
+        assert_eq!(
+            (Tier::Other, TierSource::Default),
+            repo.get_tier_with_source(d).unwrap()
+        );
+
+        repo.unpin_tier(c).unwrap();
+        assert_eq!(
+            (Tier::Secondary, TierSource::Computed),
+            repo.get_tier_with_source(c).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pins_survive_recompute() {
+        let a = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9";
+        let b = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203";
+        let c = "7c27a04b7c27299f16dc07d3eb8f28544f188bc7a34982328b7d581edc405dc2";
+        let d = "5b3a49bcbdf41f511f5c9034dbe46240863b73b39a2fdfebfb611f23b88d3922";
+        let temp = temp_path(".redb");
+        let path = temp.to_str().unwrap();
+        let primary = HashSet::from([a.to_string()]);
+        {
+            let repo = Repo::open(path, primary.clone());
+            repo.set_tier(&primary, Tier::Primary).await.unwrap();
+            repo.update_contacts(a, HashSet::from([b.to_string()]))
+                .await
+                .unwrap();
+            repo.update_contacts(b, HashSet::from([c.to_string()]))
+                .await
+                .unwrap();
+            repo.update_contacts(c, HashSet::from([d.to_string()]))
+                .await
+                .unwrap();
+
+            // Hard promote C and ban B
+            repo.pin_tier(c, Tier::Secondary).unwrap();
+            repo.pin_tier(b, Tier::Other).unwrap();
+            repo.recompute_tiers().unwrap();
+            assert_eq!(Tier::Secondary, repo.get_account_tier(c).unwrap());
+            assert_eq!(Tier::Tertiary, repo.get_account_tier(d).unwrap());
+            assert_eq!(Tier::Other, repo.get_account_tier(b).unwrap());
+
+            // A new follower does not lift a pinned account
+            repo.update_contacts(a, HashSet::from([b.to_string(), d.to_string()]))
+                .await
+                .unwrap();
+            assert_eq!(Tier::Other, repo.get_account_tier(b).unwrap());
+            assert_eq!(Tier::Secondary, repo.get_account_tier(d).unwrap());
+        }
+
+        // Pins are kept across restarts and graph rebuilds
+        let repo = Repo::open(path, primary.clone());
+        repo.clear_accounts().await.unwrap();
+        assert_eq!(2, repo.pins().unwrap().len());
+        repo.set_tier(
+            &HashSet::from([b.to_string(), c.to_string()]),
+            Tier::Tertiary,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            Some(Tier::Secondary),
+            repo.get_account(c).unwrap().map(|a| a.tier)
+        );
+        assert_eq!(Tier::Other, repo.get_account_tier(b).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_unseeded_primary() {
+        let pubkey = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9";
+        let repo = Repo::new_in_memory(HashSet::from([pubkey.to_string()]));
+        repo.clear_accounts().await.unwrap();
+
+        assert_eq!(None, repo.get_account(pubkey).unwrap());
+        assert_eq!(Tier::Primary, repo.get_account_tier(pubkey).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_merge_contact_updates() {
+        let a = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9".to_string();
+        let b = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203".to_string();
+        let c = "7c27a04b7c27299f16dc07d3eb8f28544f188bc7a34982328b7d581edc405dc2".to_string();
+        let d = "5b3a49bcbdf41f511f5c9034dbe46240863b73b39a2fdfebfb611f23b88d3922".to_string();
+        let repo = Repo::new_in_memory(HashSet::from([a.clone()]))
+            .with_contact_update_mode(ContactUpdateMode::Merge);
+        repo.set_tier(&HashSet::from([a.clone()]), Tier::Primary)
+            .await
+            .unwrap();
+
+        repo.update_contacts(&a, HashSet::from([b.clone(), c.clone()]))
+            .await
+            .unwrap();
+        // Partial list missing B and C
+        repo.update_contacts(&a, HashSet::from([d.clone()]))
+            .await
+            .unwrap();
+
+        let snapshot = repo.export_graph().unwrap();
+        for follow in [&b, &c, &d] {
+            assert!(snapshot.follows.contains(&(a.clone(), follow.clone())));
+            assert_eq!(Tier::Secondary, repo.get_account_tier(follow).unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_remaining_quota() {
+        let pubkey = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9";
+        let repo = Repo::new_in_memory(HashSet::new());
+        let limits = Limitation {
+            can_publish: true,
+            events_per_hour: Some(2),
+            events_per_day: Some(5),
+            events_per_month: Some(8),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            (None, None, None),
+            repo.remaining_quota(pubkey, &Limitation::default())
+                .unwrap()
+        );
+
+        // Distinct timestamps as events in the same second share a row
+        let now = unix_time();
+        for (n, expected) in [
+            (1, (2, 5, 8)),
+            (2, (1, 4, 7)),
+            (3, (0, 3, 6)),
+            (4, (0, 2, 5)),
+        ] {
+            assert_eq!(
+                (Some(expected.0), Some(expected.1), Some(expected.2)),
+                repo.remaining_quota(pubkey, &limits).unwrap()
+            );
+            assert_eq!(
+                expected.0 > 0,
+                repo.check_rate_limits(&limits, pubkey, 0).await.unwrap().0
+            );
+            repo.write().unwrap().write_event(pubkey, now - n).unwrap();
+        }
+
+        // Events older than a day only count toward the month
+        repo.write()
+            .unwrap()
+            .write_event(pubkey, now - 10 * 86400)
+            .unwrap();
+        assert_eq!(
+            (Some(0), Some(1), Some(3)),
+            repo.remaining_quota(pubkey, &limits).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_hourly_limit_only() {
+        let pubkey = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9";
+        let repo = Repo::new_in_memory(HashSet::new());
+        let limits = Limitation {
+            can_publish: true,
+            events_per_hour: Some(1),
+            ..Default::default()
+        };
+
+        let now = unix_time();
+        for n in 1..=2 {
+            repo.write().unwrap().write_event(pubkey, now - n).unwrap();
+        }
+
+        assert_eq!(
+            (false, Some(LimitExceeded::rate("Hour limit exhausted"))),
+            repo.check_rate_limits(&limits, pubkey, 0).await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stale_contact_list() {
+        let a = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9";
+        let b = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203";
+        let c = "7c27a04b7c27299f16dc07d3eb8f28544f188bc7a34982328b7d581edc405dc2";
+        let repo = Repo::new_in_memory(HashSet::from([a.into()]));
+        repo.set_tier(&HashSet::from([a.to_string()]), Tier::Primary)
+            .await
+            .unwrap();
+
+        let newer = HashSet::from([c.to_string()]);
+        assert!(repo.update_contacts_at(a, newer.clone(), 200).unwrap());
+
+        // Replayed or late lists do not clobber the newer one
+        for created_at in [100, 200] {
+            let stale = HashSet::from([b.to_string()]);
+            assert!(!repo.update_contacts_at(a, stale, created_at).unwrap());
+        }
+        assert_eq!(newer, repo.read().unwrap().get_follows(a).unwrap());
+        assert_eq!(Tier::Secondary, repo.get_account_tier(c).unwrap());
+        assert_eq!(Tier::Other, repo.get_account_tier(b).unwrap());
+
+        assert!(repo
+            .update_contacts_at(a, HashSet::from([b.to_string()]), 300)
+            .unwrap());
+        assert_eq!(Tier::Secondary, repo.get_account_tier(b).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_limit_boundary() {
+        let pubkey = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9";
+        let repo = Repo::new_in_memory(HashSet::new());
+        let limits = Limitation {
+            can_publish: true,
+            events_per_day: Some(10),
+            ..Default::default()
+        };
+
+        // The 10th event is permitted and the 11th denied
+        let now = unix_time();
+        for n in 1..=10 {
+            assert!(repo.check_rate_limits(&limits, pubkey, 0).await.unwrap().0);
+            repo.write()
+                .unwrap()
+                .write_event(pubkey, now - n * 60)
+                .unwrap();
+        }
+        assert_eq!(
+            (false, Some(LimitExceeded::rate("24 hours limit exhausted"))),
+            repo.check_rate_limits(&limits, pubkey, 0).await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_monthly_limit() {
+        let pubkey = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9";
+        let repo = Repo::new_in_memory(HashSet::new());
+        let limits = Limitation {
+            can_publish: true,
+            events_per_day: Some(2),
+            events_per_month: Some(3),
+            ..Default::default()
+        };
+
+        // Spread over the month so the daily limit is never reached
+        let now = unix_time();
+        for days in [20, 10, 2] {
+            assert!(repo.check_rate_limits(&limits, pubkey, 0).await.unwrap().0);
+            repo.write()
+                .unwrap()
+                .write_event(pubkey, now - days * 86400)
+                .unwrap();
+        }
+        assert_eq!(
+            (false, Some(LimitExceeded::rate("30 days limit exhausted"))),
+            repo.check_rate_limits(&limits, pubkey, 0).await.unwrap()
+        );
+
+        // Kept while the longest window needs them
+        assert_eq!(0, repo.prune_events(MONTH).unwrap());
+        assert_eq!(2, repo.prune_events(86400 * 5).unwrap());
+        assert!(repo.check_rate_limits(&limits, pubkey, 0).await.unwrap().0);
+    }
+
+    #[tokio::test]
+    async fn test_byte_limits() {
+        let pubkey = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9";
+        let repo = Repo::new_in_memory(HashSet::new());
+        let limits = Limitation {
+            can_publish: true,
+            bytes_per_hour: Some(1000),
+            bytes_per_day: Some(6000),
+            ..Default::default()
+        };
+
+        let now = unix_time();
+        repo.write()
+            .unwrap()
+            .write_bytes(pubkey, now - 7200, 4000)
+            .unwrap();
+        repo.write()
+            .unwrap()
+            .write_bytes(pubkey, now - 60, 600)
+            .unwrap();
+        assert_eq!(
+            (true, None),
+            repo.check_rate_limits(&limits, pubkey, 400).await.unwrap()
+        );
+        assert_eq!(
+            (
+                false,
+                Some(bytes_exceeded("Hour byte limit exceeded by 1 bytes"))
+            ),
+            repo.check_rate_limits(&limits, pubkey, 401).await.unwrap()
+        );
+
+        // A single event over the limit is denied without any history
+        let other = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203";
+        assert_eq!(
+            (
+                false,
+                Some(bytes_exceeded("Hour byte limit exceeded by 1 bytes"))
+            ),
+            repo.check_rate_limits(&limits, other, 1001).await.unwrap()
+        );
+
+        repo.add_bytes(pubkey, 650).unwrap();
+        assert_eq!(
+            (
+                false,
+                Some(bytes_exceeded("Hour byte limit exceeded by 250 bytes"))
+            ),
+            repo.check_rate_limits(&limits, pubkey, 0).await.unwrap()
+        );
+
+        repo.write()
+            .unwrap()
+            .write_bytes(pubkey, now - 7000, 1000)
+            .unwrap();
+        assert_eq!(
+            (
+                false,
+                Some(bytes_exceeded("24 hours byte limit exceeded by 250 bytes"))
+            ),
+            repo.check_rate_limits(&limits, pubkey, 0).await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_event_window() {
+        let a = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9";
+        let b = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203";
+        let repo = Repo::new_in_memory(HashSet::new());
+        repo.set_tier(&HashSet::from([a.to_string()]), Tier::Secondary)
+            .await
+            .unwrap();
+        repo.set_tier(&HashSet::from([b.to_string()]), Tier::Tertiary)
+            .await
+            .unwrap();
+        let limits = Limitation {
+            can_publish: true,
+            events_per_hour: Some(1),
+            ..Default::default()
+        };
+
+        let now = unix_time();
+        for pubkey in [a, b] {
+            for n in 1..=2 {
+                repo.write().unwrap().write_event(pubkey, now - n).unwrap();
+            }
+            assert!(!repo.check_rate_limits(&limits, pubkey, 0).await.unwrap().0);
+        }
+
+        repo.open_event_window(3600, HashSet::from([Tier::Secondary]));
+        assert!(repo.check_rate_limits(&limits, a, 0).await.unwrap().0);
+        assert!(!repo.check_rate_limits(&limits, b, 0).await.unwrap().0);
+
+        // Expire the window
+        repo.event_window
+            .lock()
+            .unwrap()
+            .as_mut()
+            .unwrap()
+            .expires_at = now;
+        assert!(!repo.check_rate_limits(&limits, a, 0).await.unwrap().0);
+    }
+
+    #[tokio::test]
+    async fn test_tier_cache() {
+        let a = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9";
+        let b = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203";
+        let c = "7c27a04b7c27299f16dc07d3eb8f28544f188bc7a34982328b7d581edc405dc2";
+        let repo = Repo::new_in_memory(HashSet::new()).with_tier_cache(&TierCacheSettings {
+            size: 2,
+            ttl_secs: 60,
+        });
+        let cached = |pubkey: &str| repo.tier_cache.as_ref().unwrap().get(pubkey);
+
+        repo.set_tier(&HashSet::from([a.to_string()]), Tier::Secondary)
+            .await
+            .unwrap();
+        assert_eq!(Tier::Secondary, repo.get_account_tier(a).unwrap());
+        assert_eq!(Some((Tier::Secondary, TierSource::Computed)), cached(a));
+
+        // A demotion is seen on the next lookup
+        repo.set_tier(&HashSet::from([a.to_string()]), Tier::Other)
+            .await
+            .unwrap();
+        assert_eq!(None, cached(a));
+        assert_eq!(Tier::Other, repo.get_account_tier(a).unwrap());
+        repo.pin_tier(a, Tier::Quaternary).unwrap();
+        assert_eq!(Tier::Quaternary, repo.get_account_tier(a).unwrap());
+
+        // Writes that leave a tier as it was keep it cached
+        repo.set_tier(&HashSet::from([b.to_string()]), Tier::Secondary)
+            .await
+            .unwrap();
+        repo.get_account_tier(b).unwrap();
+        repo.set_tier(
+            &HashSet::from([b.to_string(), c.to_string()]),
+            Tier::Secondary,
+        )
+        .await
+        .unwrap();
+        assert_eq!(Some((Tier::Secondary, TierSource::Computed)), cached(b));
+
+        // Bounded by size, the least recently used is dropped
+        for pubkey in [a, b, c] {
+            repo.get_account_tier(pubkey).unwrap();
+        }
+        assert_eq!(None, cached(a));
+        assert!(cached(b).is_some());
+        assert!(cached(c).is_some());
+
+        let off = Repo::new_in_memory(HashSet::new()).with_tier_cache(&TierCacheSettings {
+            size: 2,
+            ttl_secs: 0,
+        });
+        assert!(off.tier_cache.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_token_bucket() {
+        let pubkey = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9";
+        let repo = Repo::new_in_memory(HashSet::new());
+        let limits = Limitation {
+            can_publish: true,
+            events_per_hour: Some(1),
+            rate_limit_mode: RateLimitMode::Bucket,
+            tokens_per_hour: Some(3600),
+            burst: Some(2),
+            ..Default::default()
+        };
+        // Window limits are ignored in bucket mode
+        assert_eq!(
+            repo.remaining_quota(pubkey, &limits).unwrap(),
+            (None, None, None)
+        );
+
+        for _ in 0..2 {
+            assert!(repo.check_rate_limits(&limits, pubkey, 0).await.unwrap().0);
+            assert!(repo.take_token(&limits, pubkey).unwrap());
+        }
+        assert_eq!(
+            repo.check_rate_limits(&limits, pubkey, 0).await.unwrap(),
+            (false, Some(LimitExceeded::rate("Rate limit exhausted")))
+        );
+        assert!(!repo.take_token(&limits, pubkey).unwrap());
+
+        // A token a second refills, never above the burst
+        let bucket = TokenBucket {
+            tokens: 0.0,
+            refilled_at: unix_time() - 10,
+        };
+        repo.write().unwrap().write_bucket(pubkey, &bucket).unwrap();
+        assert!(repo.check_rate_limits(&limits, pubkey, 0).await.unwrap().0);
+        assert!(repo.take_token(&limits, pubkey).unwrap());
+        let tokens = repo
+            .read()
+            .unwrap()
+            .read_bucket(pubkey)
+            .unwrap()
+            .unwrap()
+            .tokens;
+        assert!((1.0..=1.01).contains(&tokens));
+    }
+
+    #[tokio::test]
+    async fn test_account_summary() {
+        let a = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9";
+        let b = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203";
+        let c = "7c27a04b7c27299f16dc07d3eb8f28544f188bc7a34982328b7d581edc405dc2";
+        let repo = Repo::new_in_memory(HashSet::from([a.into()]));
+        repo.set_tier(&HashSet::from([a.to_string()]), Tier::Primary)
+            .await
+            .unwrap();
+        repo.update_contacts(a, HashSet::from([b.to_string()]))
+            .await
+            .unwrap();
+        repo.update_contacts(b, HashSet::from([c.to_string()]))
+            .await
+            .unwrap();
+        repo.pin_tier(b, Tier::Tertiary).unwrap();
+
+        let now = unix_time();
+        for n in [30, 10] {
+            repo.write().unwrap().write_event(b, now - n).unwrap();
+        }
+
+        let mut settings = Settings {
+            tertiary: Limitation {
+                can_publish: true,
+                events_per_hour: Some(5),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        settings.info.allowlist = HashSet::from([b.to_string()]);
+
+        let summary = repo.account_summary(&settings, b).unwrap();
+        assert_eq!(
+            AccountSummary {
+                tier: Tier::Tertiary,
+                source: TierSource::Pinned,
+                can_publish: true,
+                remaining_hour: Some(3),
+                remaining_day: None,
+                remaining_month: None,
+                follower_count: 1,
+                follow_count: 1,
+                first_seen: Some(now - 30),
+                last_seen: Some(now - 10),
+                allowlisted: true,
+                blocklisted: false,
+                pinned: Some(Tier::Tertiary),
+            },
+            summary
+        );
+
+        settings.info.blocklist = HashSet::from([c.to_string()]);
+        let summary = repo.account_summary(&settings, c).unwrap();
+        assert_eq!(
+            (Tier::Quaternary, TierSource::Computed),
+            (summary.tier, summary.source)
+        );
+        assert!(summary.blocklisted);
+        assert!(!summary.allowlisted);
+        assert_eq!(None, summary.last_seen);
+        assert_eq!(None, summary.pinned);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_policy() {
+        let pubkey = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9";
+        let unknown = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203";
+        let repo = Repo::new_in_memory(HashSet::new());
+        repo.clear_accounts().await.unwrap();
+        repo.set_tier(&HashSet::from([pubkey.to_string()]), Tier::Secondary)
+            .await
+            .unwrap();
+
+        let mut settings = Settings {
+            secondary: Limitation {
+                can_publish: true,
+                events_per_hour: Some(10),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        settings.other.can_publish = true;
+        settings.info.other_read_only = true;
+
+        let (tier, limitation) = repo.resolve_policy(&settings, pubkey).unwrap();
+        assert_eq!(Tier::Secondary, tier);
+        assert!(limitation.can_publish);
+        assert_eq!(Some(10), limitation.events_per_hour);
+
+        let (tier, limitation) = repo.resolve_policy(&settings, unknown).unwrap();
+        assert_eq!(Tier::Other, tier);
+        assert!(!limitation.can_publish);
+    }
+
+    #[tokio::test]
+    async fn test_orphans() {
+        let a = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9".to_string();
+        let b = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203".to_string();
+        let c = "7c27a04b7c27299f16dc07d3eb8f28544f188bc7a34982328b7d581edc405dc2".to_string();
+        let d = "5b3a49bcbdf41f511f5c9034dbe46240863b73b39a2fdfebfb611f23b88d3922".to_string();
+        let repo = Repo::new_in_memory(HashSet::from([a.clone()]));
+        repo.clear_accounts().await.unwrap();
+
+        // A -> B -> C connected to the primary, D isolated
+        repo.set_tier(&HashSet::from([a.clone()]), Tier::Primary)
+            .await
+            .unwrap();
+        repo.set_tier(&HashSet::from([d.clone()]), Tier::Tertiary)
+            .await
+            .unwrap();
+        repo.update_contacts(&a, HashSet::from([b.clone()]))
+            .await
+            .unwrap();
+        repo.update_contacts(&b, HashSet::from([c.clone()]))
+            .await
+            .unwrap();
+
+        assert_eq!(vec![d], repo.orphans().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_distinct_kinds() {
+        let repo = Repo::new_in_memory(HashSet::new());
+        repo.clear_accounts().await.unwrap();
+        let pubkey = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9";
+        let limits = Limitation {
+            can_publish: true,
+            max_distinct_kinds: Some(2),
+            ..Default::default()
+        };
+
+        for kind in [1, 7] {
+            assert!(repo.check_distinct_kinds(&limits, pubkey, kind).unwrap());
+            repo.add_kind(pubkey, kind).unwrap();
+        }
+
+        // Novel kind is denied established ones still pass
+        assert!(!repo.check_distinct_kinds(&limits, pubkey, 4).unwrap());
+        assert!(repo.check_distinct_kinds(&limits, pubkey, 1).unwrap());
+        assert!(repo.check_distinct_kinds(&limits, pubkey, 7).unwrap());
+    }
+
+    #[test]
+    fn test_first_seen() {
+        let repo = Repo::new_in_memory(HashSet::new());
+        let pubkey = "5b3a49bcbdf41f511f5c9034dbe46240863b73b39a2fdfebfb611f23b88d3922";
+
+        repo.add_event(pubkey).unwrap();
+        let first_seen = repo.first_seen(pubkey).unwrap().unwrap();
+        assert!(first_seen <= unix_time());
+
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        repo.add_event(pubkey).unwrap();
+        assert_eq!(Some(first_seen), repo.first_seen(pubkey).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_import_follows_stream() {
+        let a = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9";
+        let b = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203";
+        let c = "7c27a04b7c27299f16dc07d3eb8f28544f188bc7a34982328b7d581edc405dc2";
+        let d = "5b3a49bcbdf41f511f5c9034dbe46240863b73b39a2fdfebfb611f23b88d3922";
+        let e = "04918dfc36c93e7db6cc0d60f37e1522f1c36b64d3f4b424c532d7c595febbc5";
+        let repo = Repo::new_in_memory(HashSet::from([a.to_string()]));
+        repo.clear_accounts().await.unwrap();
+
+        let path = std::env::temp_dir().join("contact-group-auth-import.ndjson");
+        let ndjson = format!(
+            "{{\"pubkey\":\"{a}\",\"follows\":[\"{b}\"]}}\n\
+             {{\"pubkey\":\"{b}\",\"follows\":[\"{c}\"]}}\n\
+             \n\
+             {{\"pubkey\":\"{c}\",\"follows\":[\"{d}\"]}}\n\
+             {{\"pubkey\":\"{d}\",\"follows\":[\"{e}\"]}}\n"
+        );
+        std::fs::write(&path, ndjson).unwrap();
+
+        let file = std::io::BufReader::new(std::fs::File::open(&path).unwrap());
+        let imported = repo.import_follows_stream(file, 2).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(4, imported);
+        assert_eq!(Tier::Primary, repo.get_account_tier(a).unwrap());
+        assert_eq!(Tier::Secondary, repo.get_account_tier(b).unwrap());
+        assert_eq!(Tier::Tertiary, repo.get_account_tier(c).unwrap());
+        assert_eq!(Tier::Quaternary, repo.get_account_tier(d).unwrap());
+        assert_eq!(Tier::Grace, repo.get_account_tier(e).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_max_batch_size() {
+        let keys: Vec<String> = (1..=5u8).map(|n| format!("{n:064x}")).collect();
+        let repo =
+            Repo::new_in_memory(HashSet::from([keys[0].clone()])).with_max_batch_size(Some(2));
+
+        let primary = keys[0].clone();
+        let secondaries: HashSet<String> = keys[1..].iter().cloned().collect();
+        repo.set_tier(&HashSet::from([primary.clone()]), Tier::Primary)
+            .await
+            .unwrap();
+        repo.set_tier(&secondaries, Tier::Secondary).await.unwrap();
+        for key in &secondaries {
+            assert_eq!(Tier::Secondary, repo.get_account_tier(key).unwrap());
+        }
+
+        // One contact list per line over more lines than fit in a batch
+        let ndjson: String = keys
+            .windows(2)
+            .map(|pair| {
+                format!(
+                    "{{\"pubkey\":\"{}\",\"follows\":[\"{}\"]}}\n",
+                    pair[0], pair[1]
+                )
+            })
+            .collect();
+        let imported = repo
+            .import_follows_stream(ndjson.as_bytes(), 100)
+            .await
+            .unwrap();
+
+        assert_eq!(4, imported);
+        assert_eq!(4, repo.count_follows().unwrap());
+        let tiers: Vec<Tier> = keys
+            .iter()
+            .map(|k| repo.get_account_tier(k).unwrap())
+            .collect();
+        assert_eq!(
+            vec![
+                Tier::Primary,
+                Tier::Secondary,
+                Tier::Tertiary,
+                Tier::Quaternary,
+                Tier::Grace
+            ],
+            tiers
+        );
+    }
+
+    #[tokio::test]
+    async fn test_follow_edges_chunks() {
+        let a = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9".to_string();
+        let b = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203".to_string();
+        let c = "7c27a04b7c27299f16dc07d3eb8f28544f188bc7a34982328b7d581edc405dc2".to_string();
+        let repo = Repo::new_in_memory(HashSet::new());
+        repo.set_contact_list(&a, &HashSet::from([b.clone(), c.clone()]))
+            .await
+            .unwrap();
+        repo.set_contact_list(&b, &HashSet::from([c.clone()]))
+            .await
+            .unwrap();
+
+        let chunks: Vec<Vec<(String, String)>> = repo
+            .follow_edges_chunks(1)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(2, chunks.len());
+
+        let edges: HashSet<(String, String)> = chunks.into_iter().flatten().collect();
+        let all: HashSet<(String, String)> = repo.all_follow_edges().unwrap().into_iter().collect();
+        assert_eq!(
+            HashSet::from([(a.clone(), b.clone()), (a, c.clone()), (b, c)]),
+            edges
+        );
+        assert_eq!(all, edges);
+    }
+
+    #[test]
+    fn test_export_events_csv() {
+        let a = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9";
+        let b = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203";
+        let repo = Repo::new_in_memory(HashSet::new());
+        {
+            let db = repo.db.write().unwrap();
+            db.write_event(a, 100).unwrap();
+            db.write_event(a, 200).unwrap();
+            db.write_event(b, 150).unwrap();
+        }
+
+        let mut csv = Vec::new();
+        assert_eq!(3, repo.export_events_csv(&mut csv, None, None).unwrap());
+        assert_eq!(
+            format!("pubkey,timestamp\n{a},100\n{a},200\n{b},150\n"),
+            String::from_utf8(csv).unwrap()
+        );
+
+        let mut csv = Vec::new();
+        repo.export_events_csv(&mut csv, Some(a), Some(150..=300))
+            .unwrap();
+        assert_eq!(
+            format!("pubkey,timestamp\n{a},200\n"),
+            String::from_utf8(csv).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_graph_round_trip() {
+        let a = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9";
+        let b = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203";
+        let c = "7c27a04b7c27299f16dc07d3eb8f28544f188bc7a34982328b7d581edc405dc2";
+        let d = "5b3a49bcbdf41f511f5c9034dbe46240863b73b39a2fdfebfb611f23b88d3922";
+        let primary = HashSet::from([a.to_string()]);
+        let repo = Repo::new_in_memory(primary.clone());
+        repo.set_tier(&primary, Tier::Primary).await.unwrap();
+        repo.update_contacts(a, HashSet::from([b.to_string(), c.to_string()]))
+            .await
+            .unwrap();
+        repo.update_contacts(c, HashSet::from([d.to_string(), a.to_string()]))
+            .await
+            .unwrap();
+
+        let path = temp_path(".json");
+        let path = path.to_str().unwrap();
+        repo.export_graph().unwrap().save(path).unwrap();
+        let snapshot = GraphSnapshot::load(path).unwrap();
+
+        // Importing replaces whatever was there
+        let imported = Repo::new_in_memory(primary);
+        imported
+            .set_contact_list(d, &HashSet::from([b.to_string()]))
+            .await
+            .unwrap();
+        imported.import_graph(&snapshot).await.unwrap();
+
+        assert_eq!(
+            repo.export_graph().unwrap(),
+            imported.export_graph().unwrap()
+        );
+        assert_eq!(
+            repo.follow_counts(a).unwrap(),
+            imported.follow_counts(a).unwrap()
+        );
+        for pubkey in [a, b, c, d] {
+            assert_eq!(
+                repo.get_account_tier(pubkey).unwrap(),
+                imported.get_account_tier(pubkey).unwrap()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_diff_against() {
+        let a = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9".to_string();
+        let b = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203".to_string();
+        let c = "7c27a04b7c27299f16dc07d3eb8f28544f188bc7a34982328b7d581edc405dc2".to_string();
+        let d = "5b3a49bcbdf41f511f5c9034dbe46240863b73b39a2fdfebfb611f23b88d3922".to_string();
+        let repo = Repo::new_in_memory(HashSet::from([a.clone()]));
+        repo.clear_accounts().await.unwrap();
+
+        repo.set_tier(&HashSet::from([a.clone()]), Tier::Primary)
+            .await
+            .unwrap();
+        repo.update_contacts(&a, HashSet::from([b.clone()]))
+            .await
+            .unwrap();
+        repo.update_contacts(&b, HashSet::from([c.clone()]))
+            .await
+            .unwrap();
+
+        let path = std::env::temp_dir().join("contact-group-auth-diff.json");
+        let path = path.to_string_lossy().to_string();
+        repo.export_graph().unwrap().save(&path).unwrap();
+        assert!(repo.diff_against(&path).unwrap().is_empty());
+
+        // B also follows D
+        repo.update_contacts(&b, HashSet::from([c.clone(), d.clone()]))
+            .await
+            .unwrap();
+
+        let diff = repo.diff_against(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let expected = GraphDiff {
+            added_accounts: [(d.clone(), Tier::Tertiary)].into(),
+            added_follows: [(b, d)].into(),
+            ..Default::default()
+        };
+        assert_eq!(expected, diff);
+    }
+
+    #[test]
+    fn test_begin_publish() {
+        let pubkey = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9";
+        let repo = Repo::new_in_memory(HashSet::new());
+
+        let first = repo.begin_publish(pubkey, 2).unwrap();
+        let second = repo.begin_publish(pubkey, 2).unwrap();
+        assert!(repo.begin_publish(pubkey, 2).is_none());
+        assert!(repo.begin_publish("other", 2).is_some());
+
+        drop(first);
+        let third = repo.begin_publish(pubkey, 2).unwrap();
+        drop(second);
+        drop(third);
+        assert!(repo.in_flight.lock().unwrap().is_empty());
+
+        assert!(repo.begin_publish(pubkey, 0).is_none());
+        assert!(repo.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_duplicate_content() {
+        let repo = Repo::new_in_memory(HashSet::new());
+        let pubkey = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9";
+        let limits = DuplicateContent {
+            enabled: true,
+            window: 3600,
+            max_dupes: 2,
+        };
+
+        // First post and two repeats are allowed
+        for _ in 0..3 {
+            assert!(repo.allows_content(&limits, pubkey, "buy now"));
+            repo.record_content(&limits, pubkey, "buy now");
+        }
+        assert!(!repo.allows_content(&limits, pubkey, "buy now"));
+
+        // Other content and other authors are unaffected
+        assert!(repo.allows_content(&limits, pubkey, "gm"));
+        let other = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203";
+        // Checking alone records nothing
+        for _ in 0..5 {
+            assert!(repo.allows_content(&limits, other, "buy now"));
+        }
+
+        // Posts in between do not wash the spam out
+        for n in 0..10 {
+            repo.record_content(&limits, pubkey, &n.to_string());
+            assert!(!repo.allows_content(&limits, pubkey, "buy now"));
+        }
+
+        // Expired counts are dropped
+        let expired = DuplicateContent {
+            window: 0,
+            ..limits
+        };
+        assert!(repo.allows_content(&expired, pubkey, "buy now"));
+        assert!(repo.content_hashes.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_duplicate_content_bound() {
+        let repo = Repo::new_in_memory(HashSet::new());
+        let pubkey = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9";
+        let limits = DuplicateContent {
+            enabled: true,
+            window: 3600,
+            max_dupes: 0,
+        };
+
+        repo.record_content(&limits, pubkey, "buy now");
+        for n in 0..MAX_TRACKED_CONTENT {
+            repo.record_content(&limits, pubkey, &n.to_string());
+        }
+
+        // The least recently posted content is dropped first
+        assert_eq!(
+            MAX_TRACKED_CONTENT,
+            repo.content_hashes.lock().unwrap().len()
+        );
+        assert!(repo.allows_content(&limits, pubkey, "buy now"));
+        assert!(!repo.allows_content(&limits, pubkey, "0"));
+    }
 }
@@ -0,0 +1,162 @@
+//! Startup self-test run with `--self-test`
+//! Exercises admission against a temp DB and an offline relay
+use std::collections::HashSet;
+use std::str::FromStr;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+use nostr_sdk::prelude::*;
+use tonic::Request;
+
+use crate::breaker::CircuitBreaker;
+use crate::config::{Limitation, Settings};
+use crate::db::Tier;
+use crate::metrics::Metrics;
+use crate::nauthz_grpc::authorization_server::Authorization;
+use crate::nauthz_grpc::event::TagEntry;
+use crate::nauthz_grpc::{self, Decision, EventRequest};
+use crate::nostr::Nostr;
+use crate::repo::Repo;
+use crate::updates::UpdateQueue;
+use crate::utils::unix_time;
+use crate::EventAuthz;
+
+const PRIMARY: &str = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9";
+const SECONDARY: &str = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203";
+const OTHER: &str = "7c27a04b7c27299f16dc07d3eb8f28544f188bc7a34982328b7d581edc405dc2";
+
+/// Runs each check printing pass/fail
+/// Returns true if all checks passed
+pub async fn run() -> bool {
+    let path = temp_db_path("self-test");
+
+    let primary = HashSet::from([PRIMARY.to_string()]);
+    let repo = Repo::open(&path, primary.clone());
+
+    let passed = checks(&repo, primary).await;
+
+    drop(repo);
+    let _ = std::fs::remove_file(&path);
+
+    passed
+}
+
+/// Path for a throwaway DB
+fn temp_db_path(name: &str) -> String {
+    let path = std::env::temp_dir().join(format!(
+        "contact-group-auth-{name}-{}-{}.redb",
+        std::process::id(),
+        unix_time()
+    ));
+    path.to_string_lossy().to_string()
+}
+
+async fn checks(repo: &Repo, primary: HashSet<String>) -> bool {
+    let mut passed = true;
+    let mut report = |name: &str, ok: bool| {
+        println!("{} {name}", if ok { "PASS" } else { "FAIL" });
+        passed &= ok;
+    };
+
+    let seeded = seed(repo, &primary).await.is_ok();
+    report("seed graph", seeded);
+
+    let settings = Settings {
+        primary: Limitation {
+            can_publish: true,
+            ..Default::default()
+        },
+        secondary: Limitation {
+            can_publish: true,
+            events_per_hour: Some(1),
+            events_per_day: Some(1),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    let checker = EventAuthz {
+        repo: repo.clone(),
+        settings,
+        nos: Nostr::offline(),
+        metrics: Metrics::default(),
+        updates: UpdateQueue::new(&Default::default(), Metrics::default()),
+        ready: Arc::new(AtomicBool::new(true)),
+        breaker: CircuitBreaker::default(),
+    };
+
+    let decision = admit(&checker, event(SECONDARY, 1, "self test", &[])).await;
+    report("permit", decision == Decision::Permit as i32);
+
+    // Limit is exhausted within a couple of events
+    let mut decision = Decision::Permit as i32;
+    for _ in 0..2 {
+        decision = admit(&checker, event(SECONDARY, 1, "self test rate", &[])).await;
+    }
+    report("deny by rate limit", decision == Decision::Deny as i32);
+
+    let decision = admit(&checker, event(OTHER, 1, "self test", &[])).await;
+    report("deny by tier", decision == Decision::Deny as i32);
+
+    let decision = admit(&checker, event(PRIMARY, 3, "", &[OTHER])).await;
+    checker.updates.drain(repo).await;
+    let tier = repo.get_account_tier(OTHER).ok();
+    report(
+        "contact update",
+        decision == Decision::Permit as i32 && tier == Some(Tier::Secondary),
+    );
+
+    passed
+}
+
+async fn seed(repo: &Repo, primary: &HashSet<String>) -> Result<(), crate::error::Error> {
+    repo.clear_accounts().await?;
+    repo.set_tier(primary, Tier::Primary).await?;
+    let follows = HashSet::from([SECONDARY.to_string()]);
+    repo.set_tier(&follows, Tier::Secondary).await?;
+    repo.update_contacts(PRIMARY, follows).await
+}
+
+async fn admit(checker: &EventAuthz, event: nauthz_grpc::Event) -> i32 {
+    let request = EventRequest {
+        event: Some(event),
+        ip_addr: None,
+        origin: None,
+        user_agent: None,
+        auth_pubkey: None,
+        nip05: None,
+    };
+
+    match checker.event_admit(Request::new(request)).await {
+        Ok(reply) => reply.into_inner().decision,
+        Err(_) => Decision::Unspecified as i32,
+    }
+}
+
+fn event(pubkey: &str, kind: u64, content: &str, follows: &[&str]) -> nauthz_grpc::Event {
+    let pubkey = XOnlyPublicKey::from_str(pubkey).unwrap();
+    nauthz_grpc::Event {
+        id: vec![0; 32],
+        pubkey: pubkey.serialize().to_vec(),
+        created_at: unix_time(),
+        kind,
+        content: content.to_string(),
+        tags: follows
+            .iter()
+            .map(|f| TagEntry {
+                values: vec!["p".to_string(), f.to_string()],
+            })
+            .collect(),
+        sig: vec![0; 64],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_self_test() {
+        assert!(run().await);
+    }
+}
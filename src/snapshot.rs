@@ -0,0 +1,69 @@
+//! Portable snapshots of the account graph and diffs between them
+use crate::db::Tier;
+use crate::error::Error;
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::{Deserialize, Serialize};
+
+/// Accounts with their tiers and every (follower, followee) edge
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GraphSnapshot {
+    pub accounts: BTreeMap<String, Tier>,
+    pub follows: BTreeSet<(String, String)>,
+}
+
+impl GraphSnapshot {
+    pub fn save(&self, path: &str) -> Result<(), Error> {
+        let json = serde_json::to_string_pretty(self)?;
+        std::fs::write(path, json)?;
+        Ok(())
+    }
+
+    pub fn load(path: &str) -> Result<Self, Error> {
+        let json = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&json)?)
+    }
+
+    /// Changes needed to go from `other` to this snapshot
+    pub fn diff(&self, other: &GraphSnapshot) -> GraphDiff {
+        let mut diff = GraphDiff::default();
+        for (pubkey, tier) in &self.accounts {
+            match other.accounts.get(pubkey) {
+                None => {
+                    diff.added_accounts.insert(pubkey.clone(), *tier);
+                }
+                Some(old_tier) if old_tier != tier => {
+                    diff.changed_accounts
+                        .insert(pubkey.clone(), (*old_tier, *tier));
+                }
+                _ => (),
+            }
+        }
+        for (pubkey, tier) in &other.accounts {
+            if !self.accounts.contains_key(pubkey) {
+                diff.removed_accounts.insert(pubkey.clone(), *tier);
+            }
+        }
+
+        diff.added_follows = self.follows.difference(&other.follows).cloned().collect();
+        diff.removed_follows = other.follows.difference(&self.follows).cloned().collect();
+        diff
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GraphDiff {
+    pub added_accounts: BTreeMap<String, Tier>,
+    pub removed_accounts: BTreeMap<String, Tier>,
+    /// Old and new tier of accounts whose tier changed
+    pub changed_accounts: BTreeMap<String, (Tier, Tier)>,
+    pub added_follows: BTreeSet<(String, String)>,
+    pub removed_follows: BTreeSet<(String, String)>,
+}
+
+impl GraphDiff {
+    pub fn is_empty(&self) -> bool {
+        self == &GraphDiff::default()
+    }
+}
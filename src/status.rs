@@ -0,0 +1,194 @@
+//! HTTP status server for people and dashboards, next to the gRPC health service
+use crate::db::Tier;
+use crate::error::Error;
+use crate::EventAuthz;
+
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serde::Serialize;
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Stats {
+    /// Whether the initial graph build has finished
+    pub ready: bool,
+    pub accounts: usize,
+    pub accounts_per_tier: BTreeMap<Tier, usize>,
+    pub total_follows: usize,
+    /// Unix time the graph was last built from the relays
+    pub graph_built_at: Option<u64>,
+    /// Connection state of each relay by URL
+    pub relays: BTreeMap<String, String>,
+    pub db_size_bytes: u64,
+}
+
+impl Stats {
+    pub async fn collect(checker: &EventAuthz) -> Result<Self, Error> {
+        let repo = &checker.repo;
+        let accounts_per_tier = repo.count_by_tier()?;
+        Ok(Self {
+            ready: checker.ready.load(Ordering::SeqCst),
+            accounts: accounts_per_tier.values().sum(),
+            accounts_per_tier,
+            total_follows: repo.count_follows()?,
+            graph_built_at: repo.graph_built_at()?,
+            relays: checker.nos.relay_status().await,
+            db_size_bytes: repo.db_size()?,
+        })
+    }
+}
+
+/// Binds `addr` and serves the status endpoints until the process exits
+pub fn spawn(addr: SocketAddr, checker: EventAuthz) -> Result<JoinHandle<()>, hyper::Error> {
+    let server = Server::try_bind(&addr)?;
+    let make_service = make_service_fn(move |_| {
+        let checker = checker.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let checker = checker.clone();
+                async move { Ok::<_, Infallible>(respond(&checker, request).await) }
+            }))
+        }
+    });
+
+    info!("Status server listening on {addr}");
+    Ok(tokio::spawn(async move {
+        if let Err(err) = server.serve(make_service).await {
+            error!("Status server failed: {err}");
+        }
+    }))
+}
+
+/// `/readyz` answers 503 until the initial graph build has finished,
+/// `/stats` the graph statistics as JSON and `/metrics` the admission counters
+/// in the Prometheus text format
+async fn respond(checker: &EventAuthz, request: Request<Body>) -> Response<Body> {
+    if request.method() != Method::GET {
+        return text(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed");
+    }
+
+    match request.uri().path() {
+        "/readyz" if checker.ready.load(Ordering::SeqCst) => text(StatusCode::OK, "ready"),
+        "/readyz" => text(StatusCode::SERVICE_UNAVAILABLE, "not ready"),
+        "/stats" => match Stats::collect(checker).await {
+            Ok(stats) => json(&stats),
+            Err(err) => {
+                warn!("Could not collect stats: {err}");
+                text(StatusCode::INTERNAL_SERVER_ERROR, "Could not collect stats")
+            }
+        },
+        "/metrics" => {
+            let mut response = Response::new(Body::from(checker.metrics.prometheus()));
+            response.headers_mut().insert(
+                hyper::header::CONTENT_TYPE,
+                hyper::header::HeaderValue::from_static("text/plain; version=0.0.4"),
+            );
+            response
+        }
+        _ => text(StatusCode::NOT_FOUND, "Not found"),
+    }
+}
+
+fn text(status: StatusCode, body: &'static str) -> Response<Body> {
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    response
+}
+
+fn json(value: &impl Serialize) -> Response<Body> {
+    match serde_json::to_vec(value) {
+        Ok(body) => {
+            let mut response = Response::new(Body::from(body));
+            response.headers_mut().insert(
+                hyper::header::CONTENT_TYPE,
+                hyper::header::HeaderValue::from_static("application/json"),
+            );
+            response
+        }
+        Err(err) => {
+            warn!("Could not serialize status: {err}");
+            text(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Could not serialize status",
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::breaker::CircuitBreaker;
+    use crate::config::Settings;
+    use crate::metrics::Metrics;
+    use crate::nostr::Nostr;
+    use crate::repo::Repo;
+    use crate::updates::UpdateQueue;
+
+    use std::collections::HashSet;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    async fn get(checker: &EventAuthz, path: &str) -> (StatusCode, String) {
+        let request = Request::get(path).body(Body::empty()).unwrap();
+        let response = respond(checker, request).await;
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_status_endpoints() {
+        let primary = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9";
+        let follow = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203";
+        let settings = Settings::default();
+        let checker = EventAuthz {
+            repo: Repo::new_in_memory(HashSet::from([primary.to_string()])),
+            breaker: CircuitBreaker::new(&settings.circuit_breaker),
+            settings,
+            nos: Nostr::offline(),
+            metrics: Metrics::default(),
+            updates: UpdateQueue::new(&Default::default(), Metrics::default()),
+            ready: Arc::new(AtomicBool::new(false)),
+        };
+
+        assert_eq!(
+            StatusCode::SERVICE_UNAVAILABLE,
+            get(&checker, "/readyz").await.0
+        );
+        checker.ready.store(true, Ordering::SeqCst);
+        assert_eq!(StatusCode::OK, get(&checker, "/readyz").await.0);
+
+        checker
+            .repo
+            .set_tier(&HashSet::from([primary.to_string()]), Tier::Primary)
+            .await
+            .unwrap();
+        checker
+            .repo
+            .update_contacts(primary, HashSet::from([follow.to_string()]))
+            .await
+            .unwrap();
+        checker.repo.set_graph_built().unwrap();
+        let (status, body) = get(&checker, "/stats").await;
+        assert_eq!(StatusCode::OK, status);
+        let stats: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(2, stats["accounts"]);
+        assert_eq!(1, stats["accounts_per_tier"]["Secondary"]);
+        assert_eq!(1, stats["total_follows"]);
+        assert!(stats["graph_built_at"].is_u64());
+        assert!(stats["db_size_bytes"].as_u64().unwrap() > 0);
+
+        checker.metrics.record_permit();
+        let (_, body) = get(&checker, "/metrics").await;
+        assert!(body.contains("# TYPE nauthz_permit_total counter\nnauthz_permit_total 1\n"));
+
+        assert_eq!(StatusCode::NOT_FOUND, get(&checker, "/nope").await.0);
+    }
+}
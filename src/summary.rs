@@ -0,0 +1,45 @@
+//! Summary of what the service booted with
+use crate::config::Settings;
+use crate::db::Tier;
+use crate::error::Error;
+use crate::repo::Repo;
+
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+use tracing::info;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct StartupSummary {
+    pub primary_count: usize,
+    pub accounts_per_tier: BTreeMap<Tier, usize>,
+    pub total_follows: usize,
+    pub relays: Vec<String>,
+    /// Config file read or `None` if defaults were used
+    pub config_source: Option<String>,
+    /// Whether the graph was rebuilt from the relays
+    pub rebuilt: bool,
+}
+
+impl StartupSummary {
+    pub fn collect(settings: &Settings, repo: &Repo, rebuilt: bool) -> Result<Self, Error> {
+        Ok(Self {
+            primary_count: settings.info.primary_keys.len(),
+            accounts_per_tier: repo.count_by_tier()?,
+            total_follows: repo.count_follows()?,
+            relays: settings.info.relay_url.clone(),
+            config_source: settings.config_source.clone(),
+            rebuilt,
+        })
+    }
+
+    /// Logs the summary and writes it as JSON to `path` if given
+    pub fn emit(&self, path: Option<&str>) -> Result<(), Error> {
+        let json = serde_json::to_string(self)?;
+        info!("Startup summary: {json}");
+        if let Some(path) = path {
+            std::fs::write(path, json)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,70 @@
+//! Tracing subscriber setup with optional OpenTelemetry export
+use crate::config::Settings;
+use crate::error::Error;
+
+#[cfg(feature = "otel")]
+use tracing_subscriber::layer::SubscriberExt;
+#[cfg(feature = "otel")]
+use tracing_subscriber::util::SubscriberInitExt;
+
+/// Installs the global subscriber
+/// Spans are also exported over OTLP when an endpoint is configured and the
+/// `otel` feature is enabled
+pub fn init(settings: &Settings) -> Result<(), Error> {
+    #[cfg(feature = "otel")]
+    if let Some(endpoint) = &settings.info.otlp_endpoint {
+        let tracer = otlp_tracer(endpoint)?;
+        tracing_subscriber::registry()
+            .with(tracing_subscriber::fmt::layer())
+            .with(tracing_opentelemetry::layer().with_tracer(tracer))
+            .try_init()
+            .map_err(|e| Error::SubscriberError(e.to_string()))?;
+        return Ok(());
+    }
+
+    tracing_subscriber::fmt::try_init().map_err(|e| Error::SubscriberError(e.to_string()))?;
+
+    #[cfg(not(feature = "otel"))]
+    if settings.info.otlp_endpoint.is_some() {
+        tracing::warn!("otlp_endpoint is set but the otel feature is not enabled");
+    }
+
+    Ok(())
+}
+
+/// Runs `f` with a plain subscriber so what it logs before `init` is not lost,
+/// such as warnings while loading the settings `init` needs
+pub fn bootstrap<T>(f: impl FnOnce() -> T) -> T {
+    tracing::subscriber::with_default(tracing_subscriber::fmt().finish(), f)
+}
+
+/// Flushes spans not yet exported, a no-op without the `otel` feature
+pub fn shutdown() {
+    #[cfg(feature = "otel")]
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+#[cfg(feature = "otel")]
+fn otlp_tracer(endpoint: &str) -> Result<opentelemetry::sdk::trace::Tracer, Error> {
+    use opentelemetry_otlp::WithExportConfig;
+
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .install_batch(opentelemetry::runtime::Tokio)?;
+    Ok(tracer)
+}
+
+#[cfg(all(test, feature = "otel"))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_otlp_tracer() {
+        assert!(otlp_tracer("http://localhost:4317").is_ok());
+    }
+}
@@ -0,0 +1,62 @@
+//! Fixtures shared by the unit tests
+use std::str::FromStr;
+
+use nostr_sdk::prelude::XOnlyPublicKey;
+use tempfile::TempPath;
+use tonic::Request;
+
+use crate::nauthz_grpc::authorization_server::Authorization;
+use crate::nauthz_grpc::event::TagEntry;
+use crate::nauthz_grpc::{self, Decision, EventRequest};
+use crate::utils::unix_time;
+use crate::EventAuthz;
+
+/// Path of an empty file ending in `suffix`, removed when dropped
+pub(crate) fn temp_path(suffix: &str) -> TempPath {
+    tempfile::Builder::new()
+        .prefix("contact-group-auth-")
+        .suffix(suffix)
+        .tempfile()
+        .unwrap()
+        .into_temp_path()
+}
+
+pub(crate) async fn admit(checker: &EventAuthz, event: nauthz_grpc::Event) -> i32 {
+    let request = EventRequest {
+        event: Some(event),
+        ip_addr: None,
+        origin: None,
+        user_agent: None,
+        auth_pubkey: None,
+        nip05: None,
+    };
+
+    match checker.event_admit(Request::new(request)).await {
+        Ok(reply) => reply.into_inner().decision,
+        Err(_) => Decision::Unspecified as i32,
+    }
+}
+
+/// Unsigned event by `pubkey` with a `p` tag for each of `follows`
+pub(crate) fn event(
+    pubkey: &str,
+    kind: u64,
+    content: &str,
+    follows: &[&str],
+) -> nauthz_grpc::Event {
+    let pubkey = XOnlyPublicKey::from_str(pubkey).unwrap();
+    nauthz_grpc::Event {
+        id: vec![0; 32],
+        pubkey: pubkey.serialize().to_vec(),
+        created_at: unix_time(),
+        kind,
+        content: content.to_string(),
+        tags: follows
+            .iter()
+            .map(|f| TagEntry {
+                values: vec!["p".to_string(), f.to_string()],
+            })
+            .collect(),
+        sig: vec![0; 64],
+    }
+}
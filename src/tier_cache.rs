@@ -0,0 +1,90 @@
+//! Least recently used cache of tier lookups
+use crate::db::Tier;
+use crate::repo::TierSource;
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use linked_hash_map::LinkedHashMap;
+
+/// Tier lookups kept for `ttl`, dropped by the DB when the tier or pin of
+/// their pubkey is written
+pub struct TierCache {
+    /// Least recently used first
+    entries: Mutex<LinkedHashMap<String, CachedTier>>,
+    /// Max entries
+    size: usize,
+    ttl: Duration,
+    /// Bumped by every invalidation so a lookup racing a write is not cached
+    generation: AtomicU64,
+}
+
+#[derive(Clone, Copy)]
+struct CachedTier {
+    tier: Tier,
+    source: TierSource,
+    cached_at: Instant,
+}
+
+impl TierCache {
+    pub fn new(size: usize, ttl: Duration) -> Self {
+        Self {
+            entries: Mutex::new(LinkedHashMap::new()),
+            size,
+            ttl,
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get(&self, pubkey: &str) -> Option<(Tier, TierSource)> {
+        let mut entries = self.entries.lock().unwrap();
+        let cached = *entries.get_refresh(pubkey)?;
+        if cached.cached_at.elapsed() >= self.ttl {
+            entries.remove(pubkey);
+            return None;
+        }
+        Some((cached.tier, cached.source))
+    }
+
+    /// Taken before reading a tier to insert, see `insert`
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// Caches a tier read after `generation` was taken, unless a write
+    /// invalidated anything since as the tier may be stale
+    pub fn insert(&self, pubkey: &str, generation: u64, (tier, source): (Tier, TierSource)) {
+        let mut entries = self.entries.lock().unwrap();
+        if self.generation() != generation {
+            return;
+        }
+        entries.insert(
+            pubkey.to_string(),
+            CachedTier {
+                tier,
+                source,
+                cached_at: Instant::now(),
+            },
+        );
+        while entries.len() > self.size {
+            entries.pop_front();
+        }
+    }
+
+    /// Drops the cached tiers of `pubkeys`
+    pub fn invalidate<'a>(&self, pubkeys: impl IntoIterator<Item = &'a str>) {
+        let mut entries = self.entries.lock().unwrap();
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        for pubkey in pubkeys {
+            entries.remove(pubkey);
+        }
+    }
+
+    /// Drops every cached tier, for writes replacing the whole graph
+    pub fn clear(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        entries.clear();
+    }
+}
@@ -0,0 +1,377 @@
+//! Bounded queue of contact list updates applied by a background worker
+use crate::config::{OverflowPolicy, UpdateQueueSettings};
+use crate::metrics::{Metrics, QUEUE_DROPPED, QUEUE_REJECTED};
+use crate::repo::Repo;
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::{Mutex as AsyncMutex, Notify};
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, warn};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContactUpdate {
+    pub pubkey: String,
+    pub contacts: HashSet<String>,
+    /// `created_at` of the contact list event
+    pub created_at: u64,
+    /// Relay hints of the contacts that have one, by contact
+    pub relay_hints: HashMap<String, String>,
+    /// Id of the contact list event
+    pub event_id: String,
+    /// Contact list ids named by a kind 5 deletion, the update deletes the
+    /// applied list if it is one of them instead of replacing it
+    pub deletes: HashSet<String>,
+}
+
+#[derive(Clone)]
+pub struct UpdateQueue {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    queue: Mutex<VecDeque<ContactUpdate>>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    /// Signalled when an update is queued
+    items: Notify,
+    /// Signalled when an update is taken off the queue
+    space: Notify,
+    metrics: Metrics,
+    /// Background worker, stopped on shutdown
+    worker: Mutex<Option<JoinHandle<()>>>,
+    /// Held while an update is applied so updates apply one at a time
+    applying: Arc<AsyncMutex<()>>,
+}
+
+impl UpdateQueue {
+    pub fn new(settings: &UpdateQueueSettings, metrics: Metrics) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                queue: Mutex::new(VecDeque::new()),
+                capacity: settings.size.max(1),
+                policy: settings.overflow,
+                items: Notify::new(),
+                space: Notify::new(),
+                metrics,
+                worker: Mutex::new(None),
+                applying: Arc::default(),
+            }),
+        }
+    }
+
+    /// Queues an update applying the overflow policy when full
+    /// Returns false if the update was rejected
+    pub async fn push(&self, update: ContactUpdate) -> bool {
+        loop {
+            {
+                let mut queue = self.inner.queue.lock().unwrap();
+                if queue.len() < self.inner.capacity {
+                    queue.push_back(update);
+                    self.inner.items.notify_one();
+                    return true;
+                }
+
+                match self.inner.policy {
+                    OverflowPolicy::DropOldest => {
+                        if let Some(dropped) = queue.pop_front() {
+                            debug!("Update queue full, dropped update for {}", dropped.pubkey);
+                        }
+                        self.inner.metrics.record(QUEUE_DROPPED);
+                        queue.push_back(update);
+                        self.inner.items.notify_one();
+                        return true;
+                    }
+                    OverflowPolicy::Reject => {
+                        self.inner.metrics.record(QUEUE_REJECTED);
+                        return false;
+                    }
+                    OverflowPolicy::Block => (),
+                }
+            }
+            self.inner.space.notified().await;
+        }
+    }
+
+    /// Takes the oldest update if there is one
+    pub fn pop(&self) -> Option<ContactUpdate> {
+        let update = self.inner.queue.lock().unwrap().pop_front();
+        if update.is_some() {
+            self.inner.space.notify_one();
+        }
+        update
+    }
+
+    /// Waits for the next update
+    pub async fn next(&self) -> ContactUpdate {
+        loop {
+            if let Some(update) = self.pop() {
+                return update;
+            }
+            self.inner.items.notified().await;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.queue.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Applies every queued update, returning how many were applied
+    pub async fn drain(&self, repo: &Repo) -> usize {
+        self.drain_until(repo, None).await
+    }
+
+    /// Applies queued updates until the queue is empty or `deadline` passes
+    /// The deadline is checked between updates as one being applied can not be stopped
+    async fn drain_until(&self, repo: &Repo, deadline: Option<Instant>) -> usize {
+        let mut applied = 0;
+        while deadline.is_none_or(|deadline| Instant::now() < deadline) {
+            match self.pop() {
+                Some(update) => self.apply(repo, update).await,
+                None => break,
+            }
+            applied += 1;
+        }
+        applied
+    }
+
+    /// Applies `update` on a blocking thread as it holds the DB write lock
+    /// throughout, waiting for any update still being applied
+    async fn apply(&self, repo: &Repo, update: ContactUpdate) {
+        // Moved into the blocking task so it is held until the update is
+        // applied even if the task awaiting it is cancelled
+        let applying = self.inner.applying.clone().lock_owned().await;
+        let repo = repo.clone();
+        let applied = tokio::task::spawn_blocking(move || {
+            apply(&repo, update);
+            drop(applying);
+        })
+        .await;
+        if let Err(err) = applied {
+            error!("Contact list update failed: {err}");
+        }
+    }
+
+    /// Stops the worker once its current update is applied, then applies
+    /// queued updates until empty or `timeout` elapses
+    /// Returns the number of updates left unapplied
+    pub async fn shutdown(&self, repo: &Repo, timeout: Duration) -> usize {
+        let worker = self.inner.worker.lock().unwrap().take();
+        if let Some(worker) = worker {
+            // An update the worker was applying finishes on its blocking
+            // thread, the drain below waits for it
+            worker.abort();
+            let _ = worker.await;
+        }
+
+        let applied = self.drain_until(repo, Some(Instant::now() + timeout)).await;
+        info!("Applied {applied} queued updates on shutdown");
+        if !self.is_empty() {
+            warn!("Update queue not drained within {timeout:?}");
+        }
+
+        let dropped = self.len();
+        if dropped > 0 {
+            warn!("Dropping {dropped} queued contact list updates");
+        }
+        dropped
+    }
+
+    /// Spawns the worker applying updates to the repo in order
+    pub fn spawn_worker(&self, repo: Repo) {
+        let queue = self.clone();
+        let worker = tokio::spawn(async move {
+            loop {
+                let update = queue.next().await;
+                queue.apply(&repo, update).await;
+            }
+        });
+        *self.inner.worker.lock().unwrap() = Some(worker);
+    }
+}
+
+fn apply(repo: &Repo, update: ContactUpdate) {
+    if !update.deletes.is_empty() {
+        match repo.delete_contact_list(&update.pubkey, &update.deletes) {
+            Ok(true) => info!("Contact list of {} deleted", update.pubkey),
+            Ok(false) => debug!("Deletion by {} not of its contact list", update.pubkey),
+            Err(err) => error!("Could not delete contacts of {}: {err}", update.pubkey),
+        }
+        return;
+    }
+
+    debug!("New contacts for {}: {:?}", update.pubkey, update.contacts);
+    match repo.update_contacts_at(&update.pubkey, update.contacts, update.created_at) {
+        Ok(true) => {
+            if let Err(err) = repo.set_relay_hints(&update.pubkey, &update.relay_hints) {
+                error!("Could not store relay hints for {}: {err}", update.pubkey);
+            }
+            if let Err(err) = repo.set_contact_list_id(&update.pubkey, &update.event_id) {
+                error!(
+                    "Could not store contact list id for {}: {err}",
+                    update.pubkey
+                );
+            }
+        }
+        Ok(false) => debug!("Stale contact list for {} ignored", update.pubkey),
+        Err(err) => error!("Could not update contacts for {}: {err}", update.pubkey),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Tier;
+
+    fn update(pubkey: &str) -> ContactUpdate {
+        ContactUpdate {
+            pubkey: pubkey.to_string(),
+            contacts: HashSet::new(),
+            created_at: 0,
+            relay_hints: HashMap::new(),
+            event_id: String::new(),
+            deletes: HashSet::new(),
+        }
+    }
+
+    fn queue(overflow: OverflowPolicy, metrics: Metrics) -> UpdateQueue {
+        let settings = UpdateQueueSettings {
+            size: 2,
+            overflow,
+            ..Default::default()
+        };
+        UpdateQueue::new(&settings, metrics)
+    }
+
+    #[tokio::test]
+    async fn test_drop_oldest() {
+        let metrics = Metrics::default();
+        let queue = queue(OverflowPolicy::DropOldest, metrics.clone());
+
+        for pubkey in ["a", "b", "c"] {
+            assert!(queue.push(update(pubkey)).await);
+        }
+
+        assert_eq!(1, metrics.get(QUEUE_DROPPED));
+        assert_eq!(Some(update("b")), queue.pop());
+        assert_eq!(Some(update("c")), queue.pop());
+        assert_eq!(None, queue.pop());
+    }
+
+    #[tokio::test]
+    async fn test_reject() {
+        let metrics = Metrics::default();
+        let queue = queue(OverflowPolicy::Reject, metrics.clone());
+
+        assert!(queue.push(update("a")).await);
+        assert!(queue.push(update("b")).await);
+        assert!(!queue.push(update("c")).await);
+
+        assert_eq!(1, metrics.get(QUEUE_REJECTED));
+        assert_eq!(2, queue.len());
+        assert_eq!(Some(update("a")), queue.pop());
+    }
+
+    #[tokio::test]
+    async fn test_block() {
+        let queue = queue(OverflowPolicy::Block, Metrics::default());
+
+        assert!(queue.push(update("a")).await);
+        assert!(queue.push(update("b")).await);
+
+        // Full so the push waits for space
+        let blocked = tokio::time::timeout(Duration::from_millis(50), queue.push(update("c")));
+        assert!(blocked.await.is_err());
+
+        let pusher = queue.clone();
+        let push = tokio::spawn(async move { pusher.push(update("c")).await });
+        assert_eq!(Some(update("a")), queue.pop());
+        assert!(push.await.unwrap());
+        assert_eq!(2, queue.len());
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let a = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9";
+        let b = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203";
+        let repo = Repo::new_in_memory(HashSet::from([a.into()]));
+        repo.set_tier(&HashSet::from([a.to_string()]), Tier::Primary)
+            .await
+            .unwrap();
+
+        let queue = queue(OverflowPolicy::Block, Metrics::default());
+        assert!(
+            queue
+                .push(ContactUpdate {
+                    pubkey: a.to_string(),
+                    contacts: HashSet::from([b.to_string()]),
+                    created_at: 0,
+                    relay_hints: HashMap::new(),
+                    event_id: String::new(),
+                    deletes: HashSet::new(),
+                })
+                .await
+        );
+        assert!(queue.push(update(b)).await);
+
+        assert_eq!(0, queue.shutdown(&repo, Duration::from_secs(5)).await);
+        assert!(queue.is_empty());
+        assert_eq!(Tier::Secondary, repo.get_account_tier(b).unwrap());
+
+        // Past the deadline nothing more is applied
+        assert!(queue.push(update(a)).await);
+        assert_eq!(1, queue.shutdown(&repo, Duration::ZERO).await);
+    }
+
+    #[tokio::test]
+    async fn test_worker_applies_in_order() {
+        let a = "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9";
+        let b = "d81eb632d2385c3e6bdc8da5a32b57275348819aebd39ff74613793f29694203";
+        let c = "7c27a04b7c27299f16dc07d3eb8f28544f188bc7a34982328b7d581edc405dc2";
+        let repo = Repo::new_in_memory(HashSet::from([a.into()]));
+        repo.set_tier(&HashSet::from([a.to_string()]), Tier::Primary)
+            .await
+            .unwrap();
+
+        // Two lists for the same pubkey in flight, the later one wins
+        let queue = queue(OverflowPolicy::Block, Metrics::default());
+        for (created_at, follow) in [(1, b), (2, c)] {
+            let update = ContactUpdate {
+                pubkey: a.to_string(),
+                contacts: HashSet::from([follow.to_string()]),
+                created_at,
+                relay_hints: HashMap::from([(
+                    follow.to_string(),
+                    format!("wss://relay{created_at}.example.com"),
+                )]),
+                event_id: created_at.to_string(),
+                deletes: HashSet::new(),
+            };
+            assert!(queue.push(update).await);
+        }
+        queue.spawn_worker(repo.clone());
+
+        let applied = async {
+            while repo.get_account_tier(c).unwrap() != Tier::Secondary {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), applied)
+            .await
+            .unwrap();
+        assert_eq!(0, queue.shutdown(&repo, Duration::from_secs(5)).await);
+
+        assert_eq!((0, 0), repo.follow_counts(b).unwrap());
+        // Hints of the replaced list go with its follows
+        assert_eq!(
+            HashMap::from([(c.to_string(), "wss://relay2.example.com".to_string())]),
+            repo.relay_hints(a).unwrap()
+        );
+    }
+}
@@ -1,8 +1,10 @@
 use nostr_sdk::prelude::*;
 use nostr_sdk::Client;
+use std::collections::HashMap;
 use std::time::SystemTime;
 
-use tracing::{debug, info};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, info, warn};
 
 /// Seconds since 1970.
 #[must_use]
@@ -15,15 +17,72 @@ pub fn unix_time() -> u64 {
 
 // Creates the websocket client that is used for communicating with relays
 // Copyright (c) 2022 0xtr MIT License
-pub async fn create_client(keys: &Keys, relays: Vec<String>) -> Result<Client> {
+pub async fn create_client(keys: &Keys, relays: Vec<String>, auth: bool) -> Result<Client> {
     let opts = Options::new().wait_for_send(true);
     let client = Client::new_with_opts(keys, opts);
+    if auth {
+        // Before connecting so a challenge sent on connect is not missed
+        spawn_auth_responder(client.clone(), keys.clone());
+    }
     let relays = relays.iter().map(|url| (url, None)).collect();
     client.add_relays(relays).await?;
     client.connect().await;
     Ok(client)
 }
 
+/// Answers NIP-42 AUTH challenges from the relays of `client` with `keys`
+/// for as long as the client runs, including after reconnects
+fn spawn_auth_responder(client: Client, keys: Keys) {
+    let mut notifications = client.notifications();
+    tokio::spawn(async move {
+        // AUTH events sent and not yet answered
+        let mut pending: HashMap<EventId, Url> = HashMap::new();
+        loop {
+            match notifications.recv().await {
+                Ok(RelayPoolNotification::Message(url, RelayMessage::Auth { challenge })) => {
+                    let event = match auth_event(&keys, &challenge, &url) {
+                        Ok(event) => event,
+                        Err(err) => {
+                            warn!("Could not sign AUTH for {url}: {err}");
+                            continue;
+                        }
+                    };
+                    debug!("Answering AUTH challenge from {url}");
+                    pending.insert(event.id, url.clone());
+                    let auth = ClientMessage::new_auth(event);
+                    if let Err(err) = client.send_msg_to(url.to_string(), auth).await {
+                        warn!("Could not send AUTH to {url}: {err}");
+                    }
+                }
+                Ok(RelayPoolNotification::Message(
+                    url,
+                    RelayMessage::Ok {
+                        event_id,
+                        status,
+                        message,
+                    },
+                )) if pending.remove(&event_id).is_some() => {
+                    if status {
+                        info!("Authenticated to {url}");
+                    } else {
+                        warn!("AUTH to {url} rejected: {message}");
+                    }
+                }
+                Ok(RelayPoolNotification::Shutdown) | Err(RecvError::Closed) => break,
+                Ok(_) => (),
+                Err(RecvError::Lagged(missed)) => warn!("Missed {missed} relay notifications"),
+            }
+        }
+    });
+}
+
+/// Signed NIP-42 answer to `challenge` from the relay at `url`
+pub fn auth_event(keys: &Keys, challenge: &str, url: &Url) -> Result<Event, String> {
+    EventBuilder::auth(challenge, url.clone())
+        .to_event(keys)
+        .map_err(|err| err.to_string())
+}
+
 // Parses a private key string and returns a keypair if valid.
 // If the private_key is None, a new keypair will be generated
 // Copyright (c) 2022 0xtr MIT License
@@ -45,3 +104,25 @@ pub fn handle_keys(private_key: Option<String>) -> Result<Keys> {
     debug!("Public key: {}", keys.public_key().to_bech32()?);
     Ok(keys)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_auth_event() {
+        let keys = Keys::generate();
+        let url = Url::parse("wss://relay.example.com").unwrap();
+        let event = auth_event(&keys, "challenge", &url).unwrap();
+
+        assert!(event.verify().is_ok());
+        assert_eq!(Kind::Authentication, event.kind);
+        assert_eq!(keys.public_key(), event.pubkey);
+        let tags: Vec<Vec<String>> = event.tags.iter().map(|t| t.as_vec()).collect();
+        assert!(tags.contains(&vec!["challenge".to_string(), "challenge".to_string()]));
+        assert!(tags.contains(&vec![
+            "relay".to_string(),
+            "wss://relay.example.com/".to_string()
+        ]));
+    }
+}
@@ -0,0 +1,78 @@
+//! Posts tier changes to an HTTP webhook
+use crate::db::TierChange;
+
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+
+/// Posts each change from `changes` to `url` as JSON until the senders are gone
+/// Changes are posted one at a time in order, a failed post is logged and dropped
+pub fn spawn(url: String, mut changes: mpsc::UnboundedReceiver<TierChange>) -> JoinHandle<()> {
+    let client = reqwest::Client::new();
+    tokio::spawn(async move {
+        while let Some(change) = changes.recv().await {
+            debug!(
+                "{} moved from {:?} to {:?}",
+                change.pubkey, change.old_tier, change.new_tier
+            );
+            let posted = client
+                .post(&url)
+                .json(&change)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+            if let Err(err) = posted {
+                warn!("Could not post tier change of {}: {err}", change.pubkey);
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Tier;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_post_tier_change() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/tiers", listener.local_addr().unwrap());
+
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let change = TierChange {
+            pubkey: "7995c67e4b40fcc88f7603fcedb5f2133a74b89b2678a332b21faee725f039f9".to_string(),
+            old_tier: Some(Tier::Secondary),
+            new_tier: Tier::Other,
+        };
+        sender.send(change.clone()).unwrap();
+        let worker = spawn(url, receiver);
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+        let body = loop {
+            let n = stream.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..n]);
+            let text = String::from_utf8_lossy(&request).to_string();
+            if let Some((_, body)) = text.split_once("\r\n\r\n") {
+                if serde_json::from_str::<TierChange>(body).is_ok() {
+                    break body.to_string();
+                }
+            }
+        };
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+            .await
+            .unwrap();
+
+        assert!(String::from_utf8_lossy(&request).starts_with("POST /tiers "));
+        assert_eq!(change, serde_json::from_str(&body).unwrap());
+
+        // Stops once nothing can send changes any more
+        drop(sender);
+        worker.await.unwrap();
+    }
+}